@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{crdt::Crdt, ClusterState, IO};
+
+/// Deltas kept around for peers that haven't acknowledged them yet. Peers
+/// that fall further behind than this get the full state instead.
+const MAX_DELTAS: usize = 128;
+
+/// Every this many rounds a peer is sent the full state even if it has
+/// acknowledged everything, which covers peers that restarted and lost it.
+const FULL_SYNC_EVERY: u64 = 40;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delta<C> {
+    /// Sequence number of the newest local delta included in `state`.
+    pub seq: u64,
+    /// Whether `state` is the sender's whole state rather than a delta group.
+    pub full: bool,
+    pub state: C,
+}
+
+/// Name for this node's own slot in a per-node CRDT such as `GCounter`. A
+/// restarted node has lost its old state but peers still remember it, so it
+/// starts over in a new slot rather than one they'd `max` away.
+pub fn incarnation(cluster_state: &ClusterState) -> String {
+    let started = cluster_state
+        .clock
+        .wall()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    format!("{}@{}", cluster_state.node_id, started)
+}
+
+/// Delta-state anti-entropy for any `Crdt`. Local updates are buffered as
+/// deltas and every gossip round ships each peer only the deltas it hasn't
+/// acknowledged yet.
+pub struct AntiEntropy<C> {
+    state: C,
+    deltas: BTreeMap<u64, C>,
+    last_seq: u64,
+    peers: Vec<String>,
+    acked: HashMap<String, u64>,
+    rounds: u64,
+}
+
+impl<C> AntiEntropy<C>
+where
+    C: Crdt + Default + Clone,
+{
+    pub fn new(cluster_state: &ClusterState) -> Self {
+        let peers = cluster_state
+            .node_ids
+            .iter()
+            .filter(|&n| n != &cluster_state.node_id)
+            .cloned()
+            .collect();
+
+        AntiEntropy {
+            state: C::default(),
+            deltas: BTreeMap::new(),
+            last_seq: 0,
+            peers,
+            acked: HashMap::new(),
+            rounds: 0,
+        }
+    }
+
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    pub fn set_peers(&mut self, peers: Vec<String>) {
+        self.acked.retain(|p, _| peers.contains(p));
+        self.peers = peers;
+    }
+
+    /// Merges a delta produced by a local update and queues it for gossip.
+    pub fn apply(&mut self, delta: C) {
+        self.state.merge(&delta);
+        self.push(delta);
+    }
+
+    /// Runs a local update that returns its own delta, such as
+    /// `GCounter::increment`, and queues that delta for gossip.
+    pub fn update(&mut self, f: impl FnOnce(&mut C) -> C) {
+        let delta = f(&mut self.state);
+        self.push(delta);
+    }
+
+    fn push(&mut self, delta: C) {
+        self.last_seq += 1;
+        self.deltas.insert(self.last_seq, delta);
+
+        while self.deltas.len() > MAX_DELTAS {
+            self.deltas.pop_first();
+        }
+    }
+
+    /// Merges a delta received from a peer and returns the sequence number
+    /// to acknowledge. Nothing is passed on: every node gossips its own
+    /// deltas to all its peers, and the periodic full sync carries updates
+    /// around partial partitions.
+    pub fn on_delta(&mut self, delta: &Delta<C>) -> u64 {
+        self.state.merge(&delta.state);
+
+        delta.seq
+    }
+
+    pub fn on_ack(&mut self, peer: &str, seq: u64) {
+        let acked = self.acked.entry(peer.to_string()).or_default();
+        *acked = (*acked).max(seq);
+
+        self.collect_garbage();
+    }
+
+    fn collect_garbage(&mut self) {
+        let min_acked = self
+            .peers
+            .iter()
+            .map(|p| self.acked.get(p).copied().unwrap_or_default())
+            .min()
+            .unwrap_or(self.last_seq);
+
+        self.deltas = self.deltas.split_off(&(min_acked + 1));
+    }
+
+    fn delta_for(&self, peer: &str, force_full: bool) -> Option<Delta<C>> {
+        let acked = self.acked.get(peer).copied().unwrap_or_default();
+
+        if self.last_seq == 0 {
+            return None;
+        }
+
+        if force_full {
+            return Some(self.full());
+        }
+
+        if acked >= self.last_seq {
+            return None;
+        }
+
+        match self.deltas.first_key_value() {
+            Some((&first, _)) if first <= acked + 1 => {
+                let mut state = C::default();
+                for delta in self.deltas.range(acked + 1..).map(|(_, d)| d) {
+                    state.merge(delta);
+                }
+
+                Some(Delta {
+                    seq: self.last_seq,
+                    full: false,
+                    state,
+                })
+            }
+            _ => Some(self.full()),
+        }
+    }
+
+    fn full(&self) -> Delta<C> {
+        Delta {
+            seq: self.last_seq,
+            full: true,
+            state: self.state.clone(),
+        }
+    }
+
+    /// Runs one gossip round; meant to be called from a `Timers` tick.
+    pub fn gossip<P>(&mut self, io: &mut IO<P>, to_payload: impl Fn(Delta<C>) -> P) -> Result<()>
+    where
+        P: Serialize + Clone,
+    {
+        self.rounds += 1;
+        let force_full = self.rounds.is_multiple_of(FULL_SYNC_EVERY);
+
+        for peer in &self.peers {
+            if let Some(delta) = self.delta_for(peer, force_full) {
+                io.fire_and_forget(peer, &to_payload(delta))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{crdt::GCounter, time::ManualClock, Message};
+
+    use super::*;
+
+    fn cluster_state(node_id: &str) -> Arc<ClusterState> {
+        Arc::new(ClusterState {
+            node_id: node_id.to_string(),
+            node_ids: vec!["n0".to_string(), "n1".to_string(), "n2".to_string()],
+            clock: Arc::new(ManualClock::new()),
+        })
+    }
+
+    /// Runs a gossip round and returns the deltas sent, by peer.
+    fn gossip(ae: &mut AntiEntropy<GCounter>) -> HashMap<String, Delta<GCounter>> {
+        let mut out = Vec::new();
+        let mut io = IO::new(cluster_state("n0"), &mut out);
+        ae.gossip(&mut io, |delta| delta).unwrap();
+        drop(io);
+
+        serde_json::Deserializer::from_slice(&out)
+            .into_iter::<Message<Delta<GCounter>>>()
+            .map(|m| m.map(|m| (m.dst, m.body.payload)))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn deltas_are_buffered_until_every_peer_acks_them() {
+        let mut ae = AntiEntropy::<GCounter>::new(&cluster_state("n0"));
+        ae.update(|c| c.increment("n0", 1));
+        ae.update(|c| c.increment("n0", 2));
+        assert_eq!(ae.deltas.len(), 2);
+
+        let sent = gossip(&mut ae);
+        assert_eq!(sent.len(), 2);
+        assert!(sent
+            .values()
+            .all(|d| !d.full && d.seq == 2 && d.state.get("n0") == 3));
+
+        ae.on_ack("n1", 2);
+        assert_eq!(ae.deltas.len(), 2);
+        let sent = gossip(&mut ae);
+        assert_eq!(sent.keys().collect::<Vec<_>>(), ["n2"]);
+
+        ae.on_ack("n2", 1);
+        assert_eq!(ae.deltas.keys().collect::<Vec<_>>(), [&2]);
+        ae.on_ack("n2", 2);
+        assert!(ae.deltas.is_empty());
+        assert!(gossip(&mut ae).is_empty());
+    }
+
+    #[test]
+    fn peers_behind_the_buffered_deltas_get_the_full_state() {
+        let mut ae = AntiEntropy::<GCounter>::new(&cluster_state("n0"));
+        ae.update(|c| c.increment("n0", 1));
+        ae.on_ack("n1", 1);
+        ae.on_ack("n2", 1);
+
+        for _ in 0..MAX_DELTAS {
+            ae.update(|c| c.increment("n0", 1));
+        }
+        ae.on_ack("n2", MAX_DELTAS as u64 + 1);
+        assert_eq!(ae.deltas.len(), MAX_DELTAS);
+
+        ae.update(|c| c.increment("n0", 1));
+        let sent = gossip(&mut ae);
+        assert!(sent["n1"].full);
+        assert_eq!(sent["n1"].state.get("n0"), MAX_DELTAS + 2);
+        assert!(!sent["n2"].full);
+    }
+
+    #[test]
+    fn every_so_often_peers_get_the_full_state_anyway() {
+        let mut ae = AntiEntropy::<GCounter>::new(&cluster_state("n0"));
+        ae.update(|c| c.increment("n0", 1));
+        ae.on_ack("n1", 1);
+        ae.on_ack("n2", 1);
+
+        for round in 1..=FULL_SYNC_EVERY {
+            let sent = gossip(&mut ae);
+            if round == FULL_SYNC_EVERY {
+                assert_eq!(sent.len(), 2);
+                assert!(sent.values().all(|d| d.full && d.state.get("n0") == 1));
+            } else {
+                assert!(sent.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn replicas_converge_when_deltas_are_dropped() {
+        let mut a = AntiEntropy::<GCounter>::new(&cluster_state("n0"));
+        let mut b = AntiEntropy::<GCounter>::new(&cluster_state("n1"));
+        let exchange = |from: &mut AntiEntropy<GCounter>, to: &mut AntiEntropy<GCounter>, to_id| {
+            if let Some(delta) = gossip(from).remove(to_id) {
+                let seq = to.on_delta(&delta);
+                from.on_ack(to_id, seq);
+            }
+        };
+
+        for round in 0..10 {
+            a.update(|c| c.increment("n0", 1));
+            b.update(|c| c.increment("n1", 2));
+
+            // A dropped delta is never acked, so it goes out again with the
+            // next round's.
+            if round % 3 != 0 {
+                exchange(&mut a, &mut b, "n1");
+            } else {
+                gossip(&mut a);
+            }
+            if round % 2 != 0 {
+                exchange(&mut b, &mut a, "n0");
+            } else {
+                gossip(&mut b);
+            }
+        }
+
+        exchange(&mut a, &mut b, "n1");
+        exchange(&mut b, &mut a, "n0");
+        assert_eq!(a.state(), b.state());
+        assert_eq!(a.state().value(), 30);
+    }
+}
@@ -0,0 +1,271 @@
+use gossip_glomers_rs::{
+    range_set::RangeSet,
+    topology::{Strategy, Topology},
+    ClusterState, Message, Node, Server, Timers, IO,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
+type Round = u32;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Topology { topology: Topology },
+    TopologyOk,
+    Broadcast { message: usize },
+    BroadcastOk,
+    Read,
+    ReadOk { messages: Vec<usize> },
+    Gossip { message: usize, round: Round },
+    GossipOk,
+    IHave { messages: Vec<(usize, Round)> },
+    Graft { messages: Vec<usize> },
+    Prune,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Timer {
+    LazyPush,
+    Graft,
+}
+
+/// Degree of the random overlay that lazy links are drawn from, on top of
+/// the eager tree.
+const LAZY_DEGREE: usize = 4;
+const GOSSIP_RETRY: Duration = Duration::from_millis(400);
+const GRAFT_TIMEOUT: Duration = Duration::from_millis(300);
+
+fn main() -> anyhow::Result<()> {
+    let mut node = Node::<PlumtreeServer, Payload, Timer>::init()?;
+    node.run()
+}
+
+/// A message we were told about through an IHAVE but haven't received yet.
+struct Missing {
+    since: Instant,
+    announcers: Vec<(String, Round)>,
+}
+
+/// Epidemic broadcast tree: messages are pushed eagerly along a spanning tree
+/// and announced lazily over the links of a sparse random overlay. Redundant
+/// eager links get pruned, and a missing message grafts the lazy link it was
+/// announced on back into the tree.
+struct PlumtreeServer {
+    messages: RangeSet,
+    eager: HashSet<String>,
+    lazy: HashSet<String>,
+    lazy_queue: HashMap<String, Vec<(usize, Round)>>,
+    missing: HashMap<usize, Missing>,
+    /// The peer each gossiped message first arrived from.
+    delivered_by: HashMap<usize, String>,
+}
+
+impl PlumtreeServer {
+    fn push(
+        &mut self,
+        io: &mut IO<Payload>,
+        from: Option<&str>,
+        message: usize,
+        round: Round,
+    ) -> Result<()> {
+        for n in self.eager.iter().filter(|&n| Some(n.as_str()) != from) {
+            let gossip = Payload::Gossip { message, round };
+            io.rpc_request_with_retry(n, &gossip, GOSSIP_RETRY)?;
+        }
+
+        for n in self.lazy.iter().filter(|&n| Some(n.as_str()) != from) {
+            self.lazy_queue
+                .entry(n.clone())
+                .or_default()
+                .push((message, round));
+        }
+
+        Ok(())
+    }
+
+    fn make_eager(&mut self, peer: &str) {
+        self.lazy.remove(peer);
+        self.eager.insert(peer.to_string());
+    }
+
+    fn make_lazy(&mut self, peer: &str) {
+        self.eager.remove(peer);
+        self.lazy.insert(peer.to_string());
+    }
+}
+
+impl Server<Payload, Timer> for PlumtreeServer {
+    fn init(
+        cluster_state: &ClusterState,
+        timers: &mut Timers<Payload, Timer>,
+    ) -> Result<PlumtreeServer> {
+        timers.register_timer(Timer::LazyPush, Duration::from_millis(100));
+        timers.register_timer(Timer::Graft, Duration::from_millis(50));
+
+        let node_ids = &cluster_state.node_ids;
+        let strategy = Strategy::from_env(Strategy::Tree { arity: 4 })?;
+        let eager: HashSet<String> = strategy.build(node_ids)?[&cluster_state.node_id]
+            .iter()
+            .cloned()
+            .collect();
+
+        // The same seed everywhere, so that every lazy link goes both ways.
+        let overlay = Strategy::RandomRegular {
+            degree: LAZY_DEGREE.min(node_ids.len() - 1),
+            seed: 0,
+        };
+        let lazy = overlay.build(node_ids)?[&cluster_state.node_id]
+            .iter()
+            .filter(|&n| !eager.contains(n))
+            .cloned()
+            .collect();
+
+        eprintln!("Eager peers: {:?}, lazy peers: {:?}", eager, lazy);
+
+        Ok(PlumtreeServer {
+            messages: RangeSet::new(),
+            eager,
+            lazy,
+            lazy_queue: HashMap::new(),
+            missing: HashMap::new(),
+            delivered_by: HashMap::new(),
+        })
+    }
+
+    fn on_message(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        input: Message<Payload>,
+    ) -> Result<()> {
+        let payload = &input.body.payload;
+        match payload {
+            Payload::Topology { .. } => {
+                io.rpc_reply_to(&input, &Payload::TopologyOk)?;
+            }
+            Payload::Broadcast { message } => {
+                if self.messages.insert(*message) {
+                    self.missing.remove(message);
+                    self.push(io, None, *message, 0)?;
+                }
+
+                io.rpc_reply_to(&input, &Payload::BroadcastOk)?;
+            }
+            Payload::Read => {
+                let reply = Payload::ReadOk {
+                    messages: self.messages.iter().collect(),
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::Gossip { message, round } => {
+                if self.messages.insert(*message) {
+                    self.missing.remove(message);
+                    self.delivered_by.insert(*message, input.src.clone());
+                    self.make_eager(&input.src);
+                    self.push(io, Some(&input.src), *message, round + 1)?;
+                } else if self.delivered_by.get(message) != Some(&input.src) {
+                    // A retry from whoever delivered it first only means our
+                    // gossip_ok got lost, not that the link is redundant.
+                    self.make_lazy(&input.src);
+                    io.fire_and_forget(&input.src, &Payload::Prune)?;
+                }
+
+                io.rpc_reply_to(&input, &Payload::GossipOk)?;
+            }
+            Payload::GossipOk => {
+                io.rpc_mark_completed(&input);
+            }
+            Payload::IHave { messages } => {
+                for (message, round) in messages {
+                    if self.messages.contains(*message) {
+                        continue;
+                    }
+
+                    self.missing
+                        .entry(*message)
+                        .or_insert_with(|| Missing {
+                            since: cluster_state.clock.now(),
+                            announcers: Vec::new(),
+                        })
+                        .announcers
+                        .push((input.src.clone(), *round));
+                }
+            }
+            Payload::Graft { messages } => {
+                self.make_eager(&input.src);
+                for message in messages.iter().filter(|&&m| self.messages.contains(m)) {
+                    let gossip = Payload::Gossip {
+                        message: *message,
+                        round: 0,
+                    };
+                    io.rpc_request_with_retry(&input.src, &gossip, GOSSIP_RETRY)?;
+                }
+            }
+            Payload::Prune => self.make_lazy(&input.src),
+            Payload::TopologyOk | Payload::BroadcastOk | Payload::ReadOk { .. } => {
+                bail!("unexpected payload {:?}", payload)
+            }
+        };
+
+        Ok(())
+    }
+
+    fn on_timer(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        timer: Timer,
+    ) -> Result<()> {
+        match timer {
+            Timer::LazyPush => {
+                for (n, messages) in self.lazy_queue.drain() {
+                    if !messages.is_empty() {
+                        io.fire_and_forget(&n, &Payload::IHave { messages })?;
+                    }
+                }
+            }
+            Timer::Graft => {
+                let now = cluster_state.clock.now();
+                let mut grafts: HashMap<String, Vec<usize>> = HashMap::new();
+                for (message, missing) in self.missing.iter_mut() {
+                    if now.saturating_duration_since(missing.since) < GRAFT_TIMEOUT {
+                        continue;
+                    }
+
+                    // Graft whoever announced it first, then rotate so that the
+                    // next attempt goes somewhere else.
+                    let (announcer, round) = missing.announcers.remove(0);
+                    missing.announcers.push((announcer.clone(), round));
+                    missing.since = now;
+
+                    grafts.entry(announcer).or_default().push(*message);
+                }
+
+                for (announcer, messages) in grafts {
+                    self.make_eager(&announcer);
+                    io.fire_and_forget(&announcer, &Payload::Graft { messages })?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_rpc_timeout(
+        &mut self,
+        _: &ClusterState,
+        _: &mut IO<Payload>,
+        timeout: gossip_glomers_rs::Request<Payload>,
+    ) -> Result<()> {
+        eprintln!("Timeout: {:?}", timeout);
+
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
+use gossip_glomers_rs::{
+    range_set::RangeSet,
+    topology::{Strategy, Topology},
+    ClusterState, Message, Node, Server, Timers, IO,
 };
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{bail, Result};
 
@@ -12,12 +13,12 @@ use anyhow::{bail, Result};
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Topology {
-        topology: HashMap<String, Vec<String>>,
+        topology: Topology,
     },
     TopologyOk,
     Broadcast {
         message: Option<usize>,
-        batch: Option<HashSet<usize>>,
+        batch: Option<RangeSet>,
     },
     BroadcastOk,
     Read,
@@ -25,7 +26,7 @@ enum Payload {
         messages: Vec<usize>,
     },
     Gossip {
-        messages: Vec<usize>,
+        messages: RangeSet,
     },
 }
 
@@ -40,10 +41,11 @@ fn main() -> anyhow::Result<()> {
 }
 
 struct BroadcastServer {
-    messages: HashSet<usize>,
-    seen: HashMap<String, HashSet<usize>>,
+    messages: RangeSet,
+    seen: HashMap<String, RangeSet>,
     neighbours: Vec<String>,
-    outbox: HashSet<usize>,
+    outbox: RangeSet,
+    strategy: Strategy,
 }
 
 impl Server<Payload, Timer> for BroadcastServer {
@@ -56,60 +58,22 @@ impl Server<Payload, Timer> for BroadcastServer {
         let seen = cluster_state
             .node_ids
             .iter()
-            .map(|n| (n.to_string(), HashSet::new()))
+            .map(|n| (n.to_string(), RangeSet::new()))
             .collect();
 
-        let mut nodes: Vec<String> = (0..cluster_state.node_ids.len())
-            .map(|n| format!("n{}", n))
-            .collect();
-        let mut topology: HashMap<String, Vec<String>> =
-            nodes.iter().map(|n| (n.clone(), Vec::new())).collect();
-
-        //let root = nodes.remove(0);
-        //for c in nodes {
-        //    let neighours = topology.get_mut(&c).unwrap();
-        //    neighours.push(root.clone());
-        //    let root_neigbours = topology.get_mut(&root).unwrap();
-        //    root_neigbours.push(c.clone());
-        //}
-
-        let root1 = nodes.remove(0);
-        let root2 = nodes.remove(0);
-        let mid = nodes.len() / 2;
-
-        let (children1, children2) = nodes.split_at(mid);
-        for c in children1 {
-            let neighours = topology.get_mut(c).unwrap();
-            neighours.push(root1.clone());
-            //neighours.push(root2.clone());
-            let root1_neigbours = topology.get_mut(&root1).unwrap();
-            root1_neigbours.push(c.clone());
-            //let root2_neigbours = topology.get_mut(&root2).unwrap();
-            //root2_neigbours.push(c.clone());
-        }
-
-        for c in children2 {
-            let neighours = topology.get_mut(c).unwrap();
-            //neighours.push(root1.clone());
-            neighours.push(root2.clone());
-            //let root1_neigbours = topology.get_mut(&root1).unwrap();
-            //root1_neigbours.push(c.clone());
-            let root2_neigbours = topology.get_mut(&root2).unwrap();
-            root2_neigbours.push(c.clone());
-        }
-
-        topology.get_mut(&root1).unwrap().push(root2.clone());
-        topology.get_mut(&root2).unwrap().push(root1.clone());
-        //eprintln!("Topology: {:?}", topology);
-
-        let neighbours = topology[&cluster_state.node_id].clone();
+        let strategy = Strategy::from_env(Strategy::MultiRoot { roots: 2 })?;
+        let neighbours = match strategy {
+            Strategy::Maelstrom => Vec::new(),
+            _ => strategy.build(&cluster_state.node_ids)?[&cluster_state.node_id].clone(),
+        };
         eprintln!("Discovered neighbours: {:?}", &neighbours);
 
         let server = BroadcastServer {
-            messages: HashSet::<usize>::new(),
+            messages: RangeSet::new(),
             seen,
             neighbours,
-            outbox: HashSet::new(),
+            outbox: RangeSet::new(),
+            strategy,
         };
 
         Ok(server)
@@ -117,13 +81,24 @@ impl Server<Payload, Timer> for BroadcastServer {
 
     fn on_message(
         &mut self,
-        _: &ClusterState,
+        cluster_state: &ClusterState,
         io: &mut IO<Payload>,
         input: Message<Payload>,
     ) -> Result<()> {
         let payload = &input.body.payload;
         match payload {
-            Payload::Topology { topology: _ } => {
+            Payload::Topology { topology } => {
+                if self.strategy == Strategy::Maelstrom {
+                    let neighbours = topology
+                        .get(&cluster_state.node_id)
+                        .unwrap()
+                        .iter()
+                        .cloned();
+
+                    self.neighbours.extend(neighbours);
+                    eprintln!("Discovered neighbours: {:?}", &self.neighbours);
+                }
+
                 let reply = Payload::TopologyOk;
                 io.rpc_reply_to(&input, &reply)?;
             }
@@ -136,9 +111,9 @@ impl Server<Payload, Timer> for BroadcastServer {
                         }
                     }
                     (None, Some(b)) => {
-                        let diff: Vec<usize> = b.difference(&self.messages).cloned().collect();
-                        self.messages.extend(diff);
-                        self.outbox.extend(b);
+                        let new = b.difference(&self.messages);
+                        self.messages.union(&new);
+                        self.outbox.union(&new);
                     }
                     (None, None) => bail!("Impossible"),
                     (Some(_), Some(_)) => todo!("Impossible"),
@@ -151,23 +126,20 @@ impl Server<Payload, Timer> for BroadcastServer {
                 io.rpc_mark_completed(&input);
             }
             Payload::Read => {
-                let values = self.messages.to_owned();
                 let reply = Payload::ReadOk {
-                    messages: values.into_iter().collect(),
+                    messages: self.messages.iter().collect(),
                 };
                 io.rpc_reply_to(&input, &reply)?;
             }
             Payload::ReadOk { .. } => bail!("unexpected read_ok message"),
             Payload::Gossip { messages } => {
-                let new = messages
-                    .iter()
-                    .copied()
-                    .filter(|&m| self.messages.insert(m));
+                let new = messages.difference(&self.messages);
+                self.messages.union(&new);
 
                 self.seen
                     .get_mut(&input.src)
                     .expect("got gossip from unknown node")
-                    .extend(new);
+                    .union(&new);
             }
         };
 
@@ -190,7 +162,7 @@ impl Server<Payload, Timer> for BroadcastServer {
                         _ = io.rpc_request_with_retry(n, &broadcast, Duration::from_millis(400))?;
                     }
 
-                    self.outbox.clear();
+                    self.outbox = RangeSet::new();
                 }
             }
         }
@@ -201,6 +173,7 @@ impl Server<Payload, Timer> for BroadcastServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         timeout: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
@@ -1,10 +1,11 @@
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
-use rand::seq::SliceRandom;
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
+use gossip_glomers_rs::{
+    hyparview::{HyParView, Membership},
+    range_set::RangeSet,
+    topology::{Strategy, Topology},
+    ClusterState, Message, Node, Server, Timers, IO,
 };
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{bail, Result};
 
@@ -12,100 +13,94 @@ use anyhow::{bail, Result};
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
+    Topology { topology: Topology },
     TopologyOk,
-    Broadcast {
-        message: usize,
-    },
+    Broadcast { message: usize },
     BroadcastOk,
     Read,
-    ReadOk {
-        messages: Vec<usize>,
-    },
-    Gossip {
-        messages: Vec<usize>,
-    },
+    ReadOk { messages: Vec<usize> },
+    Gossip { messages: RangeSet },
+    GossipOk,
+    Membership { membership: Membership },
 }
 
 #[derive(Clone, Copy, Debug)]
 enum Timer {
     Gossip,
+    Membership,
 }
 
+/// Gossip rounds after which an unacknowledged gossip is given up on; its
+/// values simply go out again in a later round.
+const GOSSIP_ACK_ROUNDS: usize = 8;
+
 fn main() -> anyhow::Result<()> {
     let mut node = Node::<BroadcastServer, Payload, Timer>::init()?;
     node.run()
 }
 
 struct BroadcastServer {
-    messages: HashSet<usize>,
-    seen: HashMap<String, HashSet<usize>>,
+    messages: RangeSet,
+    seen: HashMap<String, RangeSet>,
     neighbours: Vec<String>,
+    strategy: Strategy,
+    membership: HyParView,
+    in_flight: HashMap<usize, (usize, String, RangeSet)>,
+    rounds: usize,
+}
+
+impl BroadcastServer {
+    fn mark_seen(&mut self, node: &str, messages: &RangeSet) {
+        if let Some(seen) = self.seen.get_mut(node) {
+            seen.union(messages);
+        }
+    }
+
+    /// Values already on their way to `node` in an unacknowledged gossip.
+    fn pending(&self, node: &str) -> RangeSet {
+        let mut pending = RangeSet::new();
+        for (_, n, messages) in self.in_flight.values() {
+            if n == node {
+                pending.union(messages);
+            }
+        }
+
+        pending
+    }
 }
 
 impl Server<Payload, Timer> for BroadcastServer {
     fn init(
         cluster_state: &ClusterState,
-        _timers: &mut Timers<Payload, Timer>,
+        timers: &mut Timers<Payload, Timer>,
     ) -> Result<BroadcastServer> {
-        //timers.register_timer(Timer::Gossip, Duration::from_millis(250));
+        timers.register_timer(Timer::Gossip, Duration::from_millis(250));
+        timers.register_timer(Timer::Membership, Duration::from_millis(200));
 
         let seen = cluster_state
             .node_ids
             .iter()
-            .map(|n| (n.to_string(), HashSet::new()))
+            .map(|n| (n.to_string(), RangeSet::new()))
             .collect();
 
-        let mut nodes: Vec<String> = (0..cluster_state.node_ids.len())
-            .map(|n| format!("n{}", n))
-            .collect();
-        let mut topology: HashMap<String, Vec<String>> =
-            nodes.iter().map(|n| (n.clone(), Vec::new())).collect();
-
-        let root = nodes.remove(0);
-        for c in nodes {
-            let neighours = topology.get_mut(&c).unwrap();
-            neighours.push(root.clone());
-            let root_neigbours = topology.get_mut(&root).unwrap();
-            root_neigbours.push(c.clone());
-        }
-
-        //let root1 = nodes.remove(0);
-        //let root2 = nodes.remove(0);
-        //let mid = nodes.len() / 2;
-
-        //let (children1, children2) = nodes.split_at(mid);
-        //for c in children1 {
-        //    let neighours = topology.get_mut(c).unwrap();
-        //    neighours.push(root1.clone());
-        //    neighours.push(root2.clone());
-        //    let root1_neigbours = topology.get_mut(&root1).unwrap();
-        //    root1_neigbours.push(c.clone());
-        //    let root2_neigbours = topology.get_mut(&root2).unwrap();
-        //    root2_neigbours.push(c.clone());
-        //}
-
-        //for c in children2 {
-        //    let neighours = topology.get_mut(c).unwrap();
-        //    neighours.push(root1.clone());
-        //    neighours.push(root2.clone());
-        //    let root1_neigbours = topology.get_mut(&root1).unwrap();
-        //    root1_neigbours.push(c.clone());
-        //    let root2_neigbours = topology.get_mut(&root2).unwrap();
-        //    root2_neigbours.push(c.clone());
-        //}
-
-        //eprintln!("Topology: {:?}", topology);
-
-        let neighbours = topology[&cluster_state.node_id].clone();
+        let strategy = Strategy::from_env(Strategy::Star)?;
+        let neighbours = match strategy {
+            Strategy::Maelstrom => Vec::new(),
+            _ => strategy.build(&cluster_state.node_ids)?[&cluster_state.node_id].clone(),
+        };
         eprintln!("Discovered neighbours: {:?}", &neighbours);
 
+        let mut membership = HyParView::new(cluster_state);
+        membership.join();
+
         let server = BroadcastServer {
-            messages: HashSet::<usize>::new(),
+            messages: RangeSet::new(),
             seen,
             neighbours,
+            strategy,
+            membership,
+            in_flight: HashMap::new(),
+            rounds: 0,
         };
 
         Ok(server)
@@ -113,27 +108,30 @@ impl Server<Payload, Timer> for BroadcastServer {
 
     fn on_message(
         &mut self,
-        _cluster_state: &ClusterState,
+        cluster_state: &ClusterState,
         io: &mut IO<Payload>,
         input: Message<Payload>,
     ) -> Result<()> {
         let payload = &input.body.payload;
         match payload {
-            Payload::Topology { topology: _ } => {
-                //let neighbours = topology
-                //    .get(&cluster_state.node_id)
-                //    .unwrap()
-                //    .iter()
-                //    .cloned();
-
-                //self.neighbours.extend(neighbours);
-                //eprintln!("Discovered neighbours: {:?}", &self.neighbours);
+            Payload::Topology { topology } => {
+                if self.strategy == Strategy::Maelstrom {
+                    let neighbours = topology
+                        .get(&cluster_state.node_id)
+                        .unwrap()
+                        .iter()
+                        .cloned();
+
+                    self.neighbours.extend(neighbours);
+                    eprintln!("Discovered neighbours: {:?}", &self.neighbours);
+                }
 
                 let reply = Payload::TopologyOk;
                 io.rpc_reply_to(&input, &reply)?;
             }
             Payload::TopologyOk => bail!("unexpected topology_ok message"),
             Payload::Broadcast { message } => {
+                self.mark_seen(&input.src, &RangeSet::from_iter([*message]));
                 if self.messages.insert(*message) {
                     eprintln!(
                         "Sending message {} to all our neighbours: {:?}",
@@ -163,23 +161,33 @@ impl Server<Payload, Timer> for BroadcastServer {
                 io.rpc_mark_completed(&input);
             }
             Payload::Read => {
-                let values = self.messages.to_owned();
                 let reply = Payload::ReadOk {
-                    messages: values.into_iter().collect(),
+                    messages: self.messages.iter().collect(),
                 };
                 io.rpc_reply_to(&input, &reply)?;
             }
             Payload::ReadOk { .. } => bail!("unexpected read_ok message"),
             Payload::Gossip { messages } => {
-                let new = messages
-                    .iter()
-                    .copied()
-                    .filter(|&m| self.messages.insert(m));
-
-                self.seen
-                    .get_mut(&input.src)
-                    .expect("got gossip from unknown node")
-                    .extend(new);
+                self.messages.union(messages);
+                self.mark_seen(&input.src, messages);
+
+                io.rpc_reply_to(&input, &Payload::GossipOk)?;
+            }
+            Payload::GossipOk => {
+                let acked = input
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.in_flight.remove(&id));
+
+                if let Some((_, node, messages)) = acked {
+                    self.mark_seen(&node, &messages);
+                }
+            }
+            Payload::Membership { membership } => {
+                let now = cluster_state.clock.now();
+                self.membership.on_message(now, &input.src, membership);
+                self.membership
+                    .flush(io, |membership| Payload::Membership { membership })?;
             }
         };
 
@@ -197,21 +205,32 @@ impl Server<Payload, Timer> for BroadcastServer {
     {
         match input {
             Timer::Gossip => {
-                let nodes: Vec<&String> = cluster_state
-                    .node_ids
-                    .choose_multiple(&mut rand::thread_rng(), 5)
-                    .collect();
-                for n in &nodes {
-                    //let dst_seen = &self.seen[n];
-                    //let to_send: Vec<usize> = self.messages.difference(dst_seen).copied().collect();
-                    let to_send: Vec<usize> = self.messages.iter().copied().collect();
+                self.rounds += 1;
+                let rounds = self.rounds;
+                self.in_flight
+                    .retain(|_, (round, _, _)| rounds - *round < GOSSIP_ACK_ROUNDS);
+
+                for n in self.membership.active_view() {
+                    let mut to_send = match self.seen.get(n) {
+                        Some(seen) => self.messages.difference(seen),
+                        None => self.messages.clone(),
+                    };
+                    to_send = to_send.difference(&self.pending(n));
 
                     if !to_send.is_empty() {
-                        let gossip = Payload::Gossip { messages: to_send };
-                        io.fire_and_forget(n, &gossip)?;
+                        let gossip = Payload::Gossip {
+                            messages: to_send.clone(),
+                        };
+                        let id = io.send(n, None, &gossip)?;
+                        self.in_flight.insert(id, (rounds, n.clone(), to_send));
                     }
                 }
             }
+            Timer::Membership => {
+                self.membership.tick(cluster_state.clock.now());
+                self.membership
+                    .flush(io, |membership| Payload::Membership { membership })?;
+            }
         }
 
         Ok(())
@@ -220,11 +239,13 @@ impl Server<Payload, Timer> for BroadcastServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         timeout: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
         Self: Sized,
     {
+        // A slow reply isn't a failed peer: HyParView's pings decide that.
         eprintln!("Timeout: {:?}", timeout);
 
         Ok(())
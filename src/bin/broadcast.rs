@@ -1,9 +1,12 @@
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::{HashMap, HashSet},
-    time::Duration,
+use gossip_glomers_rs::{
+    iblt::Iblt,
+    range_set::RangeSet,
+    topology::{Strategy, Topology},
+    ClusterState, Message, Node, Server, Timers, IO,
 };
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{bail, Result};
 
@@ -11,58 +14,105 @@ use anyhow::{bail, Result};
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
+    Topology { topology: Topology },
     TopologyOk,
-    Broadcast {
-        message: usize,
-    },
+    Broadcast { message: usize },
     BroadcastOk,
     Read,
-    ReadOk {
-        messages: Vec<usize>,
-    },
-    Gossip {
-        messages: Vec<usize>,
-    },
+    ReadOk { messages: Vec<usize> },
+    Gossip { messages: RangeSet },
+    GossipOk,
+    Reconcile { iblt: Iblt },
+    ReconcileOk { messages: RangeSet },
+    ReconcileFailed,
 }
 
 #[derive(Clone, Copy, Debug)]
 enum Timer {
     Gossip,
+    Reconcile,
 }
 
+/// Gossip rounds after which an unacknowledged gossip is given up on; its
+/// values simply go out again in a later round.
+const GOSSIP_ACK_ROUNDS: usize = 8;
+
+/// Reconciliation tables start small, double whenever the peer can't decode
+/// them and shrink again once it can.
+const MIN_RECONCILE_CELLS: usize = 24;
+const MAX_RECONCILE_CELLS: usize = 3072;
+
 fn main() -> anyhow::Result<()> {
     let mut node = Node::<BroadcastServer, Payload, Timer>::init()?;
     node.run()
 }
 
 struct BroadcastServer {
-    messages: HashSet<usize>,
-    seen: HashMap<String, HashSet<usize>>,
+    messages: RangeSet,
+    seen: HashMap<String, RangeSet>,
     neighbours: Vec<String>,
+    strategy: Strategy,
+    in_flight: HashMap<usize, (usize, String, RangeSet)>,
+    /// Outstanding reconciliations, with the values we had when we sent them.
+    reconciling: HashMap<usize, (usize, String, RangeSet)>,
+    reconcile_cells: HashMap<String, usize>,
+    rounds: usize,
+}
+
+impl BroadcastServer {
+    fn mark_seen(&mut self, node: &str, messages: &RangeSet) {
+        self.seen
+            .entry(node.to_string())
+            .or_default()
+            .union(messages);
+    }
+
+    /// Values already on their way to `node` in an unacknowledged gossip.
+    fn pending(&self, node: &str) -> RangeSet {
+        let mut pending = RangeSet::new();
+        for (_, n, messages) in self.in_flight.values() {
+            if n == node {
+                pending.union(messages);
+            }
+        }
+
+        pending
+    }
+
+    fn iblt(&self, cells: usize) -> Iblt {
+        Iblt::from_keys(cells, self.messages.iter().map(|m| m as u64))
+    }
 }
 
 impl Server<Payload, Timer> for BroadcastServer {
     fn init(
         cluster_state: &ClusterState,
-        _timers: &mut Timers<Payload, Timer>,
+        timers: &mut Timers<Payload, Timer>,
     ) -> Result<BroadcastServer> {
-        //timers.register_timer(Timer::Gossip, Duration::from_millis(3400));
+        timers.register_timer(Timer::Gossip, Duration::from_millis(250));
+        timers.register_timer(Timer::Reconcile, Duration::from_millis(500));
 
         let seen = cluster_state
             .node_ids
             .iter()
-            .map(|n| (n.to_string(), HashSet::new()))
+            .map(|n| (n.to_string(), RangeSet::new()))
             .collect();
 
-        let neighbours = Vec::new();
+        let strategy = Strategy::from_env(Strategy::Maelstrom)?;
+        let neighbours = match strategy {
+            Strategy::Maelstrom => Vec::new(),
+            _ => strategy.build(&cluster_state.node_ids)?[&cluster_state.node_id].clone(),
+        };
 
         let server = BroadcastServer {
-            messages: HashSet::<usize>::new(),
+            messages: RangeSet::new(),
             seen,
             neighbours,
+            strategy,
+            in_flight: HashMap::new(),
+            reconciling: HashMap::new(),
+            reconcile_cells: HashMap::new(),
+            rounds: 0,
         };
 
         Ok(server)
@@ -77,13 +127,15 @@ impl Server<Payload, Timer> for BroadcastServer {
         let payload = &input.body.payload;
         match payload {
             Payload::Topology { topology } => {
-                let neighbours = topology
-                    .get(&cluster_state.node_id)
-                    .unwrap()
-                    .iter()
-                    .cloned();
+                if self.strategy == Strategy::Maelstrom {
+                    let neighbours = topology
+                        .get(&cluster_state.node_id)
+                        .unwrap()
+                        .iter()
+                        .cloned();
 
-                self.neighbours.extend(neighbours);
+                    self.neighbours.extend(neighbours);
+                }
                 eprintln!("Discovered neighbours: {:?}", &self.neighbours);
 
                 let reply = Payload::TopologyOk;
@@ -91,62 +143,166 @@ impl Server<Payload, Timer> for BroadcastServer {
             }
             Payload::TopologyOk => bail!("unexpected topology_ok message"),
             Payload::Broadcast { message } => {
-                if !self.messages.contains(message) {
-                    for n in self.neighbours.iter() {
-                        let broadcast = Payload::Broadcast { message: *message };
-
-                        _ = io.rpc_request_with_retry(n, &broadcast, Duration::from_millis(300))?;
-                    }
-                }
-
                 self.messages.insert(message.to_owned());
 
                 let reply = Payload::BroadcastOk;
                 io.rpc_reply_to(&input, &reply)?;
             }
-            Payload::BroadcastOk => {
-                io.rpc_mark_completed(&input);
-            }
+            Payload::BroadcastOk => bail!("unexpected broadcast_ok message"),
             Payload::Read => {
-                let values = self.messages.to_owned();
                 let reply = Payload::ReadOk {
-                    messages: values.into_iter().collect(),
+                    messages: self.messages.iter().collect(),
                 };
                 io.rpc_reply_to(&input, &reply)?;
             }
             Payload::ReadOk { .. } => bail!("unexpected read_ok message"),
             Payload::Gossip { messages } => {
-                let new = messages
-                    .iter()
-                    .copied()
-                    .filter(|&m| self.messages.insert(m));
+                self.messages.union(messages);
+                self.mark_seen(&input.src, messages);
+
+                io.rpc_reply_to(&input, &Payload::GossipOk)?;
+            }
+            Payload::GossipOk => {
+                let acked = input
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.in_flight.remove(&id));
+
+                if let Some((_, node, messages)) = acked {
+                    self.mark_seen(&node, &messages);
+                }
+            }
+            Payload::Reconcile { iblt } => {
+                let decoded = self
+                    .iblt(iblt.cells())
+                    .subtract(iblt)
+                    .and_then(Iblt::decode);
+                let Some(difference) = decoded else {
+                    io.rpc_reply_to(&input, &Payload::ReconcileFailed)?;
+                    return Ok(());
+                };
+
+                let ours: RangeSet = difference.ours.iter().map(|&m| m as usize).collect();
+                let theirs: RangeSet = difference.theirs.iter().map(|&m| m as usize).collect();
 
-                self.seen
-                    .get_mut(&input.src)
-                    .expect("got gossip from unknown node")
-                    .extend(new);
+                // Everything we have apart from our extras, plus their extras, is
+                // exactly what they had when they built the table.
+                let mut known = self.messages.difference(&ours);
+                known.union(&theirs);
+                self.mark_seen(&input.src, &known);
+                self.messages.union(&theirs);
+
+                let reply = Payload::ReconcileOk { messages: ours };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::ReconcileOk { messages } => {
+                let reconciled = input
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.reconciling.remove(&id));
+
+                if let Some((_, node, mut known)) = reconciled {
+                    known.union(messages);
+                    self.mark_seen(&node, &known);
+
+                    let cells = self.reconcile_cells.entry(node).or_default();
+                    *cells = (*cells / 2).max(MIN_RECONCILE_CELLS);
+                }
+                self.messages.union(messages);
+            }
+            Payload::ReconcileFailed => {
+                let reconciled = input
+                    .body
+                    .in_reply_to
+                    .and_then(|id| self.reconciling.remove(&id));
+
+                if let Some((_, node, _)) = reconciled {
+                    let cells = self.reconcile_cells.entry(node).or_default();
+                    *cells = (*cells * 2).clamp(MIN_RECONCILE_CELLS, MAX_RECONCILE_CELLS);
+                }
             }
         };
 
         Ok(())
     }
 
-    fn on_timer(&mut self, _: &ClusterState, io: &mut IO<Payload>, input: Timer) -> Result<()>
+    fn on_timer(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        input: Timer,
+    ) -> Result<()>
     where
         Self: Sized,
     {
         match input {
             Timer::Gossip => {
+                self.rounds += 1;
+                let rounds = self.rounds;
+                self.in_flight
+                    .retain(|_, (round, _, _)| rounds - *round < GOSSIP_ACK_ROUNDS);
+                self.reconciling
+                    .retain(|_, (round, _, _)| rounds - *round < GOSSIP_ACK_ROUNDS);
+
                 for n in &self.neighbours {
-                    let dst_seen = &self.seen[n];
-                    let to_send: Vec<usize> = self.messages.difference(dst_seen).copied().collect();
+                    let mut to_send = match self.seen.get(n) {
+                        Some(seen) => self.messages.difference(seen),
+                        None => self.messages.clone(),
+                    };
+                    to_send = to_send.difference(&self.pending(n));
 
                     if !to_send.is_empty() {
-                        let gossip = Payload::Gossip { messages: to_send };
-                        io.fire_and_forget(n, &gossip)?;
+                        let gossip = Payload::Gossip {
+                            messages: to_send.clone(),
+                        };
+                        let id = io.send(n, None, &gossip)?;
+                        self.in_flight.insert(id, (rounds, n.clone(), to_send));
                     }
                 }
             }
+            Timer::Reconcile => {
+                // Gossip already keeps neighbours in sync, so reconcile with the
+                // rest of the cluster. That also bridges a topology that got
+                // cut in two. Only with everybody a neighbour are they picked.
+                let others = cluster_state
+                    .node_ids
+                    .iter()
+                    .filter(|&n| n != &cluster_state.node_id);
+                let mut peers: Vec<&String> = others
+                    .clone()
+                    .filter(|&n| !self.neighbours.contains(n))
+                    .collect();
+                if peers.is_empty() {
+                    peers = others.collect();
+                }
+
+                // Peers that have acknowledged everything we have don't need
+                // it; anything they have that we don't comes up when they
+                // reconcile with us.
+                let unsure: Vec<&String> = peers
+                    .into_iter()
+                    .filter(|&n| {
+                        self.seen
+                            .get(n)
+                            .is_none_or(|seen| !self.messages.difference(seen).is_empty())
+                    })
+                    .collect();
+
+                let Some(n) = unsure.choose(&mut rand::thread_rng()).map(|&n| n.clone()) else {
+                    return Ok(());
+                };
+
+                let cells = *self
+                    .reconcile_cells
+                    .entry(n.clone())
+                    .or_insert(MIN_RECONCILE_CELLS);
+                let reconcile = Payload::Reconcile {
+                    iblt: self.iblt(cells),
+                };
+                let id = io.send(&n, None, &reconcile)?;
+                self.reconciling
+                    .insert(id, (self.rounds, n, self.messages.clone()));
+            }
         }
 
         Ok(())
@@ -155,6 +311,7 @@ impl Server<Payload, Timer> for BroadcastServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         timeout: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
@@ -53,6 +53,7 @@ impl Server<Payload, ()> for EchoServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         _: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
@@ -0,0 +1,348 @@
+use std::{collections::HashMap, mem, time::Duration};
+
+use gossip_glomers_rs::{
+    kv::{KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, SEQ_KV},
+    ClusterState, Message, Node, Server, Timers, IO,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use anyhow::{bail, Result};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Payload {
+    Add {
+        delta: usize,
+    },
+    AddOk,
+    /// Clients read without a key, seq-kv reads always have one.
+    Read {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+    ReadOk {
+        value: usize,
+    },
+
+    Write {
+        key: String,
+        value: usize,
+    },
+    WriteOk,
+    Cas {
+        key: String,
+        from: usize,
+        to: usize,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Timer {
+    Flush,
+}
+
+/// Written with a random value before every client read. seq-kv orders our
+/// reads after our own writes, so the reads that follow can't return values
+/// older than that write.
+const SYNC_KEY: &str = "g-counter-sync";
+const KV_TIMEOUT: Duration = Duration::from_millis(500);
+
+fn main() -> anyhow::Result<()> {
+    let mut node = Node::<GCounterServer, Payload, Timer>::init()?;
+    node.run()
+}
+
+fn counter_key(node: &str) -> String {
+    format!("g-counter-{}", node)
+}
+
+/// What an outstanding seq-kv request was for.
+#[derive(Clone, Copy, Debug)]
+enum Pending {
+    Sync { read: usize },
+    Count { read: usize },
+    ReadOwn,
+    Cas,
+}
+
+struct ClientRead {
+    input: Message<Payload>,
+    remaining: usize,
+    total: usize,
+}
+
+/// g-counter on top of seq-kv: every node owns one key holding its
+/// contribution and adds to it with `cas`; reads sum everyone's keys.
+struct GCounterServer {
+    key: String,
+    node_keys: Vec<String>,
+    /// Our key's value as of our last read or successful `cas`. `None` when it
+    /// has to be read again.
+    committed: Option<usize>,
+    /// Adds waiting for the next `cas`, and the ones in the current one.
+    /// Clients are only acknowledged once their delta is in seq-kv.
+    queued: Vec<(Message<Payload>, usize)>,
+    flushing: Vec<(Message<Payload>, usize)>,
+    /// Values the current batch tried to `cas` our key to, with how many of
+    /// the adds in `flushing` each included. A failed or timed out `cas` may
+    /// still have gone through, and since only we write our key, finding one
+    /// of these on a re-read means it did.
+    attempts: Vec<(usize, usize)>,
+    in_flight: bool,
+    pending: HashMap<usize, Pending>,
+    retries: Vec<(Payload, Pending)>,
+    reads: HashMap<usize, ClientRead>,
+    next_read: usize,
+}
+
+impl GCounterServer {
+    fn request(&mut self, io: &mut IO<Payload>, payload: &Payload, pending: Pending) -> Result<()> {
+        let id = io.rpc_request(SEQ_KV, payload, KV_TIMEOUT, false)?;
+        self.pending.insert(id, pending);
+
+        Ok(())
+    }
+
+    fn flush(&mut self, io: &mut IO<Payload>) -> Result<()> {
+        if self.in_flight || (self.queued.is_empty() && self.flushing.is_empty()) {
+            return Ok(());
+        }
+
+        let Some(committed) = self.committed else {
+            let read = Payload::Read {
+                key: Some(self.key.clone()),
+            };
+            self.request(io, &read, Pending::ReadOwn)?;
+            self.in_flight = true;
+            return Ok(());
+        };
+
+        self.flushing.append(&mut self.queued);
+        let to = committed + self.flushing.iter().map(|(_, d)| d).sum::<usize>();
+        let cas = Payload::Cas {
+            key: self.key.clone(),
+            from: committed,
+            to,
+            create_if_not_exists: true,
+        };
+        self.request(io, &cas, Pending::Cas)?;
+        self.attempts.push((to, self.flushing.len()));
+        self.in_flight = true;
+
+        Ok(())
+    }
+
+    /// Acknowledges the first `adds` in `flushing`, which are in `value`. The
+    /// rest go out again on top of it.
+    fn flushed(&mut self, io: &mut IO<Payload>, value: usize, adds: usize) -> Result<()> {
+        self.committed = Some(value);
+        self.attempts.clear();
+        for (input, _) in self.flushing.drain(..adds) {
+            io.rpc_reply_to(&input, &Payload::AddOk)?;
+        }
+
+        Ok(())
+    }
+
+    fn on_own_value(&mut self, io: &mut IO<Payload>, value: usize) -> Result<()> {
+        self.in_flight = false;
+        if let Some(&(_, adds)) = self.attempts.iter().find(|&&(to, _)| to == value) {
+            self.flushed(io, value, adds)?;
+        } else {
+            self.committed = Some(value);
+        }
+
+        self.flush(io)
+    }
+
+    fn on_count(&mut self, io: &mut IO<Payload>, read: usize, value: usize) -> Result<()> {
+        let Some(client_read) = self.reads.get_mut(&read) else {
+            return Ok(());
+        };
+
+        client_read.total += value;
+        client_read.remaining -= 1;
+        if client_read.remaining == 0 {
+            let client_read = self.reads.remove(&read).expect("checked above");
+            let read_ok = Payload::ReadOk {
+                value: client_read.total,
+            };
+            io.rpc_reply_to(&client_read.input, &read_ok)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Server<Payload, Timer> for GCounterServer {
+    fn init(
+        cluster_state: &ClusterState,
+        timers: &mut Timers<Payload, Timer>,
+    ) -> Result<GCounterServer> {
+        timers.register_timer(Timer::Flush, Duration::from_millis(100));
+
+        Ok(GCounterServer {
+            key: counter_key(&cluster_state.node_id),
+            node_keys: cluster_state
+                .node_ids
+                .iter()
+                .map(|n| counter_key(n))
+                .collect(),
+            committed: None,
+            queued: Vec::new(),
+            flushing: Vec::new(),
+            attempts: Vec::new(),
+            in_flight: false,
+            pending: HashMap::new(),
+            retries: Vec::new(),
+            reads: HashMap::new(),
+            next_read: 0,
+        })
+    }
+
+    fn on_message(
+        &mut self,
+        _: &ClusterState,
+        io: &mut IO<Payload>,
+        input: Message<Payload>,
+    ) -> Result<()> {
+        let payload = &input.body.payload;
+        if let Payload::Add { delta } = payload {
+            self.queued.push((input.clone(), *delta));
+            return self.flush(io);
+        }
+
+        if let Payload::Read { key: None } = payload {
+            let read = self.next_read;
+            self.next_read += 1;
+            self.reads.insert(
+                read,
+                ClientRead {
+                    input: input.clone(),
+                    remaining: self.node_keys.len(),
+                    total: 0,
+                },
+            );
+
+            let sync = Payload::Write {
+                key: SYNC_KEY.to_string(),
+                value: rand::thread_rng().gen(),
+            };
+            return self.request(io, &sync, Pending::Sync { read });
+        }
+
+        let Some(pending) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.pending.remove(&id))
+        else {
+            eprintln!("received late response");
+            return Ok(());
+        };
+        io.rpc_mark_completed(&input);
+
+        match (pending, payload) {
+            (Pending::Sync { read }, Payload::WriteOk) => {
+                for key in self.node_keys.clone() {
+                    let read_key = Payload::Read { key: Some(key) };
+                    self.request(io, &read_key, Pending::Count { read })?;
+                }
+            }
+            (Pending::Count { read }, Payload::ReadOk { value }) => {
+                self.on_count(io, read, *value)?;
+            }
+            (Pending::Count { read }, Payload::Error { code, .. })
+                if *code == KEY_DOES_NOT_EXIST =>
+            {
+                self.on_count(io, read, 0)?;
+            }
+            (Pending::ReadOwn, Payload::ReadOk { value }) => {
+                self.on_own_value(io, *value)?;
+            }
+            (Pending::ReadOwn, Payload::Error { code, .. }) if *code == KEY_DOES_NOT_EXIST => {
+                self.on_own_value(io, 0)?;
+            }
+            (Pending::Cas, Payload::CasOk) => {
+                self.in_flight = false;
+                let (to, adds) = self.attempts.last().copied().unwrap_or_default();
+                self.flushed(io, to, adds)?;
+                self.flush(io)?;
+            }
+            (Pending::Cas, Payload::Error { code, .. }) if *code == PRECONDITION_FAILED => {
+                self.in_flight = false;
+                self.committed = None;
+                self.flush(io)?;
+            }
+            (Pending::Sync { read } | Pending::Count { read }, Payload::Error { code, text }) => {
+                eprintln!("read failed: {} ({})", text, code);
+                if let Some(client_read) = self.reads.remove(&read) {
+                    let error = Payload::Error {
+                        code: *code,
+                        text: text.clone(),
+                    };
+                    io.rpc_reply_to(&client_read.input, &error)?;
+                }
+            }
+            (Pending::ReadOwn | Pending::Cas, Payload::Error { code, text }) => {
+                // Tried again on the next flush tick.
+                eprintln!("updating our count failed: {} ({})", text, code);
+                self.in_flight = false;
+                self.committed = None;
+            }
+            (_, payload) => bail!("unexpected payload {:?}", payload),
+        }
+
+        Ok(())
+    }
+
+    fn on_timer(&mut self, _: &ClusterState, io: &mut IO<Payload>, timer: Timer) -> Result<()>
+    where
+        Self: Sized,
+    {
+        match timer {
+            Timer::Flush => {
+                for (payload, pending) in mem::take(&mut self.retries) {
+                    self.request(io, &payload, pending)?;
+                }
+
+                self.flush(io)
+            }
+        }
+    }
+
+    fn on_rpc_timeout(
+        &mut self,
+        _: &ClusterState,
+        _: &mut IO<Payload>,
+        timeout: gossip_glomers_rs::Request<Payload>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        eprintln!("Timeout: {:?}", timeout);
+        let Some(pending) = self.pending.remove(&timeout.id) else {
+            return Ok(());
+        };
+
+        match pending {
+            Pending::Sync { .. } | Pending::Count { .. } => {
+                self.retries.push((timeout.payload, pending));
+            }
+            Pending::ReadOwn | Pending::Cas => {
+                self.in_flight = false;
+                self.committed = None;
+            }
+        }
+
+        Ok(())
+    }
+}
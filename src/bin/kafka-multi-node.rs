@@ -1,19 +1,48 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
-    time::Duration,
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use itertools::Itertools;
-
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
+use gossip_glomers_rs::{
+    anti_entropy::incarnation,
+    groups::{Assignor, Coordinator, DEFAULT_SESSION_TIMEOUT},
+    hash::fnv1a,
+    ids::Snowflake,
+    kv::{KEY_DOES_NOT_EXIST, LIN_KV, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT},
+    merkle::{MerkleSync, MerkleTree, NodeIndex, DEFAULT_DEPTH},
+    partition::{HashRing, DEFAULT_VIRTUAL_NODES},
+    producers::{self, ProducerId, Producers, Sequence},
+    storage::{self, LogStore, Retention, Storage},
+    time::Clock,
+    ClusterState, Message, Node, Server, Timers, IO,
+};
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 
 type Offset = usize;
 type Record = (Offset, usize);
 type ForwardedFor = (String, usize);
 
+/// Leadership of a key, kept in lin-kv under `lease_key(key)`. A new leader
+/// takes the next epoch and starts from its own copy of the log; `start` is
+/// where that copy ended, so followers know where to truncate theirs.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Lease {
+    leader: String,
+    epoch: u64,
+    /// Wall clock milliseconds since the Unix epoch.
+    expires: u64,
+    start: Offset,
+    /// In-sync replicas, the leader included. Every acknowledged record is on
+    /// all of them, so only they may take over once the lease expires.
+    isr: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -21,6 +50,15 @@ enum Payload {
     Send {
         key: String,
         msg: usize,
+        /// Compacted logs only keep the latest message for each sub-key.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        /// Sends with a producer id and a sequence number are only appended
+        /// once, however often they're retried. The leader checks them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<ProducerId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
         #[serde(skip_serializing_if = "Option::is_none")]
         forwarded_for: Option<ForwardedFor>,
     },
@@ -29,79 +67,1120 @@ enum Payload {
         #[serde(skip_serializing_if = "Option::is_none")]
         forwarded_for: Option<ForwardedFor>,
     },
+    InitProducerId,
+    InitProducerIdOk {
+        producer_id: ProducerId,
+    },
     Poll {
         offsets: HashMap<String, Offset>,
     },
     PollOk {
         msgs: HashMap<String, Vec<Record>>,
+        /// Where each polled log starts; the offsets before it are gone.
+        log_start_offsets: HashMap<String, Offset>,
+    },
+    DeleteRecords {
+        offsets: HashMap<String, Offset>,
     },
+    DeleteRecordsOk {
+        log_start_offsets: HashMap<String, Offset>,
+    },
+    /// Without a group, offsets are shared with every other consumer that
+    /// doesn't name one. Commits for a group go to its coordinator.
     CommitOffsets {
         offsets: HashMap<String, Offset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        member_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        generation: Option<u64>,
     },
     CommitOffsetsOk,
     ListCommittedOffsets {
         keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
     },
     ListCommittedOffsetsOk {
         offsets: HashMap<String, Offset>,
     },
-    ReplicaPoll {
+    /// Pushed by a leader to its followers. Empty batches probe where a
+    /// follower's log ends, or carry a new high watermark.
+    Replicate {
+        key: String,
+        lease: Lease,
+        /// Where the batch was read from. The offsets between it and the
+        /// first record were compacted away.
+        from: Offset,
+        records: Vec<storage::Record>,
+        high_watermark: Offset,
+        log_start: Offset,
+    },
+    ReplicateOk {
+        key: String,
+        epoch: u64,
+        /// Where the follower's log ends now.
+        next: Offset,
+        high_watermark: Offset,
+    },
+    Heartbeat,
+    ReplicateOffsets {
+        #[serde(default)]
+        group: String,
         offsets: HashMap<String, Offset>,
     },
-    ReplicaPollOk {
-        msgs: HashMap<String, Vec<Record>>,
+    ReplicateLogStarts {
+        offsets: HashMap<String, Offset>,
+    },
+    Merkle {
+        sync: MerkleSync<(String, String, Offset)>,
+    },
+    FindCoordinator {
+        group: String,
+    },
+    FindCoordinatorOk {
+        node: String,
+    },
+    /// Members leave `member_id` out the first time they join.
+    JoinGroup {
+        group: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        member_id: Option<String>,
+        keys: BTreeSet<String>,
+        #[serde(default)]
+        assignor: Assignor,
+    },
+    JoinGroupOk {
+        member_id: String,
+        generation: u64,
+        keys: BTreeSet<String>,
+    },
+    GroupHeartbeat {
+        group: String,
+        member_id: String,
+    },
+    GroupHeartbeatOk {
+        generation: u64,
+        keys: BTreeSet<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member_id: String,
+    },
+    LeaveGroupOk,
+
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: Lease,
+    },
+    Cas {
+        key: String,
+        from: Option<Lease>,
+        to: Lease,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        #[serde(default)]
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        forwarded_for: Option<ForwardedFor>,
     },
 }
 
 #[derive(Clone, Copy, Debug)]
 enum Timer {
-    ReplicaPoll,
+    Replicate,
+    Heartbeat,
+    AntiEntropy,
+    Leases,
+    Retention,
+    Sessions,
 }
 
+const LEASE_DURATION: u64 = 1_500;
+/// Leases are renewed once less than this is left on them.
+const RENEW_BEFORE: u64 = 1_000;
+/// A leader stops using its lease this long before it expires, to allow for
+/// clock skew with whoever takes over after it.
+const CLOCK_SAFETY: u64 = 100;
+const LEASE_TIMEOUT: Duration = Duration::from_millis(500);
+const FORWARD_TIMEOUT: Duration = Duration::from_millis(1_000);
+
+/// Environment variable with how many in-sync replicas, the leader included,
+/// a key needs before its records are acknowledged: a number or `all`.
+/// Defaults to a majority of the cluster.
+const MIN_INSYNC_ENV: &str = "KAFKA_MIN_INSYNC";
+/// Followers that haven't caught up with the leader for this long drop out
+/// of the ISR.
+const ISR_LAG: u64 = 1_000;
+/// Sends that aren't committed within this long are failed rather than left
+/// hanging, e.g. while the ISR is too small.
+const ACK_TIMEOUT: u64 = 800;
+
+const MAX_BATCH: usize = 50;
+/// How many records a follower may have unacknowledged before we stop
+/// pushing to it.
+const REPLICATION_WINDOW: usize = 200;
+/// Batches that go unacknowledged this long are pushed again.
+const RETRANSMIT: u64 = 300;
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+const SESSIONS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Offsets committed without naming a group go to this one.
+const NO_GROUP: &str = "";
+
 fn main() -> anyhow::Result<()> {
     let mut node = Node::<KafkaServer, Payload, Timer>::init()?;
     node.run()
 }
 
+/// The leader's view of a follower's copy of a log.
+struct Follower {
+    /// Everything below this is on the follower.
+    matched: Offset,
+    /// Where the next batch starts, or `None` until the follower has told us
+    /// where its log ends.
+    next: Option<Offset>,
+    /// Our log end as of the follower's previous acknowledgement. Getting up
+    /// to it means the follower is keeping up.
+    ack_end: Offset,
+    caught_up_at: u64,
+    /// When the follower last made progress, or we started waiting on it.
+    progress_at: u64,
+    /// The high watermark the follower last acknowledged.
+    high_watermark: Offset,
+}
+
+impl Follower {
+    fn new(end: Offset) -> Self {
+        Follower {
+            matched: 0,
+            next: None,
+            ack_end: end,
+            caught_up_at: 0,
+            progress_at: 0,
+            high_watermark: 0,
+        }
+    }
+}
+
 struct Log {
-    records: Vec<Record>,
+    store: Box<dyn LogStore>,
+    /// Epoch of the lease this log was last written under.
+    epoch: u64,
+    /// Records below this are on every in-sync replica, and are the only
+    /// ones clients get to see.
+    high_watermark: Offset,
+    /// Only kept while we lead the key.
+    followers: HashMap<String, Follower>,
+    /// Sends appended but not committed yet, with when to give up on them.
+    unacked: Vec<(Offset, Message<Payload>, u64)>,
+    /// Where the log was last compacted up to.
+    compacted: Offset,
+    /// Kept on every replica, so a new leader can tell retries apart too.
+    producers: Producers,
 }
 
 impl Log {
-    fn new() -> Self {
-        Log {
-            records: Vec::<Record>::new(),
+    /// Epochs and high watermarks aren't stored, so after a restart the
+    /// records are only trusted again once a leader confirms them. Only
+    /// committed records are ever deleted, though, so the log start is.
+    fn new(store: Box<dyn LogStore>) -> Result<Self> {
+        let start = store.start_offset();
+        Ok(Log {
+            producers: Producers::load(store.as_ref())?,
+            store,
+            epoch: 0,
+            high_watermark: start,
+            followers: HashMap::new(),
+            unacked: Vec::new(),
+            compacted: start,
+        })
+    }
+
+    fn append(
+        &mut self,
+        timestamp: u64,
+        message: usize,
+        producer: Option<(ProducerId, u64)>,
+        sub_key: Option<&str>,
+    ) -> Result<Offset> {
+        let offset = self
+            .store
+            .append(timestamp, &producers::encode(message, producer, sub_key))?;
+        if let Some((producer, sequence)) = producer {
+            self.producers.record(producer, sequence, offset);
+        }
+
+        Ok(offset)
+    }
+
+    /// Appends a batch the leader read from `from`, skipping the records we
+    /// already have. Batches that would leave a gap are dropped; they were
+    /// reordered or follow one that got lost.
+    fn append_records(&mut self, from: Offset, records: &[storage::Record]) -> Result<()> {
+        if from > self.next_offset() {
+            return Ok(());
+        }
+
+        let next = self.next_offset();
+        for record in records.iter().filter(|r| r.offset >= next) {
+            self.store.append_record(record.clone())?;
+            if let Some((producer, sequence)) = producers::producer(record) {
+                self.producers.record(producer, sequence, record.offset);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_committed(&self, offset: Offset) -> Result<Vec<Record>> {
+        let records = self.store.read(offset, 50)?;
+        records
+            .iter()
+            .take_while(|r| r.offset < self.high_watermark)
+            .map(producers::decode)
+            .collect()
+    }
+
+    /// Batches to push to each follower, moving their `next` past them.
+    /// Batches that went unacknowledged for too long are sent again, and a
+    /// follower that hasn't told us where its log ends gets an empty probe.
+    /// With `flush_high_watermark`, idle followers that are behind on the
+    /// high watermark get an empty batch to carry it.
+    fn batches(
+        &mut self,
+        now: u64,
+        flush_high_watermark: bool,
+    ) -> Result<Vec<(String, Offset, Vec<storage::Record>)>> {
+        let end = self.store.next_offset();
+        let mut batches = Vec::new();
+        for (node, follower) in self.followers.iter_mut() {
+            let stalled = now.saturating_sub(follower.progress_at) >= RETRANSMIT;
+            let Some(mut next) = follower.next else {
+                if stalled {
+                    follower.progress_at = now;
+                    batches.push((node.clone(), end, Vec::new()));
+                }
+                continue;
+            };
+
+            if next > follower.matched && stalled {
+                next = follower.matched;
+            }
+            if next == follower.matched {
+                follower.progress_at = now;
+            }
+
+            while next < end && next - follower.matched < REPLICATION_WINDOW {
+                let room = REPLICATION_WINDOW - (next - follower.matched);
+                let records = self.store.read(next, MAX_BATCH.min(room))?;
+                let Some(last) = records.last() else {
+                    break;
+                };
+                let from = next;
+                next = last.offset + 1;
+                batches.push((node.clone(), from, records));
+            }
+
+            let behind = follower.high_watermark < self.high_watermark;
+            if next == follower.matched && flush_high_watermark && behind {
+                batches.push((node.clone(), next, Vec::new()));
+            }
+            follower.next = Some(next);
+        }
+
+        Ok(batches)
+    }
+
+    /// Notes a follower's log now ending at `next`.
+    fn on_ack(&mut self, follower: &str, next: Offset, high_watermark: Offset, now: u64) {
+        let end = self.next_offset();
+        let Some(state) = self.followers.get_mut(follower) else {
+            return;
+        };
+
+        let next = next.min(end);
+        if next > state.matched {
+            state.matched = next;
+            state.progress_at = now;
+        }
+        state.next = Some(state.next.unwrap_or(next).max(state.matched));
+        if next >= state.ack_end {
+            state.caught_up_at = now;
+        }
+        state.ack_end = end;
+        state.high_watermark = state.high_watermark.max(high_watermark);
+    }
+
+    fn next_offset(&self) -> Offset {
+        self.store.next_offset()
+    }
+
+    /// The producers' sends that were dropped may be retried, so what we
+    /// know about them is read again from what's left.
+    fn truncate(&mut self, offset: Offset) -> Result<()> {
+        if offset >= self.next_offset() {
+            return Ok(());
         }
+
+        self.store.truncate(offset)?;
+        self.high_watermark = self.high_watermark.min(offset);
+        self.producers = Producers::load(self.store.as_ref())?;
+        Ok(())
     }
 
-    fn append(&mut self, message: usize) -> Offset {
-        let offset = self.records.len();
-        let record = (offset, message);
-        self.records.push(record);
-        offset
+    /// Everything before the log start is committed, whether or not we have
+    /// seen the high watermark pass it.
+    fn delete_before(&mut self, offset: Offset) -> Result<()> {
+        self.store.delete_before(offset)?;
+        self.high_watermark = self.high_watermark.max(self.store.start_offset());
+        Ok(())
     }
 
-    fn append_records(&mut self, mut records: Vec<Record>) {
-        self.records.append(&mut records);
+    /// Applies `retention` to the committed part of the log.
+    fn enforce(&mut self, retention: &Retention, now: u64) -> Result<()> {
+        let end = self.high_watermark;
+        let start = retention.start_for(self.store.as_ref(), now, end)?;
+        self.delete_before(start)?;
+
+        if end > self.compacted {
+            retention.compact(self.store.as_mut(), end, producers::sub_key)?;
+            self.compacted = end;
+        }
+
+        Ok(())
     }
+}
+
+fn key_hash(group: &str, key: &str) -> u64 {
+    let mut bytes = group.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend(key.as_bytes());
+    fnv1a(&bytes)
+}
 
-    fn read_from(&self, offset: Offset) -> Vec<Record> {
-        // TODO: there is probably a better way
-        self.records[offset..].iter().take(50).copied().collect()
+fn entry_hash(group: &str, key: &str, offset: Offset) -> u64 {
+    let mut bytes = group.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend(key.as_bytes());
+    bytes.extend(offset.to_le_bytes());
+    fnv1a(&bytes)
+}
+
+fn min_insync(cluster_state: &ClusterState) -> Result<usize> {
+    let nodes = cluster_state.node_ids.len();
+    let min_insync = match env::var(MIN_INSYNC_ENV) {
+        Ok(s) if s == "all" => nodes,
+        Ok(s) => s
+            .parse()
+            .with_context(|| format!("invalid {} {:?}", MIN_INSYNC_ENV, s))?,
+        Err(_) => nodes / 2 + 1,
+    };
+
+    if min_insync == 0 || min_insync > nodes {
+        bail!("{} must be between 1 and {}", MIN_INSYNC_ENV, nodes);
     }
 
-    fn current_offset(&self) -> Offset {
-        match self.records.last() {
-            Some((offset, _)) => *offset,
-            None => 0,
+    Ok(min_insync)
+}
+
+fn lease_key(key: &str) -> String {
+    format!("lease-{}", key)
+}
+
+fn now_ms(cluster_state: &ClusterState) -> u64 {
+    cluster_state
+        .clock
+        .wall()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Where a `Send` for a key has to go.
+enum Route {
+    Local,
+    Forward(String),
+    /// Nobody is known to lead the key; its lease has to be looked up first.
+    Unknown,
+}
+
+/// What an outstanding lin-kv request was for.
+#[derive(Clone, Debug)]
+enum LeaseOp {
+    Read { key: String },
+    Acquire { key: String, lease: Lease },
+    Renew { key: String, lease: Lease },
+}
+
+impl LeaseOp {
+    fn key(&self) -> &str {
+        match self {
+            LeaseOp::Read { key } => key,
+            LeaseOp::Acquire { key, .. } | LeaseOp::Renew { key, .. } => key,
         }
     }
 }
 
 struct KafkaServer {
     logs: HashMap<String, Log>,
-    offset_store: HashMap<String, Offset>,
-    my_id: usize,
+    /// Committed offsets by group and key, replicated to every node.
+    offset_store: HashMap<(String, String), Offset>,
+    /// Summarizes `offset_store` so replicas can find and repair the keys
+    /// whose committed offsets they disagree on.
+    offsets_tree: MerkleTree,
+    /// Picks which node claims a key nobody leads yet, and which one
+    /// coordinates each consumer group.
+    ring: HashRing,
+    /// The consumer groups the ring gives us.
+    coordinator: Coordinator,
+    producer_ids: Snowflake,
+    /// Latest lease seen per key.
+    leases: HashMap<String, Lease>,
+    lease_ops: HashMap<usize, LeaseOp>,
+    /// Keys with a lease operation in flight; at most one per key.
+    busy: HashSet<String>,
+    /// Sends waiting for their key's lease to be resolved.
+    waiting: HashMap<String, Vec<Message<Payload>>>,
+    /// Keys that had no lease when we last looked; sends for them go to the
+    /// ring owner, which claims them.
+    unclaimed: HashSet<String>,
+    /// Keys whose leader didn't answer a forwarded send. We claim these
+    /// ourselves if nobody holds their lease.
+    suspect: HashSet<String>,
+    /// When we last heard from each node. Leases are only taken or renewed
+    /// while a majority of the cluster is reachable, so a leader cut off from
+    /// it lets its leases lapse.
+    last_heard: HashMap<String, u64>,
+    min_insync: usize,
+    storage: Storage,
+    /// Times the fsyncs of logs opened after init.
+    clock: Arc<dyn Clock>,
+    retention: Retention,
+}
+
+impl KafkaServer {
+    fn log_mut(&mut self, key: &str) -> Result<&mut Log> {
+        if !self.logs.contains_key(key) {
+            let store = self.storage.open(key, self.clock.clone())?;
+            self.logs.insert(key.to_string(), Log::new(store)?);
+        }
+
+        Ok(self.logs.get_mut(key).expect("just inserted"))
+    }
+
+    fn log_start(&self, key: &str) -> Offset {
+        self.logs.get(key).map_or(0, |log| log.store.start_offset())
+    }
+
+    fn leads(&self, cluster_state: &ClusterState, key: &str) -> bool {
+        let now = now_ms(cluster_state);
+        self.leases.get(key).is_some_and(|lease| {
+            lease.leader == cluster_state.node_id
+                && now + CLOCK_SAFETY < lease.expires
+                && self.logs.get(key).is_some_and(|l| l.epoch == lease.epoch)
+        })
+    }
+
+    fn route(&self, cluster_state: &ClusterState, key: &str) -> Route {
+        if self.leads(cluster_state, key) {
+            return Route::Local;
+        }
+
+        let lease = self.leases.get(key);
+        match lease {
+            Some(lease)
+                if lease.leader != cluster_state.node_id
+                    && now_ms(cluster_state) <= lease.expires =>
+            {
+                Route::Forward(lease.leader.clone())
+            }
+            Some(_) => Route::Unknown,
+            None if self.unclaimed.contains(key) && !self.suspect.contains(key) => {
+                match self.ring.owner(key) {
+                    Some(owner) if owner != cluster_state.node_id => {
+                        Route::Forward(owner.to_string())
+                    }
+                    _ => Route::Unknown,
+                }
+            }
+            None => Route::Unknown,
+        }
+    }
+
+    fn in_touch_with_majority(&self, cluster_state: &ClusterState) -> bool {
+        let now = now_ms(cluster_state);
+        let reachable = self
+            .last_heard
+            .iter()
+            .filter(|(n, &heard)| {
+                n != &&cluster_state.node_id && now.saturating_sub(heard) < LEASE_DURATION
+            })
+            .count();
+
+        reachable + 1 > cluster_state.node_ids.len() / 2
+    }
+
+    fn handle_send(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        input: Message<Payload>,
+    ) -> Result<()> {
+        let Payload::Send {
+            key,
+            msg,
+            sub_key,
+            producer_id,
+            seq,
+            forwarded_for,
+        } = &input.body.payload
+        else {
+            bail!("expected a send, got {:?}", input.body.payload);
+        };
+
+        match self.route(cluster_state, key) {
+            Route::Local => {
+                let key = key.clone();
+                let now = now_ms(cluster_state);
+                let log = self.logs.get_mut(&key).expect("leaders have a log");
+                let producer = producer_id.zip(*seq);
+                let sequence = match producer {
+                    Some((producer, seq)) => log.producers.check(producer, seq),
+                    None => Sequence::New,
+                };
+
+                let text = match sequence {
+                    Sequence::New => {
+                        let offset = log.append(now, *msg, producer, sub_key.as_deref())?;
+                        log.unacked.push((offset, input, now + ACK_TIMEOUT));
+                        self.advance_high_watermark(cluster_state, io, &key)?;
+                        return self.replicate(cluster_state, io, &key, false);
+                    }
+                    Sequence::Duplicate(offset) => {
+                        // Answered like the original, once that's committed.
+                        log.unacked.push((offset, input, now + ACK_TIMEOUT));
+                        return self.advance_high_watermark(cluster_state, io, &key);
+                    }
+                    Sequence::OutOfOrder { expected } => {
+                        format!("expected sequence number {}", expected)
+                    }
+                    Sequence::TooOld => "sequence number too old to deduplicate".to_string(),
+                };
+
+                let error = Payload::Error {
+                    code: PRECONDITION_FAILED,
+                    text,
+                    forwarded_for: forwarded_for.clone(),
+                };
+                io.rpc_reply_to(&input, &error)?;
+            }
+            Route::Forward(_) if forwarded_for.is_some() => {
+                // Whoever forwarded this has an outdated idea of the leader;
+                // don't pass it further along.
+                let error = Payload::Error {
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: format!("not the leader for {}", key),
+                    forwarded_for: forwarded_for.clone(),
+                };
+                io.rpc_reply_to(&input, &error)?;
+            }
+            Route::Forward(leader) => {
+                let Some(msg_id) = input.body.id else {
+                    eprintln!("ignoring send without a msg_id from {}", input.src);
+                    return Ok(());
+                };
+
+                let send = Payload::Send {
+                    key: key.to_string(),
+                    msg: *msg,
+                    sub_key: sub_key.clone(),
+                    producer_id: *producer_id,
+                    seq: *seq,
+                    forwarded_for: Some((input.src.clone(), msg_id)),
+                };
+                io.rpc_request(&leader, &send, FORWARD_TIMEOUT, false)?;
+            }
+            Route::Unknown => {
+                let key = key.clone();
+                self.waiting.entry(key.clone()).or_default().push(input);
+                self.resolve(io, &key)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Who should be in `key`'s ISR: us, and the followers that kept up with
+    /// us recently. Newcomers also need everything below the high watermark.
+    ///
+    /// The ISR never shrinks below `min_insync`; the members that fell
+    /// behind and are closest to catching up stay in it. They only hold the
+    /// high watermark back, whereas dropping them could leave us the only
+    /// node allowed to take over.
+    fn in_sync(&self, cluster_state: &ClusterState, key: &str, lease: &Lease) -> Vec<String> {
+        let me = &cluster_state.node_id;
+        let mut isr = vec![me.clone()];
+        let Some(log) = self.logs.get(key) else {
+            return isr;
+        };
+
+        let now = now_ms(cluster_state);
+        let current = &lease.isr;
+        isr.extend(
+            log.followers
+                .iter()
+                .filter(|(n, f)| {
+                    let caught_up = now.saturating_sub(f.caught_up_at) < ISR_LAG
+                        || f.matched >= log.next_offset();
+                    caught_up && (f.matched >= log.high_watermark || current.contains(n))
+                })
+                .map(|(n, _)| n.clone()),
+        );
+
+        let mut lagging: Vec<(&String, Offset)> = current
+            .iter()
+            .filter(|&n| !isr.contains(n))
+            .map(|n| (n, log.followers.get(n).map_or(0, |f| f.matched)))
+            .collect();
+        lagging.sort_by_key(|&(_, matched)| Reverse(matched));
+
+        let missing = self.min_insync.saturating_sub(isr.len());
+        isr.extend(lagging.into_iter().take(missing).map(|(n, _)| n.clone()));
+        isr.sort();
+        isr
+    }
+
+    /// Moves the high watermark up to what every replica in the lease's ISR
+    /// has, and acknowledges the sends that are now committed.
+    fn advance_high_watermark(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+    ) -> Result<()> {
+        if !self.leads(cluster_state, key) {
+            return Ok(());
+        }
+
+        let isr = &self.leases[key].isr;
+        let log = self.logs.get_mut(key).expect("leaders have a log");
+        if isr.len() < self.min_insync {
+            return Ok(());
+        }
+
+        let committed = isr
+            .iter()
+            .map(|n| match log.followers.get(n) {
+                _ if n == &cluster_state.node_id => log.next_offset(),
+                Some(f) => f.matched,
+                None => 0,
+            })
+            .min()
+            .unwrap_or_default();
+        log.high_watermark = log.high_watermark.max(committed);
+
+        let high_watermark = log.high_watermark;
+        let (acked, unacked) = log
+            .unacked
+            .drain(..)
+            .partition(|(offset, _, _)| *offset < high_watermark);
+        log.unacked = unacked;
+
+        for (offset, input, _) in acked {
+            let Payload::Send { forwarded_for, .. } = &input.body.payload else {
+                continue;
+            };
+
+            let send_ok = Payload::SendOk {
+                offset,
+                forwarded_for: forwarded_for.clone(),
+            };
+            io.rpc_reply_to(&input, &send_ok)?;
+        }
+
+        Ok(())
+    }
+
+    /// Pushes `key`'s new records to its followers.
+    fn replicate(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+        flush_high_watermark: bool,
+    ) -> Result<()> {
+        if !self.leads(cluster_state, key) {
+            return Ok(());
+        }
+
+        let lease = &self.leases[key];
+        let log = self.logs.get_mut(key).expect("leaders have a log");
+        for (node, from, records) in log.batches(now_ms(cluster_state), flush_high_watermark)? {
+            let replicate = Payload::Replicate {
+                key: key.to_string(),
+                lease: lease.clone(),
+                from,
+                records,
+                high_watermark: log.high_watermark,
+                log_start: log.store.start_offset(),
+            };
+            io.fire_and_forget(&node, &replicate)?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails the sends that won't be committed in time, or that we can't
+    /// tell about any more because we lost the lease. They're in the log and
+    /// may still be committed, so the error is an indefinite one.
+    fn expire_unacked(&mut self, cluster_state: &ClusterState, io: &mut IO<Payload>) -> Result<()> {
+        let now = now_ms(cluster_state);
+        let keys: Vec<String> = self.logs.keys().cloned().collect();
+        for key in keys {
+            let leads = self.leads(cluster_state, &key);
+            let log = self.logs.get_mut(&key).expect("key was just listed");
+            let (expired, unacked) = log
+                .unacked
+                .drain(..)
+                .partition(|(_, _, deadline)| !leads || *deadline <= now);
+            log.unacked = unacked;
+
+            for (_, input, _) in expired {
+                let Payload::Send { forwarded_for, .. } = &input.body.payload else {
+                    continue;
+                };
+
+                let error = Payload::Error {
+                    code: TIMEOUT,
+                    text: format!("{} wasn't replicated in time", key),
+                    forwarded_for: forwarded_for.clone(),
+                };
+                io.rpc_reply_to(&input, &error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fail_waiting(&mut self, io: &mut IO<Payload>, key: &str, text: &str) -> Result<()> {
+        for input in self.waiting.remove(key).unwrap_or_default() {
+            let Payload::Send { forwarded_for, .. } = &input.body.payload else {
+                continue;
+            };
+
+            let error = Payload::Error {
+                code: TEMPORARILY_UNAVAILABLE,
+                text: text.to_string(),
+                forwarded_for: forwarded_for.clone(),
+            };
+            io.rpc_reply_to(&input, &error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Routes the sends that were waiting on `key`'s lease again.
+    fn dispatch(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+    ) -> Result<()> {
+        for input in self.waiting.remove(key).unwrap_or_default() {
+            self.handle_send(cluster_state, io, input)?;
+        }
+
+        Ok(())
+    }
+
+    fn lease_op(&mut self, io: &mut IO<Payload>, payload: &Payload, op: LeaseOp) -> Result<()> {
+        let id = io.rpc_request(LIN_KV, payload, LEASE_TIMEOUT, false)?;
+        self.lease_ops.insert(id, op);
+
+        Ok(())
+    }
+
+    fn resolve(&mut self, io: &mut IO<Payload>, key: &str) -> Result<()> {
+        if !self.busy.insert(key.to_string()) {
+            return Ok(());
+        }
+
+        let read = Payload::Read {
+            key: lease_key(key),
+        };
+        self.lease_op(
+            io,
+            &read,
+            LeaseOp::Read {
+                key: key.to_string(),
+            },
+        )
+    }
+
+    fn acquire(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+        current: Option<Lease>,
+    ) -> Result<()> {
+        let log = self.log_mut(key)?;
+        let epoch = current.as_ref().map_or(0, |l| l.epoch).max(log.epoch) + 1;
+
+        // The previous ISR has everything that was committed, so it stays in
+        // sync until its members are seen falling behind us.
+        let mut isr = current.as_ref().map(|l| l.isr.clone()).unwrap_or_default();
+        if !isr.contains(&cluster_state.node_id) {
+            isr.push(cluster_state.node_id.clone());
+            isr.sort();
+        }
+
+        let lease = Lease {
+            leader: cluster_state.node_id.clone(),
+            epoch,
+            expires: now_ms(cluster_state) + LEASE_DURATION,
+            start: log.next_offset(),
+            isr,
+        };
+
+        let cas = Payload::Cas {
+            key: lease_key(key),
+            create_if_not_exists: current.is_none(),
+            from: current,
+            to: lease.clone(),
+        };
+        self.lease_op(
+            io,
+            &cas,
+            LeaseOp::Acquire {
+                key: key.to_string(),
+                lease,
+            },
+        )
+    }
+
+    fn renew(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+        current: Lease,
+    ) -> Result<()> {
+        let lease = Lease {
+            expires: now_ms(cluster_state) + LEASE_DURATION,
+            isr: self.in_sync(cluster_state, key, &current),
+            ..current.clone()
+        };
+
+        let cas = Payload::Cas {
+            key: lease_key(key),
+            from: Some(current),
+            to: lease.clone(),
+            create_if_not_exists: false,
+        };
+        self.lease_op(
+            io,
+            &cas,
+            LeaseOp::Renew {
+                key: key.to_string(),
+                lease,
+            },
+        )
+    }
+
+    /// Decides what to do about the lease lin-kv has for `key`, or `None` if
+    /// it has none.
+    fn on_lease(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        key: &str,
+        lease: Option<Lease>,
+    ) -> Result<()> {
+        let me = &cluster_state.node_id;
+        let ours = |l: &Lease| {
+            &l.leader == me && self.logs.get(key).is_some_and(|log| log.epoch == l.epoch)
+        };
+
+        match lease {
+            Some(lease) if &lease.leader != me && now_ms(cluster_state) <= lease.expires => {
+                self.busy.remove(key);
+                self.observe_lease(cluster_state, key, lease)?;
+                return self.dispatch(cluster_state, io, key);
+            }
+            None if self.ring.owner(key) != Some(me.as_str()) && !self.suspect.contains(key) => {
+                self.busy.remove(key);
+                self.unclaimed.insert(key.to_string());
+                return self.dispatch(cluster_state, io, key);
+            }
+            _ if !self.in_touch_with_majority(cluster_state) => {
+                self.busy.remove(key);
+                return self.fail_waiting(io, key, "cut off from the rest of the cluster");
+            }
+            Some(lease) if ours(&lease) => self.renew(cluster_state, io, key, lease)?,
+            Some(lease) if !lease.isr.contains(me) => {
+                // We may be missing acknowledged records.
+                self.busy.remove(key);
+                return self.fail_waiting(io, key, "no in-sync replica to take over");
+            }
+            lease => self.acquire(cluster_state, io, key, lease)?,
+        }
+
+        Ok(())
+    }
+
+    /// Records a lease another node holds. A newer epoch means a new leader
+    /// took over from its own copy of the log, so ours is cut back to where
+    /// that copy ended. Anything we have past our high watermark may differ
+    /// from it and is dropped too, to be fetched again.
+    fn observe_lease(
+        &mut self,
+        cluster_state: &ClusterState,
+        key: &str,
+        lease: Lease,
+    ) -> Result<()> {
+        if self.leases.get(key).is_some_and(|l| l.epoch > lease.epoch) {
+            return Ok(());
+        }
+
+        let log = self.log_mut(key)?;
+        if lease.epoch > log.epoch {
+            if log.next_offset() > lease.start {
+                eprintln!(
+                    "{} taking over {} at epoch {}, dropping offsets {}..{}",
+                    lease.leader,
+                    key,
+                    lease.epoch,
+                    lease.start,
+                    log.next_offset()
+                );
+            }
+            log.truncate(lease.start.min(log.high_watermark))?;
+            log.epoch = lease.epoch;
+        }
+
+        if lease.leader != cluster_state.node_id {
+            self.suspect.remove(key);
+        }
+        self.unclaimed.remove(key);
+        self.leases.insert(key.to_string(), lease);
+
+        Ok(())
+    }
+
+    fn on_lease_reply(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        op: LeaseOp,
+        payload: &Payload,
+    ) -> Result<()> {
+        match (op, payload) {
+            (LeaseOp::Read { key }, Payload::ReadOk { value }) => {
+                self.on_lease(cluster_state, io, &key, Some(value.clone()))?;
+            }
+            (LeaseOp::Read { key }, Payload::Error { code, .. }) if *code == KEY_DOES_NOT_EXIST => {
+                self.on_lease(cluster_state, io, &key, None)?;
+            }
+            (LeaseOp::Acquire { key, lease } | LeaseOp::Renew { key, lease }, Payload::CasOk) => {
+                self.busy.remove(&key);
+                if let Some(log) = self.logs.get_mut(&key) {
+                    if log.epoch != lease.epoch {
+                        let end = log.next_offset();
+                        log.followers = cluster_state
+                            .node_ids
+                            .iter()
+                            .filter(|&n| n != &cluster_state.node_id)
+                            .map(|n| (n.clone(), Follower::new(end)))
+                            .collect();
+                    }
+                    log.epoch = lease.epoch;
+                }
+                self.suspect.remove(&key);
+                self.unclaimed.remove(&key);
+                self.leases.insert(key.clone(), lease);
+                self.advance_high_watermark(cluster_state, io, &key)?;
+                self.dispatch(cluster_state, io, &key)?;
+                self.replicate(cluster_state, io, &key, false)?;
+            }
+            (
+                LeaseOp::Acquire { key, .. } | LeaseOp::Renew { key, .. },
+                Payload::Error { code, .. },
+            ) if *code == PRECONDITION_FAILED || *code == KEY_DOES_NOT_EXIST => {
+                // Somebody else got there first, or our lease changed under
+                // us; look again.
+                self.busy.remove(&key);
+                self.leases.remove(&key);
+                self.resolve(io, &key)?;
+            }
+            (op, Payload::Error { code, text, .. }) => {
+                eprintln!("lease operation {:?} failed: {} ({})", op, text, code);
+                let key = op.key().to_string();
+                self.busy.remove(&key);
+                self.fail_waiting(io, &key, text)?;
+            }
+            (_, payload) => bail!("unexpected payload {:?}", payload),
+        }
+
+        Ok(())
+    }
+
+    /// Committed offsets only move forward, so merging is taking the max.
+    fn commit_offset(&mut self, group: &str, key: &str, offset: Offset) {
+        let entry = (group.to_string(), key.to_string());
+        let current = self.offset_store.get(&entry).copied();
+        if current.is_some_and(|c| c >= offset) {
+            return;
+        }
+
+        let key_hash = key_hash(group, key);
+        if let Some(c) = current {
+            self.offsets_tree
+                .toggle(key_hash, entry_hash(group, key, c));
+        }
+        self.offsets_tree
+            .toggle(key_hash, entry_hash(group, key, offset));
+        self.offset_store.insert(entry, offset);
+    }
+
+    fn committed_in(&self, leaves: &[NodeIndex]) -> Vec<(String, String, Offset)> {
+        self.offset_store
+            .iter()
+            .filter(|((g, k), _)| leaves.contains(&self.offsets_tree.leaf(key_hash(g, k))))
+            .map(|((g, k), o)| (g.clone(), k.clone(), *o))
+            .collect()
+    }
+
+    /// Group requests have to go to the group's coordinator; anywhere else
+    /// they're refused with a pointer to it.
+    fn check_coordinator(
+        &self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        input: &Message<Payload>,
+        group: &str,
+    ) -> Result<bool> {
+        let coordinator = self.ring.owner(group).unwrap_or(&cluster_state.node_id);
+        if coordinator == cluster_state.node_id {
+            return Ok(true);
+        }
+
+        let error = Payload::Error {
+            code: TEMPORARILY_UNAVAILABLE,
+            text: format!("{} coordinates group {}", coordinator, group),
+            forwarded_for: None,
+        };
+        io.rpc_reply_to(input, &error)?;
+        Ok(false)
+    }
 }
 
 impl Server<Payload, Timer> for KafkaServer {
@@ -109,13 +1188,57 @@ impl Server<Payload, Timer> for KafkaServer {
         cluster_state: &ClusterState,
         timers: &mut Timers<Payload, Timer>,
     ) -> Result<KafkaServer> {
-        timers.register_timer(Timer::ReplicaPoll, Duration::from_millis(250));
+        timers.register_timer(Timer::Replicate, Duration::from_millis(100));
+        timers.register_timer(Timer::Heartbeat, Duration::from_millis(500));
+        timers.register_timer(Timer::AntiEntropy, Duration::from_millis(250));
+        timers.register_timer(Timer::Leases, Duration::from_millis(250));
+        timers.register_timer(Timer::Retention, RETENTION_INTERVAL);
+        timers.register_timer(Timer::Sessions, SESSIONS_INTERVAL);
+
+        // Everyone counts as reachable until proven otherwise, so the first
+        // sends don't have to wait for a round of heartbeats.
+        let now = now_ms(cluster_state);
+        let last_heard = cluster_state
+            .node_ids
+            .iter()
+            .map(|n| (n.clone(), now))
+            .collect();
+
+        let Some(node_index) = cluster_state
+            .node_ids
+            .iter()
+            .position(|n| n == &cluster_state.node_id)
+        else {
+            bail!("{} isn't part of the cluster", cluster_state.node_id);
+        };
+
+        let storage = Storage::from_env(Storage::Memory)?.within(&cluster_state.node_id);
+        let mut logs = HashMap::<String, Log>::new();
+        for key in storage.names()? {
+            logs.insert(
+                key.clone(),
+                Log::new(storage.open(&key, cluster_state.clock.clone())?)?,
+            );
+        }
 
-        let my_id = cluster_state.node_id[1..].parse::<usize>()?;
         Ok(KafkaServer {
-            logs: HashMap::<String, Log>::new(),
-            offset_store: HashMap::<String, Offset>::new(),
-            my_id,
+            logs,
+            offset_store: HashMap::new(),
+            offsets_tree: MerkleTree::new(DEFAULT_DEPTH),
+            ring: HashRing::new(&cluster_state.node_ids, DEFAULT_VIRTUAL_NODES),
+            coordinator: Coordinator::new(&incarnation(cluster_state), DEFAULT_SESSION_TIMEOUT),
+            producer_ids: Snowflake::new(node_index as u64, cluster_state.clock.clone())?,
+            leases: HashMap::new(),
+            lease_ops: HashMap::new(),
+            busy: HashSet::new(),
+            waiting: HashMap::new(),
+            unclaimed: HashSet::new(),
+            suspect: HashSet::new(),
+            last_heard,
+            min_insync: min_insync(cluster_state)?,
+            storage,
+            clock: cluster_state.clock.clone(),
+            retention: Retention::from_env(Retention::default())?,
         })
     }
 
@@ -125,141 +1248,299 @@ impl Server<Payload, Timer> for KafkaServer {
         io: &mut IO<Payload>,
         input: Message<Payload>,
     ) -> Result<()> {
-        let payload = &input.body.payload;
-        match payload {
-            Payload::Send {
-                key,
-                msg,
-                forwarded_for,
-            } => {
-                let int_key = key.parse::<usize>()?;
-                let leader = int_key % cluster_state.node_ids.len();
-                if leader == self.my_id {
-                    let log = match self.logs.entry(key.to_string()) {
-                        Entry::Occupied(o) => o.into_mut(),
-                        Entry::Vacant(v) => v.insert(Log::new()),
-                    };
+        if let Some(heard) = self.last_heard.get_mut(&input.src) {
+            *heard = now_ms(cluster_state);
+        }
 
-                    let offset = log.append(*msg);
-                    let send_ok = Payload::SendOk {
-                        offset,
-                        forwarded_for: forwarded_for.clone(),
-                    };
-                    io.rpc_reply_to(&input, &send_ok)?;
-                } else {
-                    let send = Payload::Send {
-                        key: key.to_string(),
-                        msg: *msg,
-                        forwarded_for: Some((input.src, input.body.id.unwrap())),
-                    };
+        if let Some(op) = input
+            .body
+            .in_reply_to
+            .and_then(|id| self.lease_ops.remove(&id))
+        {
+            io.rpc_mark_completed(&input);
+            return self.on_lease_reply(cluster_state, io, op, &input.body.payload);
+        }
 
-                    let dst = format!("n{}", leader);
-                    io.rpc_request_with_retry(&dst, &send, Duration::from_millis(250))?;
-                }
+        let payload = &input.body.payload;
+        match payload {
+            Payload::Send { .. } => {
+                self.handle_send(cluster_state, io, input)?;
             }
             Payload::SendOk {
                 offset,
-                forwarded_for,
+                forwarded_for: Some((dst, msg_id)),
             } if io.rpc_still_pending(&input) => {
-                let (dst, msg_id) = forwarded_for.clone().unwrap();
                 let send_ok = Payload::SendOk {
                     offset: *offset,
                     forwarded_for: None,
                 };
 
-                io.send(&dst, Some(msg_id), &send_ok)?;
+                io.send(dst, Some(*msg_id), &send_ok)?;
+                io.rpc_mark_completed(&input);
+            }
+            Payload::Error {
+                code,
+                text,
+                forwarded_for: Some((dst, msg_id)),
+            } if io.rpc_still_pending(&input) => {
+                let error = Payload::Error {
+                    code: *code,
+                    text: text.clone(),
+                    forwarded_for: None,
+                };
+
+                io.send(dst, Some(*msg_id), &error)?;
+                io.rpc_mark_completed(&input);
+            }
+            Payload::SendOk { .. } | Payload::Error { .. } if io.rpc_still_pending(&input) => {
+                eprintln!(
+                    "ignoring reply from {} to a send nobody forwarded",
+                    input.src
+                );
                 io.rpc_mark_completed(&input);
             }
             Payload::Poll { offsets } => {
-                let messages: HashMap<String, Vec<Record>> = offsets
+                let messages = offsets
                     .iter()
                     .map(|(key, offset)| {
-                        let records = match self.logs.entry(key.to_string()) {
-                            Entry::Occupied(o) => o.get().read_from(*offset),
-                            Entry::Vacant(_) => Vec::<Record>::new(),
+                        let records = match self.logs.get(key) {
+                            Some(log) => log.read_committed(*offset)?,
+                            None => Vec::<Record>::new(),
                         };
 
-                        (key.to_string(), records)
+                        Ok((key.to_string(), records))
                     })
+                    .collect::<Result<HashMap<String, Vec<Record>>>>()?;
+
+                let log_start_offsets = offsets
+                    .keys()
+                    .filter(|key| self.logs.contains_key(*key))
+                    .map(|key| (key.to_string(), self.log_start(key)))
                     .collect();
 
-                let poll_ok = Payload::PollOk { msgs: messages };
+                let poll_ok = Payload::PollOk {
+                    msgs: messages,
+                    log_start_offsets,
+                };
                 io.rpc_reply_to(&input, &poll_ok)?;
             }
-            Payload::CommitOffsets { offsets } => {
-                for (key, value) in offsets {
-                    match self.offset_store.entry(key.to_string()) {
-                        Entry::Occupied(o) => {
-                            let current = o.into_mut();
-                            let c = *current;
-                            *current = c.max(*value);
-                        }
-                        Entry::Vacant(v) => {
-                            v.insert(*value);
-                        }
+            Payload::DeleteRecords { offsets } => {
+                // Only committed records can go. Followers that miss this
+                // catch up from the log start their leader replicates.
+                let mut log_start_offsets = HashMap::new();
+                for (key, offset) in offsets {
+                    if let Some(log) = self.logs.get_mut(key) {
+                        log.delete_before((*offset).min(log.high_watermark))?;
+                        log_start_offsets.insert(key.to_string(), log.store.start_offset());
                     }
                 }
 
                 let nodes = cluster_state
                     .node_ids
                     .iter()
-                    .filter(|&n| n != &cluster_state.node_id && n != &input.src);
+                    .filter(|&n| n != &cluster_state.node_id);
+
+                for n in nodes {
+                    let replicate = Payload::ReplicateLogStarts {
+                        offsets: log_start_offsets.clone(),
+                    };
+
+                    io.fire_and_forget(n, &replicate)?;
+                }
+
+                let delete_records_ok = Payload::DeleteRecordsOk { log_start_offsets };
+                io.rpc_reply_to(&input, &delete_records_ok)?;
+            }
+            Payload::ReplicateLogStarts { offsets } => {
+                for (key, offset) in offsets {
+                    if let Some(log) = self.logs.get_mut(key) {
+                        log.delete_before((*offset).min(log.high_watermark))?;
+                    }
+                }
+            }
+            Payload::CommitOffsets {
+                group: Some(group), ..
+            } if !self.check_coordinator(cluster_state, io, &input, group)? => {}
+            Payload::CommitOffsets {
+                offsets,
+                group,
+                member_id,
+                generation,
+            } => {
+                let group = group.as_deref().unwrap_or(NO_GROUP);
+                let check = self.coordinator.check_commit(
+                    group,
+                    member_id.as_deref(),
+                    *generation,
+                    offsets.keys(),
+                );
+                if let Err(e) = check {
+                    let error = Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                        forwarded_for: None,
+                    };
+                    io.rpc_reply_to(&input, &error)?;
+                    return Ok(());
+                }
+
+                for (key, value) in offsets {
+                    self.commit_offset(group, key, *value);
+                }
+
+                // Sent once; replicas that miss it catch up through the
+                // Merkle anti-entropy rounds.
+                let nodes = cluster_state
+                    .node_ids
+                    .iter()
+                    .filter(|&n| n != &cluster_state.node_id);
 
                 for n in nodes {
-                    let commit_offsets = Payload::CommitOffsets {
+                    let replicate = Payload::ReplicateOffsets {
+                        group: group.to_string(),
                         offsets: offsets.clone(),
                     };
 
-                    io.rpc_request_with_retry(n, &commit_offsets, Duration::from_millis(250))?;
+                    io.fire_and_forget(n, &replicate)?;
                 }
 
                 let commit_offsets_ok = Payload::CommitOffsetsOk {};
                 io.rpc_reply_to(&input, &commit_offsets_ok)?;
             }
-            Payload::CommitOffsetsOk if io.rpc_still_pending(&input) => {
-                io.rpc_mark_completed(&input);
+            Payload::ReplicateOffsets { group, offsets } => {
+                for (key, value) in offsets {
+                    self.commit_offset(group, key, *value);
+                }
+            }
+            Payload::Merkle { sync } => {
+                if let MerkleSync::Repair { entries, .. } = sync {
+                    for (group, key, value) in entries {
+                        self.commit_offset(group, key, *value);
+                    }
+                }
+
+                for sync in self.offsets_tree.reply(sync, |l| self.committed_in(l)) {
+                    io.fire_and_forget(&input.src, &Payload::Merkle { sync })?;
+                }
             }
-            Payload::ListCommittedOffsets { keys } => {
+            Payload::ListCommittedOffsets { keys, group } => {
+                let group = group.as_deref().unwrap_or(NO_GROUP);
                 let mut offsets = HashMap::new();
                 for k in keys {
-                    if let Some(offset) = self.offset_store.get(k) {
-                        offsets.insert(k.to_string(), *offset);
+                    // Consumers resume from the start of the log if what they
+                    // committed has been deleted since.
+                    let committed = self.offset_store.get(&(group.to_string(), k.to_string()));
+                    if let Some(offset) = committed {
+                        offsets.insert(k.to_string(), (*offset).max(self.log_start(k)));
                     }
                 }
 
                 let list_committed_offsets_ok = Payload::ListCommittedOffsetsOk { offsets };
                 io.rpc_reply_to(&input, &list_committed_offsets_ok)?;
             }
-            Payload::ReplicaPoll { offsets } => {
-                let messages: HashMap<String, Vec<Record>> = self
-                    .logs
-                    .iter()
-                    .filter(|(k, _)| {
-                        let Ok(int_key) = k.parse::<usize>() else {
-                            return false;
-                        };
+            Payload::InitProducerId => {
+                // Snowflake ids carry the node, so no two nodes hand out the
+                // same one.
+                let producer_id = self.producer_ids.next_id()?;
+                io.rpc_reply_to(&input, &Payload::InitProducerIdOk { producer_id })?;
+            }
+            Payload::FindCoordinator { group } => {
+                let node = self.ring.owner(group).unwrap_or(&cluster_state.node_id);
+                let find_coordinator_ok = Payload::FindCoordinatorOk {
+                    node: node.to_string(),
+                };
+                io.rpc_reply_to(&input, &find_coordinator_ok)?;
+            }
+            Payload::JoinGroup { group, .. }
+            | Payload::GroupHeartbeat { group, .. }
+            | Payload::LeaveGroup { group, .. }
+                if !self.check_coordinator(cluster_state, io, &input, group)? => {}
+            Payload::JoinGroup {
+                group,
+                member_id,
+                keys,
+                assignor,
+            } => {
+                let (member_id, generation, keys) = self.coordinator.join(
+                    group,
+                    member_id.as_deref(),
+                    keys.clone(),
+                    *assignor,
+                    now_ms(cluster_state),
+                );
 
-                        let leader = int_key % cluster_state.node_ids.len();
-                        leader == self.my_id
-                    })
-                    .map(|(k, v)| {
-                        let offset = offsets.get(k).unwrap_or(&0);
-                        let records = v.read_from(*offset);
-                        (k.clone(), records)
-                    })
-                    .collect();
+                let join_group_ok = Payload::JoinGroupOk {
+                    member_id,
+                    generation,
+                    keys,
+                };
+                io.rpc_reply_to(&input, &join_group_ok)?;
+            }
+            Payload::GroupHeartbeat { group, member_id } => {
+                let now = now_ms(cluster_state);
+                let reply = match self.coordinator.heartbeat(group, member_id, now) {
+                    Ok((generation, keys)) => Payload::GroupHeartbeatOk { generation, keys },
+                    Err(e) => Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                        forwarded_for: None,
+                    },
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::LeaveGroup { group, member_id } => {
+                let reply = match self.coordinator.leave(group, member_id) {
+                    Ok(()) => Payload::LeaveGroupOk,
+                    Err(e) => Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                        forwarded_for: None,
+                    },
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::Replicate {
+                key,
+                lease,
+                from,
+                records,
+                high_watermark,
+                log_start,
+            } => {
+                self.observe_lease(cluster_state, key, lease.clone())?;
+
+                let log = self.log_mut(key)?;
+                if log.epoch == lease.epoch {
+                    log.delete_before(*log_start)?;
+                    log.append_records(*from, records)?;
+                    let high_watermark = (*high_watermark).min(log.next_offset());
+                    log.high_watermark = log.high_watermark.max(high_watermark);
+                }
 
-                let replica_poll_ok = Payload::ReplicaPollOk { msgs: messages };
-                io.rpc_reply_to(&input, &replica_poll_ok)?;
+                let replicate_ok = Payload::ReplicateOk {
+                    key: key.clone(),
+                    epoch: log.epoch,
+                    next: log.next_offset(),
+                    high_watermark: log.high_watermark,
+                };
+                io.fire_and_forget(&input.src, &replicate_ok)?;
             }
-            Payload::ReplicaPollOk { msgs } if io.rpc_still_pending(&input) => {
-                for (l, recs) in msgs {
-                    let log = self.logs.entry(l.clone()).or_insert_with(Log::new);
-                    log.append_records(recs.to_vec())
+            Payload::ReplicateOk {
+                key,
+                epoch,
+                next,
+                high_watermark,
+            } => {
+                if !self.leads(cluster_state, key) || self.leases[key].epoch != *epoch {
+                    return Ok(());
                 }
 
-                io.rpc_mark_completed(&input);
+                let log = self.logs.get_mut(key).expect("leaders have a log");
+                log.on_ack(&input.src, *next, *high_watermark, now_ms(cluster_state));
+                self.advance_high_watermark(cluster_state, io, key)?;
+                self.replicate(cluster_state, io, key, false)?;
             }
+            Payload::Heartbeat => (),
             _ if input.body.in_reply_to.is_some() && !io.rpc_still_pending(&input) => {
                 eprintln!("received late response");
             }
@@ -279,48 +1560,83 @@ impl Server<Payload, Timer> for KafkaServer {
         Self: Sized,
     {
         match timer {
-            Timer::ReplicaPoll => {
-                let offset_groups = self
-                    .logs
+            Timer::Replicate => {
+                let keys: Vec<String> = self.logs.keys().cloned().collect();
+                for key in keys {
+                    self.replicate(cluster_state, io, &key, true)?;
+                }
+            }
+            Timer::Heartbeat => {
+                // Lets everyone tell whether they're still in touch with a
+                // majority while there's nothing to replicate.
+                for node in cluster_state
+                    .node_ids
                     .iter()
-                    .filter_map(|(k, v)| {
-                        let Ok(int_key) = k.parse::<usize>() else {
-                            return None;
-                        };
+                    .filter(|&n| n != &cluster_state.node_id)
+                {
+                    io.fire_and_forget(node, &Payload::Heartbeat)?;
+                }
+            }
+            Timer::AntiEntropy => {
+                let peers: Vec<&String> = cluster_state
+                    .node_ids
+                    .iter()
+                    .filter(|&n| n != &cluster_state.node_id)
+                    .collect();
 
-                        let leader = int_key % cluster_state.node_ids.len();
-                        Some((leader, k, v))
-                    })
-                    .filter(|(leader, _, _)| leader != &self.my_id)
-                    .group_by(|(leader, _, _)| *leader);
-
-                let requests: HashMap<String, Payload> = offset_groups
-                    .into_iter()
-                    .map(|(leader, group)| {
-                        let offsets: HashMap<String, Offset> = group
-                            .map(|(_, k, l)| {
-                                let offset = l.current_offset();
-                                (k.to_string(), offset + 1)
-                            })
-                            .collect();
+                if let Some(peer) = peers.choose(&mut rand::thread_rng()) {
+                    let sync = self.offsets_tree.summary();
+                    io.fire_and_forget(peer, &Payload::Merkle { sync })?;
+                }
+            }
+            Timer::Leases => {
+                self.expire_unacked(cluster_state, io)?;
+                if !self.in_touch_with_majority(cluster_state) {
+                    // Let our leases lapse so the majority side can take over.
+                    return Ok(());
+                }
 
-                        let node = format!("n{}", leader);
-                        let request = Payload::ReplicaPoll { offsets };
-                        (node, request)
+                // Renewing is also how ISR changes get recorded.
+                let now = now_ms(cluster_state);
+                let renewing: Vec<(String, Lease)> = self
+                    .leases
+                    .iter()
+                    .filter(|(k, l)| {
+                        self.leads(cluster_state, k)
+                            && (l.expires < now + RENEW_BEFORE
+                                || l.isr != self.in_sync(cluster_state, k, l))
+                            && !self.busy.contains(k.as_str())
                     })
+                    .map(|(k, l)| (k.clone(), l.clone()))
                     .collect();
 
-                let empty = Payload::ReplicaPoll {
-                    offsets: HashMap::<String, Offset>::new(),
-                };
+                for (key, lease) in renewing {
+                    self.busy.insert(key.clone());
+                    self.renew(cluster_state, io, &key, lease)?;
+                }
 
-                for node in cluster_state
-                    .node_ids
-                    .iter()
-                    .filter(|&n| n != &cluster_state.node_id)
-                {
-                    let request = requests.get(node).unwrap_or(&empty);
-                    io.rpc_request(node, request, Duration::from_secs(5), false)?;
+                // Keys recovered from storage, or whose leader went quiet,
+                // get a leader without waiting for someone to send to them.
+                let leaderless: Vec<String> = self
+                    .logs
+                    .keys()
+                    .filter(|k| matches!(self.route(cluster_state, k), Route::Unknown))
+                    .cloned()
+                    .collect();
+
+                for key in leaderless {
+                    self.resolve(io, &key)?;
+                }
+            }
+            Timer::Retention => {
+                let now = now_ms(cluster_state);
+                for log in self.logs.values_mut() {
+                    log.enforce(&self.retention, now)?;
+                }
+            }
+            Timer::Sessions => {
+                for (group, member) in self.coordinator.expire(now_ms(cluster_state)) {
+                    eprintln!("{} left {} without a heartbeat", member, group);
                 }
             }
         }
@@ -331,11 +1647,48 @@ impl Server<Payload, Timer> for KafkaServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
-        _: gossip_glomers_rs::Request<Payload>,
+        io: &mut IO<Payload>,
+        timeout: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
         Self: Sized,
     {
-        bail!("unexpected RPC timeout");
+        if let Some(op) = self.lease_ops.remove(&timeout.id) {
+            eprintln!("lease operation timed out: {:?}", op);
+            let key = op.key().to_string();
+            self.busy.remove(&key);
+            return self.fail_waiting(io, &key, "timed out looking up the leader");
+        }
+
+        match timeout.payload {
+            Payload::Send {
+                key,
+                forwarded_for: Some((dst, msg_id)),
+                ..
+            } => {
+                // The leader might be gone; stop trusting its lease and claim
+                // the key ourselves once nobody holds it.
+                eprintln!("leader {} for {} didn't answer", timeout.dst, key);
+                if self
+                    .leases
+                    .get(&key)
+                    .is_some_and(|l| l.leader == timeout.dst)
+                {
+                    self.leases.remove(&key);
+                }
+                self.suspect.insert(key.clone());
+
+                // The leader may have appended it before going quiet.
+                let error = Payload::Error {
+                    code: TIMEOUT,
+                    text: format!("leader for {} is unreachable", key),
+                    forwarded_for: None,
+                };
+                io.send(&dst, Some(msg_id), &error)?;
+            }
+            payload => bail!("unexpected RPC timeout for {:?}", payload),
+        }
+
+        Ok(())
     }
 }
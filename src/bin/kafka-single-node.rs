@@ -1,6 +1,17 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, UNIX_EPOCH},
+};
 
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
+use gossip_glomers_rs::{
+    anti_entropy::incarnation,
+    groups::{Assignor, Coordinator, DEFAULT_SESSION_TIMEOUT},
+    ids::Snowflake,
+    kv::PRECONDITION_FAILED,
+    producers::{self, ProducerId, Producers, Sequence},
+    storage::{LogStore, Retention, Storage},
+    ClusterState, Message, Node, Server, Timers, IO,
+};
 use serde::{Deserialize, Serialize};
 
 use anyhow::{bail, Result};
@@ -12,128 +23,451 @@ type Record = (Offset, usize);
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Send { key: String, msg: usize },
-    SendOk { offset: Offset },
-    Poll { offsets: HashMap<String, Offset> },
-    PollOk { msgs: HashMap<String, Vec<Record>> },
-    CommitOffsets { offsets: HashMap<String, Offset> },
+    Send {
+        key: String,
+        msg: usize,
+        /// Compacted logs only keep the latest message for each sub-key.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_key: Option<String>,
+        /// Sends with a producer id and a sequence number are only appended
+        /// once, however often they're retried.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<ProducerId>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    SendOk {
+        offset: Offset,
+    },
+    InitProducerId,
+    InitProducerIdOk {
+        producer_id: ProducerId,
+    },
+    Poll {
+        offsets: HashMap<String, Offset>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<Record>>,
+        /// Where each polled log starts; the offsets before it are gone.
+        log_start_offsets: HashMap<String, Offset>,
+    },
+    DeleteRecords {
+        offsets: HashMap<String, Offset>,
+    },
+    DeleteRecordsOk {
+        log_start_offsets: HashMap<String, Offset>,
+    },
+    /// Without a group, offsets are shared with every other consumer that
+    /// doesn't name one.
+    CommitOffsets {
+        offsets: HashMap<String, Offset>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        member_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        generation: Option<u64>,
+    },
     CommitOffsetsOk,
-    ListCommittedOffsets { keys: Vec<String> },
-    ListCommittedOffsetsOk { offsets: HashMap<String, Offset> },
+    ListCommittedOffsets {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, Offset>,
+    },
+    /// Members leave `member_id` out the first time they join.
+    JoinGroup {
+        group: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        member_id: Option<String>,
+        keys: BTreeSet<String>,
+        #[serde(default)]
+        assignor: Assignor,
+    },
+    JoinGroupOk {
+        member_id: String,
+        generation: u64,
+        keys: BTreeSet<String>,
+    },
+    GroupHeartbeat {
+        group: String,
+        member_id: String,
+    },
+    GroupHeartbeatOk {
+        generation: u64,
+        keys: BTreeSet<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member_id: String,
+    },
+    LeaveGroupOk,
+    Error {
+        code: u64,
+        text: String,
+    },
 }
 
+#[derive(Clone, Copy, Debug)]
+enum Timer {
+    Retention,
+    Sessions,
+}
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(1);
+const SESSIONS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Offsets committed without naming a group go to this one.
+const NO_GROUP: &str = "";
+
 fn main() -> anyhow::Result<()> {
-    let mut node = Node::<KafkaServer, Payload, ()>::init()?;
+    let mut node = Node::<KafkaServer, Payload, Timer>::init()?;
     node.run()
 }
 
 struct Log {
-    records: Vec<Record>,
+    store: Box<dyn LogStore>,
+    /// Where the log was last compacted up to.
+    compacted: Offset,
+    producers: Producers,
 }
 
 impl Log {
-    fn new() -> Self {
-        Log {
-            records: Vec::<Record>::new()
+    /// Reads through the log for the producers' latest sends.
+    fn new(store: Box<dyn LogStore>) -> Result<Self> {
+        let producers = Producers::load(store.as_ref())?;
+        Ok(Log {
+            compacted: store.start_offset(),
+            store,
+            producers,
+        })
+    }
+
+    fn append(
+        &mut self,
+        timestamp: u64,
+        message: usize,
+        producer: Option<(ProducerId, u64)>,
+        sub_key: Option<&str>,
+    ) -> Result<Offset> {
+        let offset = self
+            .store
+            .append(timestamp, &producers::encode(message, producer, sub_key))?;
+        if let Some((producer, sequence)) = producer {
+            self.producers.record(producer, sequence, offset);
         }
+
+        Ok(offset)
     }
 
-    fn append(&mut self, message: usize) -> Offset {
-        let offset = self.records.len();
-        let record = (offset, message);
-        self.records.push(record);
-        offset
+    fn read_from(&self, offset: Offset) -> Result<Vec<Record>> {
+        self.store
+            .read(offset, 10)?
+            .iter()
+            .map(producers::decode)
+            .collect()
     }
 
-    fn read_from(&self, offset: Offset) -> Vec<Record> {
-        // TODO: there is probably a better way
-        self.records[offset..].iter().take(10).copied().collect()
+    fn enforce(&mut self, retention: &Retention, now: u64) -> Result<()> {
+        let end = self.store.next_offset();
+        let start = retention.start_for(self.store.as_ref(), now, end)?;
+        self.store.delete_before(start)?;
+
+        if end > self.compacted {
+            retention.compact(self.store.as_mut(), end, producers::sub_key)?;
+            self.compacted = end;
+        }
+
+        Ok(())
     }
 }
 
+fn now_ms(cluster_state: &ClusterState) -> Result<u64> {
+    Ok(cluster_state
+        .clock
+        .wall()
+        .duration_since(UNIX_EPOCH)?
+        .as_millis() as u64)
+}
+
 struct KafkaServer {
     logs: HashMap<String, Log>,
-    offset_store: HashMap<String, Offset>,
+    /// Committed offsets by group and key.
+    offset_store: HashMap<(String, String), Offset>,
+    coordinator: Coordinator,
+    producer_ids: Snowflake,
+    storage: Storage,
+    retention: Retention,
+}
+
+impl KafkaServer {
+    fn log_start(&self, key: &str) -> Offset {
+        self.logs.get(key).map_or(0, |log| log.store.start_offset())
+    }
 }
 
-impl Server<Payload, ()> for KafkaServer {
-    fn init(_: &ClusterState, _: &mut Timers<Payload, ()>) -> Result<KafkaServer> {
+impl Server<Payload, Timer> for KafkaServer {
+    fn init(
+        cluster_state: &ClusterState,
+        timers: &mut Timers<Payload, Timer>,
+    ) -> Result<KafkaServer> {
+        timers.register_timer(Timer::Retention, RETENTION_INTERVAL);
+        timers.register_timer(Timer::Sessions, SESSIONS_INTERVAL);
+
+        let storage = Storage::from_env(Storage::Memory)?.within(&cluster_state.node_id);
+        let mut logs = HashMap::<String, Log>::new();
+        for key in storage.names()? {
+            logs.insert(
+                key.clone(),
+                Log::new(storage.open(&key, cluster_state.clock.clone())?)?,
+            );
+        }
+
         Ok(KafkaServer {
-            logs: HashMap::<String, Log>::new(),
-            offset_store: HashMap::<String, Offset>::new(),
+            logs,
+            offset_store: HashMap::new(),
+            coordinator: Coordinator::new(&incarnation(cluster_state), DEFAULT_SESSION_TIMEOUT),
+            producer_ids: Snowflake::new(0, cluster_state.clock.clone())?,
+            storage,
+            retention: Retention::from_env(Retention::default())?,
         })
     }
 
     fn on_message(
         &mut self,
-        _: &ClusterState,
+        cluster_state: &ClusterState,
         io: &mut IO<Payload>,
         input: Message<Payload>,
     ) -> Result<()> {
         let payload = &input.body.payload;
         match payload {
-            Payload::Send { key, msg } => {
+            Payload::Send {
+                key,
+                msg,
+                sub_key,
+                producer_id,
+                seq,
+            } => {
                 let log = match self.logs.entry(key.to_string()) {
                     std::collections::hash_map::Entry::Occupied(o) => o.into_mut(),
-                    std::collections::hash_map::Entry::Vacant(v) => v.insert(Log::new()),
+                    std::collections::hash_map::Entry::Vacant(v) => {
+                        let store = self.storage.open(key, cluster_state.clock.clone())?;
+                        v.insert(Log::new(store)?)
+                    }
                 };
 
-                let offset = log.append(*msg);
+                let producer = producer_id.zip(*seq);
+                let sequence = match producer {
+                    Some((producer, seq)) => log.producers.check(producer, seq),
+                    None => Sequence::New,
+                };
 
-                let send_ok = Payload::SendOk { offset };
-                io.rpc_reply_to(&input, &send_ok)?;
-            },
+                let reply = match sequence {
+                    Sequence::New => {
+                        let now = now_ms(cluster_state)?;
+                        let offset = log.append(now, *msg, producer, sub_key.as_deref())?;
+                        Payload::SendOk { offset }
+                    }
+                    Sequence::Duplicate(offset) => Payload::SendOk { offset },
+                    Sequence::OutOfOrder { expected } => Payload::Error {
+                        code: PRECONDITION_FAILED,
+                        text: format!("expected sequence number {}", expected),
+                    },
+                    Sequence::TooOld => Payload::Error {
+                        code: PRECONDITION_FAILED,
+                        text: "sequence number too old to deduplicate".to_string(),
+                    },
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
             Payload::SendOk { offset: _ } => todo!(),
+            Payload::InitProducerId => {
+                let producer_id = self.producer_ids.next_id()?;
+                io.rpc_reply_to(&input, &Payload::InitProducerIdOk { producer_id })?;
+            }
+            Payload::InitProducerIdOk { .. } => {
+                eprintln!("ignoring init_producer_id_ok from {}", input.src);
+            }
             Payload::Poll { offsets } => {
-                let messages: HashMap<String, Vec<Record>> = offsets.iter().map(|(key, offset)| {
-                    let records = match self.logs.entry(key.to_string()) {
-                        std::collections::hash_map::Entry::Occupied(o) => o.get().read_from(*offset),
-                        std::collections::hash_map::Entry::Vacant(_) => Vec::<Record>::new(),
-                    };
+                let messages = offsets
+                    .iter()
+                    .map(|(key, offset)| {
+                        let records = match self.logs.get(key) {
+                            Some(log) => log.read_from(*offset)?,
+                            None => Vec::<Record>::new(),
+                        };
+
+                        Ok((key.to_string(), records))
+                    })
+                    .collect::<Result<HashMap<String, Vec<Record>>>>()?;
 
-                    (key.to_string(), records)
-                }).collect();
+                let log_start_offsets = offsets
+                    .keys()
+                    .filter(|key| self.logs.contains_key(*key))
+                    .map(|key| (key.to_string(), self.log_start(key)))
+                    .collect();
 
-                let poll_ok = Payload::PollOk { msgs: messages };
+                let poll_ok = Payload::PollOk {
+                    msgs: messages,
+                    log_start_offsets,
+                };
                 io.rpc_reply_to(&input, &poll_ok)?;
-            },
-            Payload::PollOk { msgs: _ } => todo!(),
-            Payload::CommitOffsets { offsets } => {
+            }
+            Payload::PollOk { .. } => todo!(),
+            Payload::DeleteRecords { offsets } => {
+                let mut log_start_offsets = HashMap::new();
+                for (key, offset) in offsets {
+                    if let Some(log) = self.logs.get_mut(key) {
+                        let offset = (*offset).min(log.store.next_offset());
+                        log.store.delete_before(offset)?;
+                        log_start_offsets.insert(key.to_string(), log.store.start_offset());
+                    }
+                }
+
+                let delete_records_ok = Payload::DeleteRecordsOk { log_start_offsets };
+                io.rpc_reply_to(&input, &delete_records_ok)?;
+            }
+            Payload::DeleteRecordsOk { .. } => {
+                eprintln!("ignoring delete_records_ok from {}", input.src);
+            }
+            Payload::CommitOffsets {
+                offsets,
+                group,
+                member_id,
+                generation,
+            } => {
+                let group = group.as_deref().unwrap_or(NO_GROUP);
+                let check = self.coordinator.check_commit(
+                    group,
+                    member_id.as_deref(),
+                    *generation,
+                    offsets.keys(),
+                );
+                if let Err(e) = check {
+                    let error = Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                    };
+                    io.rpc_reply_to(&input, &error)?;
+                    return Ok(());
+                }
+
                 for (k, v) in offsets {
-                    self.offset_store.insert(k.to_string(), *v);
+                    self.offset_store
+                        .insert((group.to_string(), k.to_string()), *v);
                 }
 
-                let commit_offsets_ok = Payload::CommitOffsetsOk{};
+                let commit_offsets_ok = Payload::CommitOffsetsOk {};
                 io.rpc_reply_to(&input, &commit_offsets_ok)?;
-            },
+            }
             Payload::CommitOffsetsOk => todo!(),
-            Payload::ListCommittedOffsets { keys } => {
+            Payload::ListCommittedOffsets { keys, group } => {
+                let group = group.as_deref().unwrap_or(NO_GROUP);
                 let mut offsets = HashMap::new();
                 for k in keys {
-                    if let Some(offset) = self.offset_store.get(k) {
-                        offsets.insert(k.to_string(), *offset);
+                    // Consumers resume from the start of the log if what they
+                    // committed has been deleted since.
+                    let committed = self.offset_store.get(&(group.to_string(), k.to_string()));
+                    if let Some(offset) = committed {
+                        offsets.insert(k.to_string(), (*offset).max(self.log_start(k)));
                     }
                 }
 
                 let list_committed_offsets_ok = Payload::ListCommittedOffsetsOk { offsets };
                 io.rpc_reply_to(&input, &list_committed_offsets_ok)?;
-            },
+            }
             Payload::ListCommittedOffsetsOk { offsets: _ } => todo!(),
+            Payload::JoinGroup {
+                group,
+                member_id,
+                keys,
+                assignor,
+            } => {
+                let (member_id, generation, keys) = self.coordinator.join(
+                    group,
+                    member_id.as_deref(),
+                    keys.clone(),
+                    *assignor,
+                    now_ms(cluster_state)?,
+                );
+
+                let join_group_ok = Payload::JoinGroupOk {
+                    member_id,
+                    generation,
+                    keys,
+                };
+                io.rpc_reply_to(&input, &join_group_ok)?;
+            }
+            Payload::JoinGroupOk { .. } => {
+                eprintln!("ignoring join_group_ok from {}", input.src);
+            }
+            Payload::GroupHeartbeat { group, member_id } => {
+                let now = now_ms(cluster_state)?;
+                let reply = match self.coordinator.heartbeat(group, member_id, now) {
+                    Ok((generation, keys)) => Payload::GroupHeartbeatOk { generation, keys },
+                    Err(e) => Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                    },
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::GroupHeartbeatOk { .. } => {
+                eprintln!("ignoring group_heartbeat_ok from {}", input.src);
+            }
+            Payload::LeaveGroup { group, member_id } => {
+                let reply = match self.coordinator.leave(group, member_id) {
+                    Ok(()) => Payload::LeaveGroupOk,
+                    Err(e) => Payload::Error {
+                        code: e.code(),
+                        text: e.to_string(),
+                    },
+                };
+                io.rpc_reply_to(&input, &reply)?;
+            }
+            Payload::LeaveGroupOk => {
+                eprintln!("ignoring leave_group_ok from {}", input.src);
+            }
+            Payload::Error { code, text } => {
+                eprintln!("ignoring error {} from {}: {}", code, input.src, text);
+            }
         };
 
         Ok(())
     }
 
-    fn on_timer(&mut self, _: &ClusterState, _: &mut IO<Payload>, _: ()) -> Result<()>
+    fn on_timer(
+        &mut self,
+        cluster_state: &ClusterState,
+        _: &mut IO<Payload>,
+        timer: Timer,
+    ) -> Result<()>
     where
         Self: Sized,
     {
+        let now = now_ms(cluster_state)?;
+        match timer {
+            Timer::Retention => {
+                for log in self.logs.values_mut() {
+                    log.enforce(&self.retention, now)?;
+                }
+            }
+            Timer::Sessions => {
+                for (group, member) in self.coordinator.expire(now) {
+                    eprintln!("{} left {} without a heartbeat", member, group);
+                }
+            }
+        }
+
         Ok(())
     }
 
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         _: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
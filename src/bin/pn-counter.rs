@@ -0,0 +1,12 @@
+use gossip_glomers_rs::{
+    counter::{CounterServer, Payload, Timer},
+    crdt::PNCounter,
+    Node,
+};
+
+/// Like the g-counter, but every slot is a pair of increment and decrement
+/// totals so that negative deltas merge just as safely.
+fn main() -> anyhow::Result<()> {
+    let mut node = Node::<CounterServer<PNCounter>, Payload<PNCounter>, Timer>::init()?;
+    node.run()
+}
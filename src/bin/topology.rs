@@ -0,0 +1,37 @@
+use std::env;
+
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+
+use gossip_glomers_rs::topology::{self, Strategy};
+
+const USAGE: &str = "usage: topology <node count> <strategy> [dot|json|stats]";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (count, strategy, format) = match args.as_slice() {
+        [count, strategy] => (count, strategy, "dot"),
+        [count, strategy, format] => (count, strategy, format.as_str()),
+        _ => bail!(USAGE),
+    };
+
+    let count: usize = count.parse().context(USAGE)?;
+    let strategy: Strategy = strategy.parse()?;
+    let node_ids: Vec<String> = (0..count).map(|n| format!("n{}", n)).collect();
+    let topology = strategy.build(&node_ids)?;
+
+    match format {
+        "dot" => print!("{}", topology::to_dot(&topology, &node_ids)),
+        "json" => println!("{}", topology::to_json(&topology)?),
+        "stats" => {
+            let stats = json!({
+                "strategy": strategy.to_string(),
+                "stats": topology::stats(&topology),
+            });
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+        _ => bail!(USAGE),
+    }
+
+    Ok(())
+}
@@ -48,11 +48,6 @@ impl Serialize for Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Txn {
-    txn: Vec<Op>,
-}
-
 fn main() -> anyhow::Result<()> {
     let mut node = Node::<TxnKVServer, Payload, ()>::init()?;
     node.run()
@@ -114,9 +109,7 @@ impl Server<Payload, ()> for TxnKVServer {
 
                     for n in nodes {
                         for w in &writes {
-                            let replicate = Payload::Replicate {
-                                ops: vec![*w],
-                            };
+                            let replicate = Payload::Replicate { ops: vec![*w] };
 
                             io.rpc_request_with_retry(n, &replicate, Duration::from_millis(500))?;
                         }
@@ -158,6 +151,7 @@ impl Server<Payload, ()> for TxnKVServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         _: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
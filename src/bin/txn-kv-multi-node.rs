@@ -1,6 +1,12 @@
 use std::{collections::HashMap, time::Duration};
 
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
+use gossip_glomers_rs::{
+    clocks::{LamportTimestamp, LogicalClock},
+    hash::fnv1a,
+    merkle::{MerkleSync, MerkleTree, NodeIndex, DEFAULT_DEPTH},
+    ClusterState, Message, Node, Server, Timers, IO,
+};
+use rand::seq::SliceRandom;
 use serde::{ser::SerializeSeq, Deserialize, Serialize};
 
 use anyhow::{bail, Result};
@@ -9,11 +15,27 @@ use anyhow::{bail, Result};
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 enum Payload {
-    Txn { txn: Vec<Op> },
-    TxnOk { txn: Vec<Op> },
+    Txn {
+        txn: Vec<Op>,
+    },
+    TxnOk {
+        txn: Vec<Op>,
+    },
 
-    Replicate { ops: Vec<Op> },
-    ReplicateOk,
+    Replicate {
+        ops: Vec<Op>,
+        version: LamportTimestamp,
+    },
+    Merkle {
+        sync: MerkleSync<Entry>,
+    },
+}
+
+type Entry = (usize, usize, LamportTimestamp);
+
+#[derive(Clone, Copy, Debug)]
+enum Timer {
+    AntiEntropy,
 }
 
 #[derive(Debug, Deserialize, Clone, Copy)]
@@ -48,29 +70,74 @@ impl Serialize for Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Txn {
-    txn: Vec<Op>,
-}
-
 fn main() -> anyhow::Result<()> {
-    let mut node = Node::<TxnKVServer, Payload, ()>::init()?;
+    let mut node = Node::<TxnKVServer, Payload, Timer>::init()?;
     node.run()
 }
 
+fn key_hash(key: usize) -> u64 {
+    fnv1a(&key.to_le_bytes())
+}
+
+fn entry_hash(key: usize, value: usize, version: &LamportTimestamp) -> u64 {
+    let mut bytes = Vec::new();
+    bytes.extend(key.to_le_bytes());
+    bytes.extend(value.to_le_bytes());
+    bytes.extend(version.time.to_le_bytes());
+    bytes.extend(version.node.as_bytes());
+    fnv1a(&bytes)
+}
+
 struct TxnKVServer {
-    store: HashMap<usize, usize>,
+    store: HashMap<usize, (usize, LamportTimestamp)>,
+    tree: MerkleTree,
 }
 
-impl Server<Payload, ()> for TxnKVServer {
-    fn init(_: &ClusterState, _: &mut Timers<Payload, ()>) -> Result<TxnKVServer> {
+impl TxnKVServer {
+    /// Last writer wins by Lamport timestamp, so replicas converge no matter
+    /// in which order `Replicate` messages arrive.
+    fn apply(&mut self, key: usize, value: usize, version: &LamportTimestamp) {
+        match self.store.get(&key) {
+            Some((_, current)) if current > version => (),
+            current => {
+                if let Some((v, current)) = current {
+                    self.tree
+                        .toggle(key_hash(key), entry_hash(key, *v, current));
+                }
+                self.tree
+                    .toggle(key_hash(key), entry_hash(key, value, version));
+                self.store.insert(key, (value, version.clone()));
+            }
+        }
+    }
+
+    fn entries(&self, leaves: &[NodeIndex]) -> Vec<Entry> {
+        self.store
+            .iter()
+            .filter(|(&k, _)| leaves.contains(&self.tree.leaf(key_hash(k))))
+            .map(|(&k, (v, version))| (k, *v, version.clone()))
+            .collect()
+    }
+}
+
+impl Server<Payload, Timer> for TxnKVServer {
+    fn init(_: &ClusterState, timers: &mut Timers<Payload, Timer>) -> Result<TxnKVServer> {
+        timers.register_timer(Timer::AntiEntropy, Duration::from_millis(250));
+
         let server = TxnKVServer {
-            store: HashMap::<usize, usize>::new(),
+            store: HashMap::<usize, (usize, LamportTimestamp)>::new(),
+            tree: MerkleTree::new(DEFAULT_DEPTH),
         };
 
         Ok(server)
     }
 
+    /// Stamping every message between nodes keeps the clock ahead of every
+    /// version a node has been sent, so there's nothing to observe by hand.
+    fn logical_clock(_: &ClusterState) -> Option<LogicalClock> {
+        Some(LogicalClock::lamport())
+    }
+
     fn on_message(
         &mut self,
         cluster_state: &ClusterState,
@@ -80,12 +147,16 @@ impl Server<Payload, ()> for TxnKVServer {
         let payload = &input.body.payload;
         match payload {
             Payload::Txn { txn } => {
+                let Some(LogicalClock::Lamport(clock)) = io.logical_clock() else {
+                    bail!("messages aren't stamped with a Lamport clock");
+                };
+                let version = clock.timestamp(&cluster_state.node_id);
                 let mut result = Vec::new();
                 let mut writes = Vec::new();
                 for t in txn {
                     match t {
                         Op::Read { key, value: _ } => match self.store.get(key) {
-                            Some(v) => {
+                            Some((v, _)) => {
                                 result.push(Op::Read {
                                     key: *key,
                                     value: Some(*v),
@@ -99,13 +170,15 @@ impl Server<Payload, ()> for TxnKVServer {
                             }
                         },
                         Op::Write { key, value } => {
-                            self.store.insert(*key, *value);
+                            self.apply(*key, *value, &version);
                             result.push(*t);
                             writes.push(*t);
                         }
                     }
                 }
 
+                // Sent once; anything lost is picked up by the Merkle
+                // anti-entropy rounds.
                 if !writes.is_empty() {
                     let nodes = cluster_state
                         .node_ids
@@ -115,30 +188,33 @@ impl Server<Payload, ()> for TxnKVServer {
                     for n in nodes {
                         let replicate = Payload::Replicate {
                             ops: writes.clone(),
+                            version: version.clone(),
                         };
 
-                        io.rpc_request_with_retry(n, &replicate, Duration::from_millis(500))?;
+                        io.fire_and_forget(n, &replicate)?;
                     }
                 }
 
                 let txn_ok = Payload::TxnOk { txn: result };
                 io.rpc_reply_to(&input, &txn_ok)?;
             }
-            Payload::Replicate { ops } if !io.rpc_still_pending(&input) => {
+            Payload::Replicate { ops, version } => {
                 for op in ops {
                     if let Op::Write { key, value } = op {
-                        self.store.insert(*key, *value);
+                        self.apply(*key, *value, version);
                     }
                 }
-
-                let replicate_ok = Payload::ReplicateOk {};
-                io.rpc_reply_to(&input, &replicate_ok)?;
             }
-            Payload::ReplicateOk => {
-                io.rpc_mark_completed(&input);
-            }
-            _ if input.body.in_reply_to.is_some() && !io.rpc_still_pending(&input) => {
-                eprintln!("received late response");
+            Payload::Merkle { sync } => {
+                if let MerkleSync::Repair { entries, .. } = sync {
+                    for (key, value, version) in entries {
+                        self.apply(*key, *value, version);
+                    }
+                }
+
+                for sync in self.tree.reply(sync, |leaves| self.entries(leaves)) {
+                    io.fire_and_forget(&input.src, &Payload::Merkle { sync })?;
+                }
             }
             _ => bail!("unexpected payload {:?}", payload),
         };
@@ -146,16 +222,37 @@ impl Server<Payload, ()> for TxnKVServer {
         Ok(())
     }
 
-    fn on_timer(&mut self, _: &ClusterState, _: &mut IO<Payload>, _: ()) -> Result<()>
+    fn on_timer(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<Payload>,
+        timer: Timer,
+    ) -> Result<()>
     where
         Self: Sized,
     {
+        match timer {
+            Timer::AntiEntropy => {
+                let peers: Vec<&String> = cluster_state
+                    .node_ids
+                    .iter()
+                    .filter(|&n| n != &cluster_state.node_id)
+                    .collect();
+
+                if let Some(peer) = peers.choose(&mut rand::thread_rng()) {
+                    let sync = self.tree.summary();
+                    io.fire_and_forget(peer, &Payload::Merkle { sync })?;
+                }
+            }
+        }
+
         Ok(())
     }
 
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         _: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
@@ -45,11 +45,6 @@ impl Serialize for Op {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Txn {
-    txn: Vec<Op>,
-}
-
 fn main() -> anyhow::Result<()> {
     let mut node = Node::<TxnKVServer, Payload, ()>::init()?;
     node.run()
@@ -118,6 +113,7 @@ impl Server<Payload, ()> for TxnKVServer {
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
+        _: &mut IO<Payload>,
         _: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
@@ -1,4 +1,10 @@
-use gossip_glomers_rs::{ClusterState, Message, Node, Server, Timers, IO};
+use std::{collections::VecDeque, time::Duration};
+
+use gossip_glomers_rs::{
+    ids::{BlockIds, Id, IdFormat, IdGenerator},
+    kv::{KEY_DOES_NOT_EXIST, LIN_KV, PRECONDITION_FAILED},
+    ClusterState, Message, Node, Server, Timers, IO,
+};
 use serde::{Deserialize, Serialize};
 
 use anyhow::{bail, Result};
@@ -8,57 +14,229 @@ use anyhow::{bail, Result};
 #[serde(rename_all = "snake_case")]
 enum Payload {
     Generate,
-    GenerateOk { id: String },
+    GenerateOk {
+        id: Id,
+    },
+
+    Read {
+        key: String,
+    },
+    ReadOk {
+        value: u64,
+    },
+    Cas {
+        key: String,
+        from: u64,
+        to: u64,
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: u64,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Timer {
+    Lease,
 }
 
+/// lin-kv key holding the start of the next unleased block.
+const BLOCK_KEY: &str = "unique-ids-next-block";
+const BLOCK_SIZE: u64 = 1000;
+const LEASE_TIMEOUT: Duration = Duration::from_millis(500);
+
 fn main() -> anyhow::Result<()> {
-    let mut node = Node::<UniqueIdServer, Payload, ()>::init()?;
+    let mut node = Node::<UniqueIdServer, Payload, Timer>::init()?;
     node.run()
 }
 
-struct UniqueIdServer {}
+enum Ids {
+    Generated(IdGenerator),
+    Leased(BlockIds),
+}
+
+/// Where we are in leasing the next block: read the counter, then move it
+/// forward by a block with a `cas`. Losing the race means starting over.
+#[derive(Clone, Copy, Debug)]
+enum Lease {
+    Idle,
+    Reading,
+    Swapping { from: u64 },
+}
+
+struct UniqueIdServer {
+    ids: Ids,
+    lease: Lease,
+    waiting: VecDeque<Message<Payload>>,
+}
+
+impl UniqueIdServer {
+    /// Answers queued `generate` requests for as long as the leased blocks
+    /// last.
+    fn serve(&mut self, io: &mut IO<Payload>) -> Result<()> {
+        let Ids::Leased(blocks) = &mut self.ids else {
+            return Ok(());
+        };
+
+        while !self.waiting.is_empty() {
+            let Some(id) = blocks.next_id() else {
+                break;
+            };
+
+            let input = self.waiting.pop_front().expect("checked above");
+            let reply = Payload::GenerateOk {
+                id: Id::Numeric(id),
+            };
+            io.rpc_reply_to(&input, &reply)?;
+        }
 
-impl Server<Payload, ()> for UniqueIdServer {
-    fn init(_: &ClusterState, _: &mut Timers<Payload, ()>) -> Result<UniqueIdServer> {
-        Ok(UniqueIdServer {})
+        Ok(())
+    }
+
+    fn lease_more(&mut self, io: &mut IO<Payload>) -> Result<()> {
+        let Ids::Leased(blocks) = &self.ids else {
+            return Ok(());
+        };
+
+        if matches!(self.lease, Lease::Idle) && blocks.needs_block() {
+            let read = Payload::Read {
+                key: BLOCK_KEY.to_string(),
+            };
+            io.rpc_request(LIN_KV, &read, LEASE_TIMEOUT, false)?;
+            self.lease = Lease::Reading;
+        }
+
+        Ok(())
+    }
+
+    fn swap(&mut self, io: &mut IO<Payload>, from: u64, create: bool) -> Result<()> {
+        let Ids::Leased(blocks) = &self.ids else {
+            return Ok(());
+        };
+
+        let cas = Payload::Cas {
+            key: BLOCK_KEY.to_string(),
+            from,
+            to: from + blocks.block_size(),
+            create_if_not_exists: create,
+        };
+        io.rpc_request(LIN_KV, &cas, LEASE_TIMEOUT, false)?;
+        self.lease = Lease::Swapping { from };
+
+        Ok(())
+    }
+}
+
+impl Server<Payload, Timer> for UniqueIdServer {
+    fn init(
+        cluster_state: &ClusterState,
+        timers: &mut Timers<Payload, Timer>,
+    ) -> Result<UniqueIdServer> {
+        let format = IdFormat::from_env(IdFormat::Numeric)?;
+        eprintln!("Generating {} ids", format);
+
+        let ids = match format {
+            IdFormat::Block => {
+                timers.register_timer(Timer::Lease, Duration::from_millis(100));
+                Ids::Leased(BlockIds::new(BLOCK_SIZE))
+            }
+            _ => Ids::Generated(IdGenerator::new(format, cluster_state)?),
+        };
+
+        Ok(UniqueIdServer {
+            ids,
+            lease: Lease::Idle,
+            waiting: VecDeque::new(),
+        })
     }
 
     fn on_message(
         &mut self,
-        cluster_state: &ClusterState,
+        _: &ClusterState,
         io: &mut IO<Payload>,
         input: Message<Payload>,
     ) -> Result<()> {
         let payload = &input.body.payload;
         match payload {
-            Payload::Generate => {
-                let id = format!("{}-{}", cluster_state.node_id, io.seq);
-                let reply = Payload::GenerateOk { id };
-                io.rpc_reply_to(&input, &reply)?;
+            Payload::Generate => match &mut self.ids {
+                Ids::Generated(generator) => {
+                    let id = generator.next_id()?;
+                    let reply = Payload::GenerateOk { id };
+                    io.rpc_reply_to(&input, &reply)?;
+                }
+                Ids::Leased(_) => {
+                    self.waiting.push_back(input);
+                    self.serve(io)?;
+                    self.lease_more(io)?;
+                }
+            },
+            Payload::ReadOk { value } if io.rpc_still_pending(&input) => {
+                io.rpc_mark_completed(&input);
+                if let Lease::Reading = self.lease {
+                    self.swap(io, *value, false)?;
+                }
             }
-            Payload::GenerateOk { .. } => {
-                bail!("received unexpected GenerateOk message");
+            Payload::CasOk if io.rpc_still_pending(&input) => {
+                io.rpc_mark_completed(&input);
+                if let (Lease::Swapping { from }, Ids::Leased(blocks)) = (self.lease, &mut self.ids)
+                {
+                    blocks.add_block(from);
+                }
+
+                self.lease = Lease::Idle;
+                self.serve(io)?;
+                self.lease_more(io)?;
+            }
+            Payload::Error { code, text } if io.rpc_still_pending(&input) => {
+                io.rpc_mark_completed(&input);
+                match (self.lease, *code) {
+                    (Lease::Reading, KEY_DOES_NOT_EXIST) => self.swap(io, 0, true)?,
+                    (Lease::Swapping { .. }, PRECONDITION_FAILED) => {
+                        self.lease = Lease::Idle;
+                        self.lease_more(io)?;
+                    }
+                    _ => {
+                        // Retried from the lease timer.
+                        eprintln!("leasing a block failed: {} ({})", text, code);
+                        self.lease = Lease::Idle;
+                    }
+                }
+            }
+            _ if input.body.in_reply_to.is_some() && !io.rpc_still_pending(&input) => {
+                eprintln!("received late response");
             }
+            _ => bail!("unexpected payload {:?}", payload),
         };
 
         Ok(())
     }
 
-    fn on_timer(&mut self, _: &ClusterState, _: &mut IO<Payload>, _: ()) -> Result<()>
+    fn on_timer(&mut self, _: &ClusterState, io: &mut IO<Payload>, timer: Timer) -> Result<()>
     where
         Self: Sized,
     {
-        todo!()
+        match timer {
+            Timer::Lease => self.lease_more(io),
+        }
     }
 
     fn on_rpc_timeout(
         &mut self,
         _: &ClusterState,
-        _: gossip_glomers_rs::Request<Payload>,
+        _: &mut IO<Payload>,
+        timeout: gossip_glomers_rs::Request<Payload>,
     ) -> Result<()>
     where
         Self: Sized,
     {
-        bail!("unexpected RPC timeout");
+        // A timed out cas may still have gone through, which only wastes
+        // that block.
+        eprintln!("Timeout: {:?}", timeout);
+        self.lease = Lease::Idle;
+
+        Ok(())
     }
 }
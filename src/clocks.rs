@@ -0,0 +1,384 @@
+use std::{
+    cmp::{self, Ordering},
+    collections::BTreeMap,
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::time::Clock;
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        LamportClock::default()
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Advances the clock for a local event and returns the new time.
+    pub fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    /// Advances the clock past a time seen on an incoming message.
+    pub fn observe(&mut self, time: u64) -> u64 {
+        self.time = cmp::max(self.time, time) + 1;
+        self.time
+    }
+
+    /// A totally ordered timestamp for the next local event, using the node
+    /// id to break ties between events with the same time.
+    pub fn timestamp(&mut self, node: &str) -> LamportTimestamp {
+        LamportTimestamp {
+            time: self.tick(),
+            node: node.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LamportTimestamp {
+    pub time: u64,
+    pub node: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock {
+    entries: BTreeMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        VectorClock::default()
+    }
+
+    pub fn get(&self, node: &str) -> u64 {
+        self.entries.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn tick(&mut self, node: &str) -> u64 {
+        let entry = self.entries.entry(node.to_string()).or_default();
+        *entry += 1;
+        *entry
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, &time) in &other.entries {
+            let entry = self.entries.entry(node.clone()).or_default();
+            *entry = cmp::max(*entry, time);
+        }
+    }
+
+    /// Merges the clock of an incoming message and counts the receive as a
+    /// local event.
+    pub fn observe(&mut self, node: &str, other: &VectorClock) {
+        self.merge(other);
+        self.tick(node);
+    }
+
+    /// `None` when the two clocks are concurrent.
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        let nodes = self.entries.keys().chain(other.entries.keys());
+        for node in nodes {
+            let current = match self.get(node).cmp(&other.get(node)) {
+                Ordering::Equal => continue,
+                o => o,
+            };
+
+            if ordering == Ordering::Equal {
+                ordering = current;
+            } else if ordering != current {
+                return None;
+            }
+        }
+
+        Some(ordering)
+    }
+
+    pub fn happened_before(&self, other: &VectorClock) -> bool {
+        self.compare(other) == Some(Ordering::Less)
+    }
+
+    pub fn concurrent_with(&self, other: &VectorClock) -> bool {
+        self.compare(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.compare(other)
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct HlcTimestamp {
+    /// Milliseconds since the UNIX epoch.
+    pub wall: u64,
+    pub logical: u32,
+}
+
+/// Hybrid logical clock: timestamps stay close to physical time but still
+/// respect causality when physical clocks drift apart.
+pub struct HybridLogicalClock {
+    last: HlcTimestamp,
+    clock: Arc<dyn Clock>,
+}
+
+impl HybridLogicalClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        HybridLogicalClock {
+            last: HlcTimestamp::default(),
+            clock,
+        }
+    }
+
+    pub fn last(&self) -> HlcTimestamp {
+        self.last
+    }
+
+    fn physical(&self) -> u64 {
+        self.clock
+            .wall()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    pub fn tick(&mut self) -> HlcTimestamp {
+        let physical = self.physical();
+        self.last = if physical > self.last.wall {
+            HlcTimestamp {
+                wall: physical,
+                logical: 0,
+            }
+        } else {
+            HlcTimestamp {
+                wall: self.last.wall,
+                logical: self.last.logical + 1,
+            }
+        };
+
+        self.last
+    }
+
+    pub fn observe(&mut self, remote: HlcTimestamp) -> HlcTimestamp {
+        let physical = self.physical();
+        let wall = self.last.wall.max(remote.wall).max(physical);
+        let logical = if wall == self.last.wall && wall == remote.wall {
+            cmp::max(self.last.logical, remote.logical) + 1
+        } else if wall == self.last.wall {
+            self.last.logical + 1
+        } else if wall == remote.wall {
+            remote.logical + 1
+        } else {
+            0
+        };
+
+        self.last = HlcTimestamp { wall, logical };
+        self.last
+    }
+}
+
+/// Timestamp attached to a message `Body` when stamping is enabled.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stamp {
+    Lamport(u64),
+    Vector(VectorClock),
+    Hybrid(HlcTimestamp),
+}
+
+pub enum LogicalClock {
+    Lamport(LamportClock),
+    Vector(VectorClock),
+    Hybrid(HybridLogicalClock),
+}
+
+impl LogicalClock {
+    pub fn lamport() -> Self {
+        LogicalClock::Lamport(LamportClock::new())
+    }
+
+    pub fn vector() -> Self {
+        LogicalClock::Vector(VectorClock::new())
+    }
+
+    pub fn hybrid(clock: Arc<dyn Clock>) -> Self {
+        LogicalClock::Hybrid(HybridLogicalClock::new(clock))
+    }
+
+    /// Advances the clock for an outgoing message and returns its stamp.
+    pub fn stamp(&mut self, node: &str) -> Stamp {
+        match self {
+            LogicalClock::Lamport(c) => Stamp::Lamport(c.tick()),
+            LogicalClock::Vector(c) => {
+                c.tick(node);
+                Stamp::Vector(c.clone())
+            }
+            LogicalClock::Hybrid(c) => Stamp::Hybrid(c.tick()),
+        }
+    }
+
+    /// Advances the clock past the stamp of an incoming message. Stamps of a
+    /// different kind are ignored.
+    pub fn observe(&mut self, node: &str, stamp: &Stamp) {
+        match (self, stamp) {
+            (LogicalClock::Lamport(c), Stamp::Lamport(t)) => {
+                c.observe(*t);
+            }
+            (LogicalClock::Vector(c), Stamp::Vector(v)) => c.observe(node, v),
+            (LogicalClock::Hybrid(c), Stamp::Hybrid(t)) => {
+                c.observe(*t);
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::{seq::SliceRandom, Rng};
+
+    use crate::time::ManualClock;
+
+    use super::*;
+
+    fn vector(entries: &[(&str, u64)]) -> VectorClock {
+        VectorClock {
+            entries: entries.iter().map(|&(n, t)| (n.to_string(), t)).collect(),
+        }
+    }
+
+    #[test]
+    fn vector_clocks_compare_by_every_entry() {
+        let a = vector(&[("n0", 1), ("n1", 2)]);
+        let b = vector(&[("n0", 2), ("n1", 2)]);
+        let c = vector(&[("n0", 0), ("n1", 3)]);
+
+        assert_eq!(a.compare(&a), Some(Ordering::Equal));
+        assert_eq!(a.compare(&b), Some(Ordering::Less));
+        assert_eq!(b.compare(&a), Some(Ordering::Greater));
+        assert!(a.happened_before(&b));
+        assert!(b.concurrent_with(&c));
+        assert!(c.concurrent_with(&b));
+
+        // Missing entries count as zero.
+        assert_eq!(
+            vector(&[("n0", 0)]).compare(&VectorClock::new()),
+            Some(Ordering::Equal)
+        );
+        assert!(VectorClock::new().happened_before(&vector(&[("n2", 1)])));
+    }
+
+    #[test]
+    fn merged_vector_clocks_are_the_least_upper_bound() {
+        let mut rng = rand::thread_rng();
+        let nodes = ["n0", "n1", "n2"];
+        for _ in 0..100 {
+            let mut clocks: Vec<VectorClock> = (0..3)
+                .map(|_| {
+                    let mut clock = VectorClock::new();
+                    for _ in 0..rng.gen_range(0..10) {
+                        clock.tick(nodes.choose(&mut rng).unwrap());
+                    }
+                    clock
+                })
+                .collect();
+
+            let mut merged = VectorClock::new();
+            for clock in &clocks {
+                merged.merge(clock);
+                assert!(clock <= &merged);
+            }
+            for node in nodes {
+                assert_eq!(
+                    merged.get(node),
+                    clocks.iter().map(|c| c.get(node)).max().unwrap()
+                );
+            }
+
+            clocks.shuffle(&mut rng);
+            let mut shuffled = VectorClock::new();
+            for clock in &clocks {
+                shuffled.merge(clock);
+            }
+            assert_eq!(shuffled, merged);
+        }
+    }
+
+    #[test]
+    fn hybrid_timestamps_only_go_forward_when_clocks_are_skewed() {
+        let clock = Arc::new(ManualClock::new());
+        let mut hlc = HybridLogicalClock::new(clock.clone());
+        let mut last = hlc.tick();
+
+        // A node whose clock is a minute ahead.
+        let ahead = HlcTimestamp {
+            wall: last.wall + 60_000,
+            logical: 3,
+        };
+        let observed = hlc.observe(ahead);
+        assert!(observed > ahead && observed > last);
+        last = observed;
+
+        for _ in 0..100 {
+            clock.advance(Duration::from_millis(10));
+            let next = hlc.tick();
+            assert!(next > last);
+            assert_eq!(next.wall, ahead.wall);
+            last = next;
+        }
+
+        // A node whose clock is behind doesn't hold us back.
+        let behind = HlcTimestamp {
+            wall: last.wall - 120_000,
+            logical: 0,
+        };
+        let observed = hlc.observe(behind);
+        assert!(observed > last);
+
+        // Once the physical clock catches up, it takes over again.
+        clock.advance(Duration::from_secs(120));
+        let next = hlc.tick();
+        assert!(next > observed);
+        assert_eq!(next.logical, 0);
+    }
+
+    #[test]
+    fn stamps_round_trip_through_json() {
+        let stamps = [
+            Stamp::Lamport(7),
+            Stamp::Vector(vector(&[("n0", 1), ("n1", 4)])),
+            Stamp::Hybrid(HlcTimestamp {
+                wall: 1_700_000_000_000,
+                logical: 2,
+            }),
+        ];
+
+        for stamp in stamps {
+            let json = serde_json::to_string(&stamp).unwrap();
+            assert_eq!(serde_json::from_str::<Stamp>(&json).unwrap(), stamp);
+        }
+
+        let json = serde_json::to_string(&Stamp::Vector(vector(&[("n0", 1)]))).unwrap();
+        assert_eq!(json, r#"{"vector":{"n0":1}}"#);
+    }
+}
@@ -0,0 +1,114 @@
+//! The server behind the g-counter and pn-counter binaries: each node only
+//! ever counts in its own slot, and the whole counter is gossiped as deltas
+//! that merge with `max`, so lost or repeated gossip can't skew the count.
+
+use std::{fmt::Debug, time::Duration};
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    anti_entropy::{incarnation, AntiEntropy, Delta},
+    crdt::Counter,
+    ClusterState, Message, Server, Timers, IO,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+#[serde(bound = "C: Serialize + DeserializeOwned, C::Value: Serialize + DeserializeOwned")]
+pub enum Payload<C: Counter> {
+    Add { delta: C::Value },
+    AddOk,
+    Read,
+    ReadOk { value: C::Value },
+    Gossip { delta: Delta<C> },
+    GossipOk { seq: u64 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Timer {
+    Gossip,
+}
+
+pub struct CounterServer<C> {
+    counter: AntiEntropy<C>,
+    slot: String,
+}
+
+impl<C> Server<Payload<C>, Timer> for CounterServer<C>
+where
+    C: Counter + Default + Clone + PartialEq + Debug + Serialize + DeserializeOwned,
+    C::Value: Copy + Debug + Serialize + DeserializeOwned,
+{
+    fn init(
+        cluster_state: &ClusterState,
+        timers: &mut Timers<Payload<C>, Timer>,
+    ) -> Result<CounterServer<C>> {
+        timers.register_timer(Timer::Gossip, Duration::from_millis(200));
+
+        let server = CounterServer {
+            counter: AntiEntropy::new(cluster_state),
+            slot: incarnation(cluster_state),
+        };
+
+        Ok(server)
+    }
+
+    fn on_message(
+        &mut self,
+        _: &ClusterState,
+        io: &mut IO<Payload<C>>,
+        input: Message<Payload<C>>,
+    ) -> Result<()> {
+        let payload = &input.body.payload;
+        match payload {
+            Payload::Add { delta } => {
+                self.counter
+                    .update(|counter| counter.increment(&self.slot, *delta));
+
+                let add_ok = Payload::AddOk {};
+                io.rpc_reply_to(&input, &add_ok)?;
+            }
+            Payload::AddOk => bail!("unexpected add_ok message"),
+            Payload::Read => {
+                let total = self.counter.state().value();
+                let read_ok = Payload::ReadOk { value: total };
+
+                io.rpc_reply_to(&input, &read_ok)?;
+            }
+            Payload::ReadOk { .. } => bail!("unexpected read_ok message"),
+            Payload::Gossip { delta } => {
+                let seq = self.counter.on_delta(delta);
+                io.rpc_reply_to(&input, &Payload::GossipOk { seq })?;
+            }
+            Payload::GossipOk { seq } => {
+                self.counter.on_ack(&input.src, *seq);
+            }
+        }
+        Ok(())
+    }
+
+    fn on_timer(&mut self, _: &ClusterState, io: &mut IO<Payload<C>>, timer: Timer) -> Result<()>
+    where
+        Self: Sized,
+    {
+        match timer {
+            Timer::Gossip => self.counter.gossip(io, |delta| Payload::Gossip { delta }),
+        }
+    }
+
+    fn on_rpc_timeout(
+        &mut self,
+        _: &ClusterState,
+        _: &mut IO<Payload<C>>,
+        timeout: crate::Request<Payload<C>>,
+    ) -> Result<()>
+    where
+        Self: Sized,
+    {
+        eprintln!("Timeout: {:?}", timeout);
+
+        Ok(())
+    }
+}
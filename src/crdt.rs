@@ -0,0 +1,632 @@
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A state-based CRDT. `merge` must be commutative, associative and
+/// idempotent, so replicas converge regardless of how states are exchanged.
+pub trait Crdt {
+    type Value;
+
+    fn merge(&mut self, other: &Self);
+
+    fn value(&self) -> Self::Value;
+}
+
+/// A CRDT counted up (or down) in per-node slots.
+pub trait Counter: Crdt {
+    /// Adds `delta` to `node`'s slot and returns the delta state.
+    fn increment(&mut self, node: &str, delta: Self::Value) -> Self;
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: HashMap<String, usize>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        GCounter::default()
+    }
+
+    /// Only `node` itself should ever increment its own slot. Returns the
+    /// delta state: just the updated slot.
+    pub fn increment(&mut self, node: &str, delta: usize) -> GCounter {
+        let count = self.counts.entry(node.to_string()).or_default();
+        *count += delta;
+
+        GCounter {
+            counts: HashMap::from([(node.to_string(), *count)]),
+        }
+    }
+
+    pub fn get(&self, node: &str) -> usize {
+        self.counts.get(node).copied().unwrap_or_default()
+    }
+}
+
+impl Crdt for GCounter {
+    type Value = usize;
+
+    fn merge(&mut self, other: &Self) {
+        for (node, &count) in &other.counts {
+            let current = self.counts.entry(node.clone()).or_default();
+            *current = cmp::max(*current, count);
+        }
+    }
+
+    fn value(&self) -> usize {
+        self.counts.values().sum()
+    }
+}
+
+impl Counter for GCounter {
+    fn increment(&mut self, node: &str, delta: usize) -> GCounter {
+        GCounter::increment(self, node, delta)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PNCounter {
+    pub fn new() -> Self {
+        PNCounter::default()
+    }
+
+    /// Returns the delta state: just the updated slot.
+    pub fn increment(&mut self, node: &str, delta: i64) -> PNCounter {
+        let mut result = PNCounter::new();
+        if delta >= 0 {
+            result.increments = self.increments.increment(node, delta as usize);
+        } else {
+            result.decrements = self
+                .decrements
+                .increment(node, delta.unsigned_abs() as usize);
+        }
+
+        result
+    }
+}
+
+impl Crdt for PNCounter {
+    type Value = i64;
+
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Counter for PNCounter {
+    fn increment(&mut self, node: &str, delta: i64) -> PNCounter {
+        PNCounter::increment(self, node, delta)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GSet<T>
+where
+    T: Eq + Hash,
+{
+    elements: HashSet<T>,
+}
+
+impl<T> GSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        GSet {
+            elements: HashSet::new(),
+        }
+    }
+
+    /// Returns whether the element was new.
+    pub fn insert(&mut self, element: T) -> bool {
+        self.elements.insert(element)
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.elements.contains(element)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.elements.iter()
+    }
+}
+
+impl<T> Default for GSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        GSet::new()
+    }
+}
+
+impl<T> Crdt for GSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    type Value = HashSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        self.elements.extend(other.elements.iter().cloned());
+    }
+
+    fn value(&self) -> HashSet<T> {
+        self.elements.clone()
+    }
+}
+
+/// A unique tag for a single add, issued by the node that performed it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub node: String,
+    pub counter: u64,
+}
+
+/// The set of dots a replica has seen: a contiguous prefix per node plus
+/// the dots that arrived out of order.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DotContext {
+    clock: HashMap<String, u64>,
+    cloud: HashSet<Dot>,
+}
+
+impl DotContext {
+    pub fn contains(&self, dot: &Dot) -> bool {
+        self.clock.get(&dot.node).is_some_and(|&c| dot.counter <= c) || self.cloud.contains(dot)
+    }
+
+    pub fn next_dot(&mut self, node: &str) -> Dot {
+        let counter = self.clock.entry(node.to_string()).or_default();
+        *counter += 1;
+        Dot {
+            node: node.to_string(),
+            counter: *counter,
+        }
+    }
+
+    pub fn insert(&mut self, dot: Dot) {
+        self.cloud.insert(dot);
+        self.compact();
+    }
+
+    pub fn merge(&mut self, other: &DotContext) {
+        for (node, &counter) in &other.clock {
+            let current = self.clock.entry(node.clone()).or_default();
+            *current = cmp::max(*current, counter);
+        }
+
+        self.cloud.extend(other.cloud.iter().cloned());
+        self.compact();
+    }
+
+    fn compact(&mut self) {
+        loop {
+            let mut changed = false;
+            self.cloud.retain(|dot| {
+                let counter = self.clock.entry(dot.node.clone()).or_default();
+                if dot.counter == *counter + 1 {
+                    *counter += 1;
+                    changed = true;
+                    false
+                } else {
+                    dot.counter > *counter
+                }
+            });
+
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+/// Observed-remove set: a concurrent add and remove of the same element
+/// resolves in favour of the add.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ORSet<T>
+where
+    T: Eq + Hash,
+{
+    entries: HashMap<T, HashSet<Dot>>,
+    context: DotContext,
+}
+
+impl<T> ORSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        ORSet {
+            entries: HashMap::new(),
+            context: DotContext::default(),
+        }
+    }
+
+    pub fn insert(&mut self, node: &str, element: T) {
+        let dot = self.context.next_dot(node);
+        self.entries.insert(element, HashSet::from([dot]));
+    }
+
+    /// Removes every add of `element` this replica has observed.
+    pub fn remove(&mut self, element: &T) -> bool {
+        self.entries.remove(element).is_some()
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.entries.contains_key(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.keys()
+    }
+}
+
+impl<T> Default for ORSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        ORSet::new()
+    }
+}
+
+impl<T> Crdt for ORSet<T>
+where
+    T: Eq + Hash + Clone,
+{
+    type Value = HashSet<T>;
+
+    fn merge(&mut self, other: &Self) {
+        let elements: HashSet<T> = self
+            .entries
+            .keys()
+            .chain(other.entries.keys())
+            .cloned()
+            .collect();
+
+        let empty = HashSet::new();
+        for element in elements {
+            let ours = self.entries.get(&element).unwrap_or(&empty);
+            let theirs = other.entries.get(&element).unwrap_or(&empty);
+
+            // A dot survives if both sides have it, or if the side lacking it
+            // has never seen it (and so can't have removed it).
+            let dots: HashSet<Dot> = ours
+                .intersection(theirs)
+                .chain(
+                    ours.difference(theirs)
+                        .filter(|d| !other.context.contains(d)),
+                )
+                .chain(
+                    theirs
+                        .difference(ours)
+                        .filter(|d| !self.context.contains(d)),
+                )
+                .cloned()
+                .collect();
+
+            if dots.is_empty() {
+                self.entries.remove(&element);
+            } else {
+                self.entries.insert(element, dots);
+            }
+        }
+
+        self.context.merge(&other.context);
+    }
+
+    fn value(&self) -> HashSet<T> {
+        self.entries.keys().cloned().collect()
+    }
+}
+
+/// Last-writer-wins register. Timestamps must be unique per write, e.g. a
+/// `LamportTimestamp` or an HLC timestamp paired with the node id.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<V, T> {
+    value: V,
+    timestamp: T,
+}
+
+impl<V, T> LwwRegister<V, T>
+where
+    V: Clone,
+    T: Ord + Clone,
+{
+    pub fn new(value: V, timestamp: T) -> Self {
+        LwwRegister { value, timestamp }
+    }
+
+    /// Returns whether the write took effect.
+    pub fn set(&mut self, value: V, timestamp: T) -> bool {
+        if timestamp > self.timestamp {
+            self.value = value;
+            self.timestamp = timestamp;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get(&self) -> &V {
+        &self.value
+    }
+
+    pub fn timestamp(&self) -> &T {
+        &self.timestamp
+    }
+}
+
+impl<V, T> Crdt for LwwRegister<V, T>
+where
+    V: Clone,
+    T: Ord + Clone,
+{
+    type Value = V;
+
+    fn merge(&mut self, other: &Self) {
+        self.set(other.value.clone(), other.timestamp.clone());
+    }
+
+    fn value(&self) -> V {
+        self.value.clone()
+    }
+}
+
+/// Map of last-writer-wins registers. Removes leave a timestamped tombstone
+/// so that an older write can't resurrect the key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwMap<K, V, T>
+where
+    K: Eq + Hash,
+{
+    entries: HashMap<K, LwwRegister<Option<V>, T>>,
+}
+
+impl<K, V, T> LwwMap<K, V, T>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    T: Ord + Clone,
+{
+    pub fn new() -> Self {
+        LwwMap {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V, timestamp: T) -> bool {
+        self.write(key, Some(value), timestamp)
+    }
+
+    pub fn remove(&mut self, key: K, timestamp: T) -> bool {
+        self.write(key, None, timestamp)
+    }
+
+    fn write(&mut self, key: K, value: Option<V>, timestamp: T) -> bool {
+        match self.entries.get_mut(&key) {
+            Some(register) => register.set(value, timestamp),
+            None => {
+                self.entries.insert(key, LwwRegister::new(value, timestamp));
+                true
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).and_then(|r| r.get().as_ref())
+    }
+}
+
+impl<K, V, T> Default for LwwMap<K, V, T>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    T: Ord + Clone,
+{
+    fn default() -> Self {
+        LwwMap::new()
+    }
+}
+
+impl<K, V, T> Crdt for LwwMap<K, V, T>
+where
+    K: Eq + Hash + Clone,
+    V: Clone,
+    T: Ord + Clone,
+{
+    type Value = HashMap<K, V>;
+
+    fn merge(&mut self, other: &Self) {
+        for (key, register) in &other.entries {
+            match self.entries.get_mut(key) {
+                Some(current) => current.merge(register),
+                None => {
+                    self.entries.insert(key.clone(), register.clone());
+                }
+            }
+        }
+    }
+
+    fn value(&self) -> HashMap<K, V> {
+        self.entries
+            .iter()
+            .filter_map(|(k, r)| r.get().as_ref().map(|v| (k.clone(), v.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use rand::{rngs::ThreadRng, Rng};
+
+    use super::*;
+
+    const NODES: [&str; 3] = ["n0", "n1", "n2"];
+
+    /// Unique per write: the random part makes writes race, the rest breaks
+    /// ties.
+    type Timestamp = (u8, String, usize);
+
+    fn merged<C: Crdt + Clone>(a: &C, b: &C) -> C {
+        let mut result = a.clone();
+        result.merge(b);
+        result
+    }
+
+    /// Replicas that each applied random updates with `update` and now and
+    /// then merged in another replica's state.
+    fn replicas<C, F>(rng: &mut ThreadRng, mut update: F) -> Vec<C>
+    where
+        C: Crdt + Clone + Default,
+        F: FnMut(&mut C, &str, &mut ThreadRng),
+    {
+        let mut replicas = vec![C::default(); NODES.len()];
+        for _ in 0..40 {
+            let i = rng.gen_range(0..NODES.len());
+            if rng.gen_bool(0.25) {
+                let other = replicas[rng.gen_range(0..NODES.len())].clone();
+                replicas[i].merge(&other);
+            } else {
+                update(&mut replicas[i], NODES[i], rng);
+            }
+        }
+
+        replicas
+    }
+
+    fn check_laws<C, F>(mut update: F)
+    where
+        C: Crdt + Clone + Default + PartialEq + Debug,
+        F: FnMut(&mut C, &str, &mut ThreadRng),
+    {
+        let mut rng = rand::thread_rng();
+        for _ in 0..200 {
+            let replicas = replicas(&mut rng, &mut update);
+            let (x, y, z) = (&replicas[0], &replicas[1], &replicas[2]);
+
+            assert_eq!(merged(x, y), merged(y, x), "commutativity");
+            assert_eq!(
+                merged(&merged(x, y), z),
+                merged(x, &merged(y, z)),
+                "associativity"
+            );
+            assert_eq!(&merged(x, x), x, "idempotence");
+        }
+    }
+
+    #[test]
+    fn g_counter_merges_are_a_semilattice() {
+        check_laws(|c: &mut GCounter, node, rng| {
+            c.increment(node, rng.gen_range(0..5));
+        });
+    }
+
+    #[test]
+    fn pn_counter_merges_are_a_semilattice() {
+        check_laws(|c: &mut PNCounter, node, rng| {
+            c.increment(node, rng.gen_range(-5..5));
+        });
+    }
+
+    #[test]
+    fn g_set_merges_are_a_semilattice() {
+        check_laws(|s: &mut GSet<u8>, _, rng| {
+            s.insert(rng.gen_range(0..20));
+        });
+    }
+
+    #[test]
+    fn or_set_merges_are_a_semilattice() {
+        check_laws(|s: &mut ORSet<u8>, node, rng| {
+            let element = rng.gen_range(0..5);
+            if rng.gen_bool(0.3) {
+                s.remove(&element);
+            } else {
+                s.insert(node, element);
+            }
+        });
+    }
+
+    #[test]
+    fn lww_register_merges_are_a_semilattice() {
+        let mut writes = 0;
+        check_laws(|r: &mut LwwRegister<u8, Timestamp>, node, rng| {
+            writes += 1;
+            r.set(rng.gen(), (rng.gen_range(0..4), node.to_string(), writes));
+        });
+    }
+
+    #[test]
+    fn lww_map_merges_are_a_semilattice() {
+        let mut writes = 0;
+        check_laws(|m: &mut LwwMap<u8, u8, Timestamp>, node, rng| {
+            writes += 1;
+            let timestamp = (rng.gen_range(0..4), node.to_string(), writes);
+            let key = rng.gen_range(0..5);
+            if rng.gen_bool(0.3) {
+                m.remove(key, timestamp);
+            } else {
+                m.insert(key, rng.gen(), timestamp);
+            }
+        });
+    }
+
+    #[test]
+    fn or_set_add_wins_over_a_concurrent_remove() {
+        let mut a = ORSet::new();
+        a.insert("n0", "x");
+        let mut b = a.clone();
+
+        a.remove(&"x");
+        b.insert("n1", "x");
+
+        assert!(merged(&a, &b).contains(&"x"));
+        assert!(merged(&b, &a).contains(&"x"));
+        assert_eq!(merged(&a, &b), merged(&b, &a));
+    }
+
+    #[test]
+    fn or_set_remove_wins_over_the_adds_it_observed() {
+        let mut a = ORSet::new();
+        a.insert("n0", "x");
+        let mut b = a.clone();
+        b.remove(&"x");
+
+        assert!(!merged(&a, &b).contains(&"x"));
+        assert!(!merged(&b, &a).contains(&"x"));
+    }
+
+    /// `LwwRegister` has no `Default`, the laws helper needs one.
+    impl Default for LwwRegister<u8, Timestamp> {
+        fn default() -> Self {
+            LwwRegister::new(0, (0, String::new(), 0))
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! Consumer groups: members join a group with the keys they want, and the
+//! group's coordinator splits those keys between them. Every join, leave or
+//! expired session starts a new generation with a new assignment, which
+//! members pick up from their next heartbeat. Offsets committed for a group
+//! are fenced by generation, so a member that hasn't caught up with a
+//! rebalance can't overwrite the progress of a key's new owner.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{KEY_DOES_NOT_EXIST, PRECONDITION_FAILED};
+
+/// Members that haven't sent a heartbeat for this many milliseconds are
+/// dropped from their group.
+pub const DEFAULT_SESSION_TIMEOUT: u64 = 3_000;
+
+/// Members of a group and the keys each of them subscribes to or is
+/// assigned.
+pub type Assignment = BTreeMap<String, BTreeSet<String>>;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Assignor {
+    /// Keys with the same subscribers are sorted and split into contiguous
+    /// runs, one per subscriber.
+    #[default]
+    Range,
+    /// Keys are dealt out in order, each to the next member subscribed to it.
+    RoundRobin,
+    /// Keys stay with their previous owner unless that leaves the members
+    /// unbalanced, so rebalances move as few of them as possible.
+    Sticky,
+}
+
+impl Assignor {
+    /// Splits the keys in `subscriptions` between its members. `previous` is
+    /// the assignment being replaced, which only `Sticky` looks at.
+    pub fn assign(&self, subscriptions: &Assignment, previous: &Assignment) -> Assignment {
+        let mut assignment: Assignment = subscriptions
+            .keys()
+            .map(|m| (m.clone(), BTreeSet::new()))
+            .collect();
+
+        let mut subscribers: BTreeMap<&String, Vec<&String>> = BTreeMap::new();
+        for (member, keys) in subscriptions {
+            for key in keys {
+                subscribers.entry(key).or_default().push(member);
+            }
+        }
+
+        match self {
+            Assignor::Range => {
+                let mut runs: BTreeMap<&Vec<&String>, Vec<&String>> = BTreeMap::new();
+                for (key, members) in &subscribers {
+                    runs.entry(members).or_default().push(key);
+                }
+
+                for (members, keys) in runs {
+                    let mut keys = keys.into_iter();
+                    let per_member = keys.len() / members.len();
+                    let extra = keys.len() % members.len();
+                    for (i, member) in members.iter().enumerate() {
+                        let count = per_member + usize::from(i < extra);
+                        let run = keys.by_ref().take(count).cloned();
+                        assignment.get_mut(*member).unwrap().extend(run);
+                    }
+                }
+            }
+            Assignor::RoundRobin => {
+                let members: Vec<&String> = subscriptions.keys().collect();
+                let mut next = 0;
+                for (key, subscribed) in &subscribers {
+                    while !subscribed.contains(&members[next % members.len()]) {
+                        next += 1;
+                    }
+                    let member = members[next % members.len()];
+                    assignment.get_mut(member).unwrap().insert((*key).clone());
+                    next += 1;
+                }
+            }
+            Assignor::Sticky => {
+                let mut owners: BTreeMap<&String, &String> = BTreeMap::new();
+                for (member, keys) in previous {
+                    let Some(subscribed) = subscriptions.get(member) else {
+                        continue;
+                    };
+                    for key in keys.intersection(subscribed) {
+                        owners.entry(key).or_insert(member);
+                    }
+                }
+
+                let mut load: HashMap<&String, usize> =
+                    subscriptions.keys().map(|m| (m, 0)).collect();
+                for member in owners.values() {
+                    *load.get_mut(member).unwrap() += 1;
+                }
+
+                for (key, members) in &subscribers {
+                    if !owners.contains_key(key) {
+                        let member = least_loaded(&load, members);
+                        owners.insert(key, member);
+                        *load.get_mut(member).unwrap() += 1;
+                    }
+                }
+
+                // Every move shrinks the spread between members, so this ends.
+                loop {
+                    let mut moved = false;
+                    for (key, members) in &subscribers {
+                        let owner = owners[key];
+                        let target = least_loaded(&load, members);
+                        if load[owner] > load[target] + 1 {
+                            *load.get_mut(owner).unwrap() -= 1;
+                            *load.get_mut(target).unwrap() += 1;
+                            owners.insert(key, target);
+                            moved = true;
+                        }
+                    }
+
+                    if !moved {
+                        break;
+                    }
+                }
+
+                for (key, member) in owners {
+                    assignment.get_mut(member).unwrap().insert(key.clone());
+                }
+            }
+        }
+
+        assignment
+    }
+}
+
+fn least_loaded<'a>(load: &HashMap<&String, usize>, members: &[&'a String]) -> &'a String {
+    members
+        .iter()
+        .min_by_key(|m| (load[*m], **m))
+        .expect("keys have subscribers")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GroupError {
+    /// The member was never in the group, or was dropped from it. It has to
+    /// join again.
+    UnknownMember,
+    /// The group has moved on to a newer generation since.
+    StaleGeneration,
+    /// The key is assigned to somebody else.
+    NotAssigned,
+}
+
+impl GroupError {
+    /// The Maelstrom error code to reply with.
+    pub fn code(&self) -> u64 {
+        match self {
+            GroupError::UnknownMember => KEY_DOES_NOT_EXIST,
+            GroupError::StaleGeneration | GroupError::NotAssigned => PRECONDITION_FAILED,
+        }
+    }
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::UnknownMember => write!(f, "unknown member"),
+            GroupError::StaleGeneration => write!(f, "stale generation"),
+            GroupError::NotAssigned => write!(f, "key not assigned to member"),
+        }
+    }
+}
+
+impl std::error::Error for GroupError {}
+
+#[derive(Clone, Debug)]
+struct Group {
+    assignor: Assignor,
+    generation: u64,
+    subscriptions: Assignment,
+    assignment: Assignment,
+    /// When each member was last heard from, in milliseconds.
+    last_heard: HashMap<String, u64>,
+}
+
+impl Group {
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        self.assignment = self.assignor.assign(&self.subscriptions, &self.assignment);
+    }
+
+    fn remove(&mut self, member: &str) -> bool {
+        self.last_heard.remove(member);
+        self.subscriptions.remove(member).is_some()
+    }
+}
+
+/// The groups a node coordinates. Their state is only kept in memory, so if
+/// the coordinator restarts, members find out from their next heartbeat and
+/// join again.
+#[derive(Clone, Debug)]
+pub struct Coordinator {
+    incarnation: String,
+    session_timeout: u64,
+    groups: HashMap<String, Group>,
+    /// For making up member ids.
+    joined: u64,
+}
+
+impl Coordinator {
+    /// `incarnation` names this run of the node, as from
+    /// `anti_entropy::incarnation`, so that the member ids made up after a
+    /// restart differ from the ones handed out before it.
+    pub fn new(incarnation: &str, session_timeout: u64) -> Self {
+        Coordinator {
+            incarnation: incarnation.to_string(),
+            session_timeout,
+            groups: HashMap::new(),
+            joined: 0,
+        }
+    }
+
+    /// Adds a member to `group`, creating it with `assignor` if needed, or
+    /// updates the keys of one that's already in it. Members joining for the
+    /// first time get an id made up for them. Returns the member id, the
+    /// generation and what the member is assigned in it.
+    pub fn join(
+        &mut self,
+        group: &str,
+        member: Option<&str>,
+        keys: BTreeSet<String>,
+        assignor: Assignor,
+        now: u64,
+    ) -> (String, u64, BTreeSet<String>) {
+        let member = match member {
+            Some(member) => member.to_string(),
+            None => {
+                self.joined += 1;
+                format!("{}-{}", self.incarnation, self.joined)
+            }
+        };
+
+        let group = self
+            .groups
+            .entry(group.to_string())
+            .or_insert_with(|| Group {
+                assignor,
+                generation: 0,
+                subscriptions: Assignment::new(),
+                assignment: Assignment::new(),
+                last_heard: HashMap::new(),
+            });
+
+        group.last_heard.insert(member.clone(), now);
+        if group.subscriptions.get(&member) != Some(&keys) {
+            group.subscriptions.insert(member.clone(), keys);
+            group.rebalance();
+        }
+
+        let assigned = group.assignment[&member].clone();
+        (member, group.generation, assigned)
+    }
+
+    /// Keeps `member`'s session alive, and tells it the current generation
+    /// and its assignment in it.
+    pub fn heartbeat(
+        &mut self,
+        group: &str,
+        member: &str,
+        now: u64,
+    ) -> Result<(u64, BTreeSet<String>), GroupError> {
+        let group = self
+            .groups
+            .get_mut(group)
+            .ok_or(GroupError::UnknownMember)?;
+        let heard = group
+            .last_heard
+            .get_mut(member)
+            .ok_or(GroupError::UnknownMember)?;
+        *heard = now;
+
+        Ok((group.generation, group.assignment[member].clone()))
+    }
+
+    pub fn leave(&mut self, group: &str, member: &str) -> Result<(), GroupError> {
+        let group = self
+            .groups
+            .get_mut(group)
+            .ok_or(GroupError::UnknownMember)?;
+        if !group.remove(member) {
+            return Err(GroupError::UnknownMember);
+        }
+
+        group.rebalance();
+        Ok(())
+    }
+
+    /// Drops the members whose sessions ran out, returning them with their
+    /// groups.
+    pub fn expire(&mut self, now: u64) -> Vec<(String, String)> {
+        let mut expired = Vec::new();
+        for (name, group) in self.groups.iter_mut() {
+            let members: Vec<String> = group
+                .last_heard
+                .iter()
+                .filter(|(_, &heard)| now.saturating_sub(heard) > self.session_timeout)
+                .map(|(m, _)| m.clone())
+                .collect();
+
+            if members.is_empty() {
+                continue;
+            }
+            for member in members {
+                group.remove(&member);
+                expired.push((name.clone(), member));
+            }
+            group.rebalance();
+        }
+
+        expired
+    }
+
+    /// Whether `member` may commit offsets for `keys` in `generation`. While
+    /// the group has members, only the current owner of a key may; once it's
+    /// empty, anyone may, e.g. to reset its offsets.
+    pub fn check_commit<'a>(
+        &self,
+        group: &str,
+        member: Option<&str>,
+        generation: Option<u64>,
+        mut keys: impl Iterator<Item = &'a String>,
+    ) -> Result<(), GroupError> {
+        let Some(group) = self
+            .groups
+            .get(group)
+            .filter(|g| !g.subscriptions.is_empty())
+        else {
+            return Ok(());
+        };
+
+        let assigned = member
+            .and_then(|m| group.assignment.get(m))
+            .ok_or(GroupError::UnknownMember)?;
+        if generation != Some(group.generation) {
+            return Err(GroupError::StaleGeneration);
+        }
+        if !keys.all(|k| assigned.contains(k)) {
+            return Err(GroupError::NotAssigned);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(keys: &[&str]) -> BTreeSet<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    fn assignment(members: &[(&str, &[&str])]) -> Assignment {
+        members
+            .iter()
+            .map(|(m, k)| (m.to_string(), keys(k)))
+            .collect()
+    }
+
+    /// Every subscribed key goes to exactly one of its subscribers.
+    fn check_complete(subscriptions: &Assignment, assignment: &Assignment) {
+        let mut assigned = BTreeSet::new();
+        for (member, keys) in assignment {
+            assert!(keys.is_subset(&subscriptions[member]), "{:?}", assignment);
+            for key in keys {
+                assert!(assigned.insert(key.clone()), "{} assigned twice", key);
+            }
+        }
+        let subscribed: BTreeSet<String> = subscriptions.values().flatten().cloned().collect();
+        assert_eq!(assigned, subscribed);
+    }
+
+    #[test]
+    fn range_splits_keys_with_the_same_subscribers_into_runs() {
+        let all = ["k1", "k2", "k3", "k4", "k5"];
+        let subscriptions = assignment(&[("a", &all), ("b", &all)]);
+        let assigned = Assignor::Range.assign(&subscriptions, &Assignment::new());
+        check_complete(&subscriptions, &assigned);
+        assert_eq!(
+            assigned,
+            assignment(&[("a", &["k1", "k2", "k3"]), ("b", &["k4", "k5"])])
+        );
+
+        let subscriptions = assignment(&[("a", &["x", "y"]), ("b", &["y", "z"])]);
+        let assigned = Assignor::Range.assign(&subscriptions, &Assignment::new());
+        check_complete(&subscriptions, &assigned);
+        assert_eq!(assigned, assignment(&[("a", &["x", "y"]), ("b", &["z"])]));
+    }
+
+    #[test]
+    fn round_robin_deals_keys_out_to_subscribed_members() {
+        let all = ["k1", "k2", "k3", "k4", "k5"];
+        let subscriptions = assignment(&[("a", &all), ("b", &all), ("c", &all)]);
+        let assigned = Assignor::RoundRobin.assign(&subscriptions, &Assignment::new());
+        check_complete(&subscriptions, &assigned);
+        assert_eq!(
+            assigned,
+            assignment(&[("a", &["k1", "k4"]), ("b", &["k2", "k5"]), ("c", &["k3"])])
+        );
+
+        // Members are skipped for the keys they don't want.
+        let subscriptions = assignment(&[("a", &["k1", "k2", "k3"]), ("b", &["k2"])]);
+        let assigned = Assignor::RoundRobin.assign(&subscriptions, &Assignment::new());
+        check_complete(&subscriptions, &assigned);
+        assert_eq!(
+            assigned,
+            assignment(&[("a", &["k1", "k3"]), ("b", &["k2"])])
+        );
+    }
+
+    #[test]
+    fn sticky_only_moves_keys_to_rebalance() {
+        let all = ["k1", "k2", "k3", "k4", "k5", "k6"];
+        let previous = assignment(&[("a", &["k1", "k2", "k3"]), ("b", &["k4", "k5", "k6"])]);
+
+        // Nothing changed, so nothing moves.
+        let subscriptions = assignment(&[("a", &all), ("b", &all)]);
+        assert_eq!(Assignor::Sticky.assign(&subscriptions, &previous), previous);
+
+        // A new member takes one key from each, and the rest stay put.
+        let subscriptions = assignment(&[("a", &all), ("b", &all), ("c", &all)]);
+        let assigned = Assignor::Sticky.assign(&subscriptions, &previous);
+        check_complete(&subscriptions, &assigned);
+        assert!(
+            assigned.values().all(|keys| keys.len() == 2),
+            "{:?}",
+            assigned
+        );
+        assert!(assigned["a"].is_subset(&previous["a"]));
+        assert!(assigned["b"].is_subset(&previous["b"]));
+
+        // Keys of a member that left go to whoever has the fewest.
+        let subscriptions = assignment(&[("b", &all), ("c", &all)]);
+        let after = Assignor::Sticky.assign(&subscriptions, &assigned);
+        check_complete(&subscriptions, &after);
+        assert_eq!(after["b"].len(), 3);
+        assert!(after["b"].is_superset(&assigned["b"]));
+        assert!(after["c"].is_superset(&assigned["c"]));
+    }
+
+    #[test]
+    fn commits_are_fenced_by_generation_and_assignment() {
+        let mut coordinator = Coordinator::new("n0", DEFAULT_SESSION_TIMEOUT);
+        let all = keys(&["k1", "k2"]);
+        let (a, first, _) = coordinator.join("g", None, all.clone(), Assignor::Range, 0);
+        let (b, second, b_keys) = coordinator.join("g", None, all.clone(), Assignor::Range, 0);
+        assert_ne!(a, b);
+        assert_eq!(second, first + 1);
+
+        let (generation, a_keys) = coordinator.heartbeat("g", &a, 0).unwrap();
+        assert_eq!(generation, second);
+        let commit = |member: &str, generation, keys: &BTreeSet<String>| {
+            coordinator.check_commit("g", Some(member), Some(generation), keys.iter())
+        };
+
+        assert_eq!(commit(&a, second, &a_keys), Ok(()));
+        assert_eq!(commit(&a, first, &a_keys), Err(GroupError::StaleGeneration));
+        assert_eq!(commit(&a, second, &b_keys), Err(GroupError::NotAssigned));
+        assert_eq!(
+            commit("nobody", second, &a_keys),
+            Err(GroupError::UnknownMember)
+        );
+
+        // Once everybody has left, anyone may commit.
+        coordinator.leave("g", &a).unwrap();
+        coordinator.leave("g", &b).unwrap();
+        assert_eq!(coordinator.leave("g", &b), Err(GroupError::UnknownMember));
+        assert_eq!(
+            coordinator.check_commit("g", None, None, all.iter()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn member_ids_differ_across_restarts() {
+        let all = keys(&["k1"]);
+        let mut before = Coordinator::new("n0@1000", DEFAULT_SESSION_TIMEOUT);
+        let mut after = Coordinator::new("n0@2000", DEFAULT_SESSION_TIMEOUT);
+
+        let (a, _, _) = before.join("g", None, all.clone(), Assignor::Range, 0);
+        let (b, _, _) = after.join("g", None, all.clone(), Assignor::Range, 0);
+        assert_ne!(a, b);
+
+        // A member from before the restart rejoining under its old id doesn't
+        // get mixed up with new ones.
+        after.join("g", Some(&a), all.clone(), Assignor::Range, 0);
+        let (c, _, _) = after.join("g", None, all, Assignor::Range, 0);
+        assert!(c != a && c != b);
+    }
+
+    #[test]
+    fn members_without_heartbeats_expire_and_their_keys_move() {
+        let mut coordinator = Coordinator::new("n0", 100);
+        let all = keys(&["k1", "k2"]);
+        let (a, _, _) = coordinator.join("g", None, all.clone(), Assignor::Range, 0);
+        let (b, generation, _) = coordinator.join("g", None, all.clone(), Assignor::Range, 0);
+
+        coordinator.heartbeat("g", &a, 80).unwrap();
+        assert!(coordinator.expire(100).is_empty());
+        assert_eq!(coordinator.expire(150), vec![("g".to_string(), b.clone())]);
+
+        assert_eq!(
+            coordinator.heartbeat("g", &a, 150),
+            Ok((generation + 1, all))
+        );
+        assert_eq!(
+            coordinator.heartbeat("g", &b, 150),
+            Err(GroupError::UnknownMember)
+        );
+        assert!(coordinator.expire(250).is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+//! Stable hashing for anything that has to agree across nodes. `std`'s
+//! `DefaultHasher` is randomly keyed per process, so it can't be used for
+//! data that gets compared on another node.
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_with_seed(0, bytes)
+}
+
+/// FNV-1a with the seed mixed in first, for when several independent hash
+/// functions are needed.
+pub fn fnv1a_with_seed(seed: u64, bytes: &[u8]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for byte in seed.to_le_bytes().iter().chain(bytes) {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+/// SplitMix64 finalizer. FNV on short, similar inputs like `n1#0` and `n1#1`
+/// leaves related hashes; this spreads them over the whole range.
+pub fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
@@ -0,0 +1,496 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::{ClusterState, IO};
+
+/// Hops a join is forwarded before the joining node is forced into an active
+/// view, and the hop at which it is added to passive views along the way.
+const ACTIVE_RANDOM_WALK: usize = 4;
+const PASSIVE_RANDOM_WALK: usize = 2;
+
+/// Active and passive nodes included in each shuffle.
+const SHUFFLE_ACTIVE: usize = 3;
+const SHUFFLE_PASSIVE: usize = 4;
+const SHUFFLE_EVERY: u64 = 10;
+
+/// Active peers we haven't heard from for this long are considered failed.
+const FAILURE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum Membership {
+    Join,
+    ForwardJoin {
+        node: String,
+        ttl: usize,
+    },
+    Disconnect,
+    Neighbor {
+        high_priority: bool,
+    },
+    NeighborReply {
+        accepted: bool,
+    },
+    Shuffle {
+        origin: String,
+        nodes: Vec<String>,
+        ttl: usize,
+    },
+    ShuffleReply {
+        nodes: Vec<String>,
+    },
+    Ping,
+    Pong,
+}
+
+/// HyParView partial-view membership. The small, symmetric active view is
+/// what protocols gossip over; the larger passive view is a pool of
+/// replacements for active peers that fail.
+pub struct HyParView {
+    node_id: String,
+    contact: Option<String>,
+    active: Vec<String>,
+    passive: Vec<String>,
+    active_size: usize,
+    passive_size: usize,
+    last_heard: HashMap<String, Instant>,
+    outbox: Vec<(String, Membership)>,
+    ticks: u64,
+}
+
+impl HyParView {
+    /// Uses the first other node in `node_ids` as the contact node to join
+    /// through, and sizes the views from the cluster size.
+    pub fn new(cluster_state: &ClusterState) -> Self {
+        let n = cluster_state.node_ids.len().max(1);
+        let active_size = ((n as f64).ln().ceil() as usize + 1).max(2);
+
+        let contact = cluster_state
+            .node_ids
+            .first()
+            .filter(|&c| c != &cluster_state.node_id)
+            .cloned();
+
+        HyParView {
+            node_id: cluster_state.node_id.clone(),
+            contact,
+            active: Vec::new(),
+            passive: Vec::new(),
+            active_size,
+            passive_size: active_size * 6,
+            last_heard: HashMap::new(),
+            outbox: Vec::new(),
+            ticks: 0,
+        }
+    }
+
+    pub fn active_view(&self) -> &[String] {
+        &self.active
+    }
+
+    pub fn passive_view(&self) -> &[String] {
+        &self.passive
+    }
+
+    pub fn join(&mut self) {
+        if let Some(contact) = self.contact.clone() {
+            self.outbox.push((contact, Membership::Join));
+        }
+    }
+
+    /// Takes the messages that need to go out.
+    pub fn drain(&mut self) -> Vec<(String, Membership)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn flush<P>(&mut self, io: &mut IO<P>, to_payload: impl Fn(Membership) -> P) -> Result<()>
+    where
+        P: serde::Serialize + Clone,
+    {
+        for (dst, membership) in self.drain() {
+            io.fire_and_forget(&dst, &to_payload(membership))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn on_message(&mut self, now: Instant, from: &str, message: &Membership) {
+        if self.active.iter().any(|p| p == from) {
+            self.last_heard.insert(from.to_string(), now);
+        }
+
+        match message {
+            Membership::Join => {
+                self.add_active(from, now);
+                let neighbor = Membership::Neighbor {
+                    high_priority: true,
+                };
+                self.outbox.push((from.to_string(), neighbor));
+
+                for peer in self.active.iter().filter(|&p| p != from) {
+                    let forward = Membership::ForwardJoin {
+                        node: from.to_string(),
+                        ttl: ACTIVE_RANDOM_WALK,
+                    };
+                    self.outbox.push((peer.clone(), forward));
+                }
+            }
+            Membership::ForwardJoin { node, ttl } => {
+                if *ttl == 0 || self.active.len() <= 1 {
+                    if !self.active.contains(node) && node != &self.node_id {
+                        self.add_active(node, now);
+                        let neighbor = Membership::Neighbor {
+                            high_priority: true,
+                        };
+                        self.outbox.push((node.clone(), neighbor));
+                    }
+                    return;
+                }
+
+                if *ttl == PASSIVE_RANDOM_WALK {
+                    self.add_passive(node);
+                }
+
+                if let Some(next) = self.random_active_except(&[from, node]) {
+                    let forward = Membership::ForwardJoin {
+                        node: node.clone(),
+                        ttl: ttl - 1,
+                    };
+                    self.outbox.push((next, forward));
+                }
+            }
+            Membership::Disconnect => {
+                if self.remove_active(from) {
+                    self.add_passive(from);
+                }
+            }
+            Membership::Neighbor { high_priority } => {
+                let accepted = *high_priority || self.active.len() < self.active_size;
+                if accepted {
+                    self.add_active(from, now);
+                }
+                let reply = Membership::NeighborReply { accepted };
+                self.outbox.push((from.to_string(), reply));
+            }
+            Membership::NeighborReply { accepted } => {
+                if *accepted {
+                    self.add_active(from, now);
+                }
+            }
+            Membership::Shuffle { origin, nodes, ttl } => {
+                if *ttl > 1 && self.active.len() > 1 {
+                    if let Some(next) = self.random_active_except(&[from, origin]) {
+                        let forward = Membership::Shuffle {
+                            origin: origin.clone(),
+                            nodes: nodes.clone(),
+                            ttl: ttl - 1,
+                        };
+                        self.outbox.push((next, forward));
+                        return;
+                    }
+                }
+
+                let reply = Membership::ShuffleReply {
+                    nodes: self.sample(&self.passive, nodes.len()),
+                };
+                self.outbox.push((origin.clone(), reply));
+                for node in nodes {
+                    self.add_passive(node);
+                }
+            }
+            Membership::ShuffleReply { nodes } => {
+                for node in nodes {
+                    self.add_passive(node);
+                }
+            }
+            Membership::Ping => {
+                // A peer that still has us active after we dropped it, e.g.
+                // because our disconnect got lost, is told again.
+                let reply = if self.active.iter().any(|p| p == from) {
+                    Membership::Pong
+                } else {
+                    Membership::Disconnect
+                };
+                self.outbox.push((from.to_string(), reply));
+            }
+            Membership::Pong => (),
+        }
+    }
+
+    /// Drops an active peer that the caller found unreachable; its
+    /// replacement is picked from the passive view on the next tick.
+    pub fn on_peer_failed(&mut self, peer: &str) {
+        self.remove_active(peer);
+    }
+
+    /// Failure detection, view repair and the periodic shuffle. Meant to be
+    /// called from a `Timers` tick.
+    pub fn tick(&mut self, now: Instant) {
+        self.ticks += 1;
+
+        let failed: Vec<String> = self
+            .active
+            .iter()
+            .filter(|p| {
+                self.last_heard
+                    .get(*p)
+                    .is_some_and(|&t| now.saturating_duration_since(t) >= FAILURE_TIMEOUT)
+            })
+            .cloned()
+            .collect();
+
+        for peer in failed {
+            self.remove_active(&peer);
+            self.add_passive(&peer);
+        }
+
+        for peer in &self.active {
+            self.outbox.push((peer.clone(), Membership::Ping));
+        }
+
+        if self.active.len() < self.active_size {
+            let candidates: Vec<&String> = self
+                .passive
+                .iter()
+                .filter(|p| !self.outbox.iter().any(|(dst, _)| dst == *p))
+                .collect();
+
+            match candidates.choose(&mut rand::thread_rng()) {
+                Some(&candidate) => {
+                    let neighbor = Membership::Neighbor {
+                        high_priority: self.active.is_empty(),
+                    };
+                    self.outbox.push((candidate.clone(), neighbor));
+                }
+                None if self.active.is_empty() => self.join(),
+                None => (),
+            }
+        }
+
+        if self.ticks.is_multiple_of(SHUFFLE_EVERY) {
+            self.shuffle();
+        }
+    }
+
+    fn shuffle(&mut self) {
+        let Some(peer) = self.random_active_except(&[]) else {
+            return;
+        };
+
+        let mut nodes = vec![self.node_id.clone()];
+        nodes.extend(self.sample(&self.active, SHUFFLE_ACTIVE));
+        nodes.extend(self.sample(&self.passive, SHUFFLE_PASSIVE));
+
+        let shuffle = Membership::Shuffle {
+            origin: self.node_id.clone(),
+            nodes,
+            ttl: ACTIVE_RANDOM_WALK,
+        };
+        self.outbox.push((peer, shuffle));
+    }
+
+    /// New active peers get a full failure timeout before they have to be
+    /// heard from.
+    fn add_active(&mut self, peer: &str, now: Instant) {
+        if peer == self.node_id || self.active.iter().any(|p| p == peer) {
+            return;
+        }
+
+        if self.active.len() >= self.active_size {
+            let i = rand::thread_rng().gen_range(0..self.active.len());
+            let dropped = self.active.swap_remove(i);
+            self.outbox.push((dropped.clone(), Membership::Disconnect));
+            self.add_passive(&dropped);
+        }
+
+        self.passive.retain(|p| p != peer);
+        self.active.push(peer.to_string());
+        self.last_heard.insert(peer.to_string(), now);
+    }
+
+    fn remove_active(&mut self, peer: &str) -> bool {
+        self.last_heard.remove(peer);
+        let before = self.active.len();
+        self.active.retain(|p| p != peer);
+        before != self.active.len()
+    }
+
+    fn add_passive(&mut self, peer: &str) {
+        if peer == self.node_id
+            || self.active.iter().any(|p| p == peer)
+            || self.passive.iter().any(|p| p == peer)
+        {
+            return;
+        }
+
+        if self.passive.len() >= self.passive_size {
+            let i = rand::thread_rng().gen_range(0..self.passive.len());
+            self.passive.swap_remove(i);
+        }
+
+        self.passive.push(peer.to_string());
+    }
+
+    fn random_active_except(&self, except: &[&str]) -> Option<String> {
+        let candidates: Vec<&String> = self
+            .active
+            .iter()
+            .filter(|p| !except.contains(&p.as_str()))
+            .collect();
+
+        candidates
+            .choose(&mut rand::thread_rng())
+            .map(|p| p.to_string())
+    }
+
+    fn sample(&self, view: &[String], n: usize) -> Vec<String> {
+        view.choose_multiple(&mut rand::thread_rng(), n)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::time::SystemClock;
+
+    use super::*;
+
+    fn view(node_id: &str) -> HyParView {
+        cluster_view(node_id, 5)
+    }
+
+    fn cluster_view(node_id: &str, n: usize) -> HyParView {
+        HyParView::new(&ClusterState {
+            node_id: node_id.to_string(),
+            node_ids: (0..n).map(|i| format!("n{}", i)).collect(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Delivers messages between the views until none are left.
+    fn settle(views: &mut HashMap<String, HyParView>, now: Instant) {
+        loop {
+            let sent: Vec<(String, String, Membership)> = views
+                .iter_mut()
+                .flat_map(|(src, view)| {
+                    view.drain()
+                        .into_iter()
+                        .map(move |(dst, m)| (src.clone(), dst, m))
+                })
+                .collect();
+            if sent.is_empty() {
+                return;
+            }
+
+            for (src, dst, m) in sent {
+                views.get_mut(&dst).unwrap().on_message(now, &src, &m);
+            }
+        }
+    }
+
+    #[test]
+    fn peers_promoted_from_the_passive_view_get_a_fresh_timeout() {
+        let start = Instant::now();
+        let mut view = view("n1");
+        view.on_message(start, "n2", &Membership::ShuffleReply { nodes: vec![] });
+        assert!(view.active_view().is_empty());
+
+        let later = start + FAILURE_TIMEOUT * 2;
+        let join = Membership::ForwardJoin {
+            node: "n2".to_string(),
+            ttl: 0,
+        };
+        view.on_message(later, "n0", &join);
+        view.tick(later + FAILURE_TIMEOUT / 2);
+        assert_eq!(view.active_view(), ["n2"]);
+
+        view.tick(later + FAILURE_TIMEOUT);
+        assert!(view.active_view().is_empty());
+        assert_eq!(view.passive_view(), ["n2"]);
+    }
+
+    #[test]
+    fn active_views_end_up_symmetric() {
+        let mut now = Instant::now();
+        let mut views: HashMap<String, HyParView> = (0..12)
+            .map(|i| (format!("n{}", i), cluster_view(&format!("n{}", i), 12)))
+            .collect();
+        for view in views.values_mut() {
+            view.join();
+        }
+
+        for _ in 0..3 * SHUFFLE_EVERY {
+            settle(&mut views, now);
+            now += Duration::from_millis(100);
+            for view in views.values_mut() {
+                view.tick(now);
+            }
+        }
+        settle(&mut views, now);
+
+        for (node, view) in &views {
+            assert!(!view.active_view().is_empty());
+            for peer in view.active_view() {
+                assert!(views[peer].active_view().contains(node));
+            }
+        }
+    }
+
+    #[test]
+    fn pings_from_peers_we_dropped_get_a_disconnect() {
+        let now = Instant::now();
+        let mut view = view("n1");
+        view.on_message(now, "n2", &Membership::Join);
+        view.drain();
+
+        view.on_message(now, "n2", &Membership::Ping);
+        view.on_message(now, "n3", &Membership::Ping);
+        let replies = view.drain();
+        assert!(matches!(&replies[..], [
+            (a, Membership::Pong),
+            (b, Membership::Disconnect),
+        ] if a == "n2" && b == "n3"));
+    }
+
+    #[test]
+    fn failed_active_peers_are_replaced_from_the_passive_view() {
+        let now = Instant::now();
+        let mut view = view("n1");
+        view.on_message(now, "n2", &Membership::Join);
+        let shuffle = Membership::ShuffleReply {
+            nodes: vec!["n3".to_string()],
+        };
+        view.on_message(now, "n2", &shuffle);
+        view.drain();
+
+        view.on_peer_failed("n2");
+        assert!(view.active_view().is_empty());
+        view.tick(now);
+        let neighbor = view
+            .drain()
+            .into_iter()
+            .find(|(dst, _)| dst == "n3")
+            .map(|(_, m)| m);
+        assert!(matches!(
+            neighbor,
+            Some(Membership::Neighbor {
+                high_priority: true
+            })
+        ));
+
+        let reply = Membership::NeighborReply { accepted: true };
+        view.on_message(now, "n3", &reply);
+        assert_eq!(view.active_view(), ["n3"]);
+        assert!(view.passive_view().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hash::fnv1a_with_seed;
+
+/// Number of cells each key is added to.
+const HASHES: usize = 3;
+const CHECKSUM_SEED: u64 = HASHES as u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Cell(i64, u64, u32);
+
+impl Cell {
+    fn toggle(&mut self, key: u64, count: i64) {
+        self.0 += count;
+        self.1 ^= key;
+        self.2 ^= checksum(key);
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Cell::default()
+    }
+
+    /// A cell holding exactly one key, which can be peeled off.
+    fn pure(&self) -> Option<(u64, i64)> {
+        match self.0 {
+            1 | -1 if checksum(self.1) == self.2 => Some((self.1, self.0)),
+            _ => None,
+        }
+    }
+}
+
+fn checksum(key: u64) -> u32 {
+    (fnv1a_with_seed(CHECKSUM_SEED, &key.to_le_bytes()) >> 32) as u32
+}
+
+/// Invertible Bloom lookup table. Subtracting two peers' tables cancels out
+/// every key they share, so the difference can be decoded from a table sized
+/// for the difference rather than for the sets themselves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Iblt {
+    cells: Vec<Cell>,
+}
+
+/// What decoding `ours - theirs` found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Difference {
+    pub ours: Vec<u64>,
+    pub theirs: Vec<u64>,
+}
+
+impl Iblt {
+    /// Rounded up so that every hash gets an equally sized slice of the table.
+    pub fn new(cells: usize) -> Self {
+        let cells = cells.max(HASHES).div_ceil(HASHES) * HASHES;
+        Iblt {
+            cells: vec![Cell::default(); cells],
+        }
+    }
+
+    pub fn from_keys(cells: usize, keys: impl IntoIterator<Item = u64>) -> Self {
+        let mut iblt = Iblt::new(cells);
+        for key in keys {
+            iblt.insert(key);
+        }
+
+        iblt
+    }
+
+    pub fn cells(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn insert(&mut self, key: u64) {
+        self.update(key, 1);
+    }
+
+    pub fn remove(&mut self, key: u64) {
+        self.update(key, -1);
+    }
+
+    fn update(&mut self, key: u64, count: i64) {
+        let slice = self.cells.len() / HASHES;
+        for i in 0..HASHES {
+            let hash = fnv1a_with_seed(i as u64, &key.to_le_bytes());
+            // FNV's low bits only depend on the low bits of the input, so
+            // index with the high ones.
+            let cell = i * slice + ((hash as u128 * slice as u128) >> 64) as usize;
+            self.cells[cell].toggle(key, count);
+        }
+    }
+
+    /// Cell-wise `self - other`. Both tables must have the same size.
+    pub fn subtract(&self, other: &Iblt) -> Option<Iblt> {
+        if self.cells.len() != other.cells.len() {
+            return None;
+        }
+
+        let cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(a, b)| Cell(a.0 - b.0, a.1 ^ b.1, a.2 ^ b.2))
+            .collect();
+
+        Some(Iblt { cells })
+    }
+
+    /// Peels pure cells until nothing is left. Returns `None` if the table was
+    /// too small for the difference it holds, or is corrupt: a cell can look
+    /// pure by accident, and peeling it may then go on forever.
+    pub fn decode(mut self) -> Option<Difference> {
+        let mut difference = Difference::default();
+        let mut peels = 0;
+        while let Some((key, count)) = self.cells.iter().find_map(Cell::pure) {
+            peels += 1;
+            if peels > self.cells.len() * HASHES {
+                return None;
+            }
+
+            if count > 0 {
+                difference.ours.push(key);
+            } else {
+                difference.theirs.push(key);
+            }
+            self.update(key, -count);
+        }
+
+        if self.cells.iter().all(Cell::is_empty) {
+            difference.ours.sort_unstable();
+            difference.theirs.sort_unstable();
+            Some(difference)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_the_difference_between_two_sets() {
+        let ours = Iblt::from_keys(30, (0..100).chain([1000, 1001]));
+        let theirs = Iblt::from_keys(30, (0..100).chain([2000]));
+
+        let difference = ours.subtract(&theirs).unwrap().decode().unwrap();
+        assert_eq!(difference.ours, [1000, 1001]);
+        assert_eq!(difference.theirs, [2000]);
+    }
+
+    #[test]
+    fn fails_to_decode_a_difference_too_big_for_the_table() {
+        let ours = Iblt::from_keys(6, 0..100);
+        let theirs = Iblt::new(6);
+
+        assert_eq!(ours.subtract(&theirs).unwrap().decode(), None);
+    }
+
+    #[test]
+    fn gives_up_on_a_corrupt_table_that_never_runs_out_of_pure_cells() {
+        let mut iblt = Iblt::new(30);
+
+        // A pure-looking cell for a key that doesn't hash to it: peeling the
+        // key never empties it.
+        let probe = |key: u64| {
+            let mut iblt = Iblt::new(30);
+            iblt.insert(key);
+            iblt.cells[0].is_empty()
+        };
+        let key = (0..).find(|&k| probe(k)).unwrap();
+        iblt.cells[0].toggle(key, 1);
+
+        assert_eq!(iblt.decode(), None);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    env, fmt,
+    ops::Range,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{time::Clock, ClusterState};
+
+/// Environment variable `unique_ids` reads its output format from, e.g.
+/// `ID_FORMAT=ulid`.
+pub const ID_FORMAT_ENV: &str = "ID_FORMAT";
+
+/// Snowflake layout, from the top: one unused sign bit, then milliseconds since
+/// `SNOWFLAKE_EPOCH`, the node and a per-millisecond sequence.
+const NODE_BITS: u32 = 10;
+const SEQUENCE_BITS: u32 = 12;
+const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+/// 2024-01-01T00:00:00Z, which leaves the 41 timestamp bits good for ~69
+/// years.
+pub const SNOWFLAKE_EPOCH: u64 = 1_704_067_200_000;
+
+/// How far the wall clock may jump back before generating ids is refused
+/// rather than waited out.
+const MAX_CLOCK_REGRESSION: u64 = 1_000;
+
+const ULID_RANDOM_BITS: u32 = 80;
+const ULID_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const UUID_COUNTER_BITS: u32 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdFormat {
+    /// 64-bit snowflake ids.
+    Numeric,
+    Ulid,
+    UuidV7,
+    /// Numeric ids from blocks leased off a shared lin-kv counter.
+    Block,
+}
+
+impl IdFormat {
+    pub fn from_env(default: IdFormat) -> Result<IdFormat> {
+        match env::var(ID_FORMAT_ENV) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(default),
+        }
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "numeric" | "snowflake" => Ok(IdFormat::Numeric),
+            "ulid" => Ok(IdFormat::Ulid),
+            "uuid" | "uuidv7" => Ok(IdFormat::UuidV7),
+            "block" => Ok(IdFormat::Block),
+            _ => bail!("unknown id format {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for IdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdFormat::Numeric => write!(f, "numeric"),
+            IdFormat::Ulid => write!(f, "ulid"),
+            IdFormat::UuidV7 => write!(f, "uuidv7"),
+            IdFormat::Block => write!(f, "block"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum Id {
+    Numeric(u64),
+    Text(String),
+}
+
+/// Milliseconds since an epoch that never go backwards. Small clock
+/// regressions are absorbed by staying on the last millisecond handed out,
+/// and running out of ids in a millisecond by waiting for the clock to move
+/// on. Ids never get ahead of the clock, so a restarted node can't hand out
+/// the same millisecond again.
+struct Millis {
+    clock: Arc<dyn Clock>,
+    epoch: u64,
+    last: u64,
+}
+
+impl Millis {
+    fn new(clock: Arc<dyn Clock>, epoch: u64) -> Self {
+        Millis {
+            clock,
+            epoch,
+            last: 0,
+        }
+    }
+
+    fn read(&self) -> u64 {
+        let unix = self
+            .clock
+            .wall()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        unix.saturating_sub(self.epoch)
+    }
+
+    fn check(&self, now: u64) -> Result<()> {
+        if now + MAX_CLOCK_REGRESSION < self.last {
+            bail!("clock moved backwards by {}ms", self.last - now);
+        }
+
+        Ok(())
+    }
+
+    /// The current millisecond, or the last one handed out if the clock is
+    /// behind it.
+    fn current(&self) -> Result<u64> {
+        let now = self.read();
+        self.check(now)?;
+        Ok(now.max(self.last))
+    }
+
+    /// Waits for the clock to get past the last millisecond handed out, for
+    /// when everything in it has been used up.
+    fn past_last(&self) -> Result<u64> {
+        loop {
+            let now = self.read();
+            self.check(now)?;
+            if now > self.last {
+                return Ok(now);
+            }
+
+            self.clock.sleep(Duration::from_millis(self.last + 1 - now));
+        }
+    }
+}
+
+/// Time-sortable 64-bit ids. They stay unique across restarts as long as a
+/// node's clock doesn't go back past its last id's millisecond while it's
+/// down.
+pub struct Snowflake {
+    millis: Millis,
+    node: u64,
+    sequence: u64,
+}
+
+impl Snowflake {
+    pub fn new(node: u64, clock: Arc<dyn Clock>) -> Result<Self> {
+        if node > MAX_NODE {
+            bail!("node {} doesn't fit in {} bits", node, NODE_BITS);
+        }
+
+        Ok(Snowflake {
+            millis: Millis::new(clock, SNOWFLAKE_EPOCH),
+            node,
+            sequence: 0,
+        })
+    }
+
+    pub fn next_id(&mut self) -> Result<u64> {
+        let mut ms = self.millis.current()?;
+        if ms == self.millis.last {
+            self.sequence += 1;
+            if self.sequence > MAX_SEQUENCE {
+                ms = self.millis.past_last()?;
+                self.sequence = 0;
+            }
+        } else {
+            self.sequence = 0;
+        }
+        self.millis.last = ms;
+
+        Ok(ms << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | self.sequence)
+    }
+
+    /// Splits an id into milliseconds since the epoch, node and sequence.
+    pub fn decompose(id: u64) -> (u64, u64, u64) {
+        (
+            id >> (NODE_BITS + SEQUENCE_BITS),
+            (id >> SEQUENCE_BITS) & MAX_NODE,
+            id & MAX_SEQUENCE,
+        )
+    }
+}
+
+/// Monotonic ULIDs: within a millisecond the random part is incremented
+/// instead of redrawn, so ids from one node still sort in generation order.
+pub struct Ulid {
+    millis: Millis,
+    random: u128,
+}
+
+impl Ulid {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Ulid {
+            millis: Millis::new(clock, 0),
+            random: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> Result<String> {
+        let max_random = (1u128 << ULID_RANDOM_BITS) - 1;
+        let mut ms = self.millis.current()?;
+        if ms == self.millis.last && self.random < max_random {
+            self.random += 1;
+        } else {
+            if ms == self.millis.last {
+                ms = self.millis.past_last()?;
+            }
+            self.random = rand::thread_rng().gen::<u128>() & max_random;
+        }
+        self.millis.last = ms;
+
+        let value = (ms as u128) << ULID_RANDOM_BITS | self.random;
+        let encoded = (0..26)
+            .map(|i| ULID_ALPHABET[((value >> (125 - 5 * i)) & 31) as usize] as char)
+            .collect();
+
+        Ok(encoded)
+    }
+}
+
+/// RFC 9562 version 7 UUIDs, using the 12 `rand_a` bits as a counter within
+/// the millisecond.
+pub struct UuidV7 {
+    millis: Millis,
+    counter: u64,
+}
+
+impl UuidV7 {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        UuidV7 {
+            millis: Millis::new(clock, 0),
+            counter: 0,
+        }
+    }
+
+    pub fn next_id(&mut self) -> Result<String> {
+        let mut ms = self.millis.current()?;
+        if ms == self.millis.last {
+            self.counter += 1;
+            if self.counter >= 1 << UUID_COUNTER_BITS {
+                ms = self.millis.past_last()?;
+                self.counter = 0;
+            }
+        } else {
+            self.counter = 0;
+        }
+        self.millis.last = ms;
+
+        let random = rand::thread_rng().gen::<u64>() >> 2;
+        let high = ms << 16 | 0x7 << UUID_COUNTER_BITS | self.counter;
+        let low = 0b10 << 62 | random;
+
+        let hex = format!("{:016x}{:016x}", high, low);
+        Ok(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        ))
+    }
+}
+
+pub enum IdGenerator {
+    Snowflake(Snowflake),
+    Ulid(Ulid),
+    UuidV7(UuidV7),
+}
+
+impl IdGenerator {
+    /// Snowflake node bits come from the node's position in the cluster.
+    pub fn new(format: IdFormat, cluster_state: &ClusterState) -> Result<Self> {
+        let clock = cluster_state.clock.clone();
+        let generator = match format {
+            IdFormat::Numeric => {
+                let Some(node) = cluster_state
+                    .node_ids
+                    .iter()
+                    .position(|n| n == &cluster_state.node_id)
+                else {
+                    bail!("{} isn't part of the cluster", cluster_state.node_id);
+                };
+
+                IdGenerator::Snowflake(Snowflake::new(node as u64, clock)?)
+            }
+            IdFormat::Ulid => IdGenerator::Ulid(Ulid::new(clock)),
+            IdFormat::UuidV7 => IdGenerator::UuidV7(UuidV7::new(clock)),
+            IdFormat::Block => bail!("block ids have to be leased, see BlockIds"),
+        };
+
+        Ok(generator)
+    }
+
+    pub fn next_id(&mut self) -> Result<Id> {
+        let id = match self {
+            IdGenerator::Snowflake(g) => Id::Numeric(g.next_id()?),
+            IdGenerator::Ulid(g) => Id::Text(g.next_id()?),
+            IdGenerator::UuidV7(g) => Id::Text(g.next_id()?),
+        };
+
+        Ok(id)
+    }
+}
+
+/// Hi/lo ids: blocks of `block_size` ids are leased from a shared counter and
+/// handed out locally. Leasing is left to the caller; this keeps track of
+/// what's left and asks for the next block while there's still some of the
+/// current one to go.
+pub struct BlockIds {
+    current: Range<u64>,
+    next: Option<Range<u64>>,
+    block_size: u64,
+    prefetch_at: u64,
+}
+
+impl BlockIds {
+    /// Prefetches once a fifth of the current block is left.
+    pub fn new(block_size: u64) -> Self {
+        BlockIds {
+            current: 0..0,
+            next: None,
+            block_size,
+            prefetch_at: block_size / 5,
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn next_id(&mut self) -> Option<u64> {
+        if self.current.is_empty() {
+            self.current = self.next.take()?;
+        }
+
+        self.current.next()
+    }
+
+    pub fn needs_block(&self) -> bool {
+        self.next.is_none() && self.current.end - self.current.start <= self.prefetch_at
+    }
+
+    /// Adds the block starting at `start` that was just leased.
+    pub fn add_block(&mut self, start: u64) {
+        let block = start..start + self.block_size;
+        if self.current.is_empty() {
+            self.current = block;
+        } else {
+            self.next = Some(block);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::time::ManualClock;
+
+    use super::*;
+
+    #[test]
+    fn snowflakes_wait_for_the_next_millisecond_once_the_sequence_runs_out() {
+        let clock = Arc::new(ManualClock::new());
+        let mut snowflake = Snowflake::new(7, clock.clone()).unwrap();
+
+        let first = snowflake.next_id().unwrap();
+        let start = snowflake.millis.read();
+        let ids: Vec<u64> = (0..3 * (MAX_SEQUENCE + 1))
+            .map(|_| snowflake.next_id().unwrap())
+            .collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+
+        let (ms, node, _) = Snowflake::decompose(first);
+        let (last_ms, last_node, _) = Snowflake::decompose(*ids.last().unwrap());
+        assert_eq!((last_ms, last_node), (ms + 3, node));
+        assert_eq!(snowflake.millis.read(), start + 3);
+    }
+
+    #[test]
+    fn small_clock_regressions_are_waited_out_and_large_ones_refused() {
+        let clock = Arc::new(ManualClock::new());
+        let mut snowflake = Snowflake::new(0, clock.clone()).unwrap();
+        let before = snowflake.next_id().unwrap();
+
+        // As if the clock had been ahead when the last id was handed out.
+        let now = snowflake.millis.read();
+        snowflake.millis.last = now + 5;
+        let ids: Vec<u64> = (0..2 * (MAX_SEQUENCE + 1))
+            .map(|_| snowflake.next_id().unwrap())
+            .collect();
+        assert!(before < ids[0] && ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(Snowflake::decompose(ids[0]).0, now + 5);
+        assert_eq!(snowflake.millis.read(), now + 7);
+
+        snowflake.millis.last = snowflake.millis.read() + MAX_CLOCK_REGRESSION + 1;
+        assert!(snowflake.next_id().is_err());
+    }
+
+    #[test]
+    fn ulids_and_uuids_sort_in_generation_order() {
+        let clock = Arc::new(ManualClock::new());
+        let mut ulid = Ulid::new(clock.clone());
+        let mut uuid = UuidV7::new(clock.clone());
+
+        let mut ulids = Vec::new();
+        let mut uuids = Vec::new();
+        for i in 0..10_000 {
+            if i % 1_000 == 0 {
+                clock.advance(Duration::from_millis(1));
+            }
+            ulids.push(ulid.next_id().unwrap());
+            uuids.push(uuid.next_id().unwrap());
+        }
+
+        assert!(ulids.windows(2).all(|w| w[0] < w[1]));
+        assert!(uuids.windows(2).all(|w| w[0] < w[1]));
+        assert!(uuids.iter().all(|u| u.len() == 36 && &u[14..15] == "7"));
+    }
+
+    #[test]
+    fn block_ids_hand_out_each_block_and_prefetch_the_next() {
+        let mut ids = BlockIds::new(10);
+        assert!(ids.needs_block());
+        assert_eq!(ids.next_id(), None);
+
+        ids.add_block(100);
+        assert!(!ids.needs_block());
+        let first: Vec<u64> = (0..8).map(|_| ids.next_id().unwrap()).collect();
+        assert_eq!(first, (100..108).collect::<Vec<_>>());
+        assert!(ids.needs_block());
+
+        ids.add_block(300);
+        assert!(!ids.needs_block());
+        let rest: Vec<u64> = (0..4).map(|_| ids.next_id().unwrap()).collect();
+        assert_eq!(rest, [108, 109, 300, 301]);
+
+        assert!(!ids.needs_block());
+        for _ in 0..6 {
+            ids.next_id().unwrap();
+        }
+        assert!(ids.needs_block());
+        assert_eq!(ids.next_id(), Some(308));
+        assert_eq!(ids.next_id(), Some(309));
+        assert_eq!(ids.next_id(), None);
+    }
+}
@@ -0,0 +1,16 @@
+//! Names and error codes of Maelstrom's built-in key-value services. The
+//! request and response payloads live in each binary's `Payload` enum.
+
+pub const LIN_KV: &str = "lin-kv";
+pub const SEQ_KV: &str = "seq-kv";
+pub const LWW_KV: &str = "lww-kv";
+
+pub const TIMEOUT: u64 = 0;
+pub const NOT_SUPPORTED: u64 = 10;
+pub const TEMPORARILY_UNAVAILABLE: u64 = 11;
+pub const CRASH: u64 = 13;
+pub const ABORT: u64 = 14;
+pub const KEY_DOES_NOT_EXIST: u64 = 20;
+pub const KEY_ALREADY_EXISTS: u64 = 21;
+pub const PRECONDITION_FAILED: u64 = 22;
+pub const TXN_CONFLICT: u64 = 30;
@@ -1,9 +1,27 @@
+pub mod anti_entropy;
+pub mod clocks;
+pub mod counter;
+pub mod crdt;
+pub mod groups;
+pub mod hash;
+pub mod hyparview;
+pub mod iblt;
+pub mod ids;
+pub mod kv;
+pub mod merkle;
+pub mod partition;
+pub mod producers;
+pub mod range_set;
+pub mod storage;
+pub mod time;
+pub mod topology;
+
 use serde::{Deserialize, Serialize};
 
 use std::{
     cmp,
     collections::HashMap,
-    io::{BufRead, StdoutLock, Write},
+    io::{BufRead, Write},
     marker::PhantomData,
     sync::{
         mpsc::{self, Receiver, Sender},
@@ -15,10 +33,16 @@ use std::{
 
 use anyhow::{bail, Context, Result};
 
+use crate::{
+    clocks::{LogicalClock, Stamp},
+    time::{Clock, SystemClock},
+};
+
 // TODO: membership table?
 pub struct ClusterState {
     pub node_id: String,
     pub node_ids: Vec<String>,
+    pub clock: Arc<dyn Clock>,
 }
 
 //pub enum RPCRetryPolicy {
@@ -45,27 +69,51 @@ where
 {
     pub seq: usize,
     cluster_state: Arc<ClusterState>,
-    stdout: StdoutLock<'a>,
+    stdout: Box<dyn Write + 'a>,
     _payload: PhantomData<P>,
     pending_requests: HashMap<usize, Request<P>>,
+    logical_clock: Option<LogicalClock>,
 }
 
 impl<'a, P> IO<'a, P>
 where
     P: Serialize + Clone,
 {
+    /// Writes messages to `out` rather than STDOUT, e.g. to see what a server
+    /// sends without running a node.
+    pub fn new(cluster_state: Arc<ClusterState>, out: impl Write + 'a) -> Self {
+        IO {
+            seq: 0,
+            cluster_state,
+            stdout: Box::new(out),
+            _payload: PhantomData,
+            pending_requests: HashMap::new(),
+            logical_clock: None,
+        }
+    }
+
     pub fn send(
         &mut self,
         to: &str,
         in_reply_to: Option<usize>,
         payload: &P,
     ) -> anyhow::Result<usize> {
+        // Only messages between cluster nodes carry a stamp; clients don't
+        // know what to do with one.
+        let stamp = match &mut self.logical_clock {
+            Some(clock) if self.cluster_state.node_ids.iter().any(|n| n == to) => {
+                Some(clock.stamp(&self.cluster_state.node_id))
+            }
+            _ => None,
+        };
+
         let message = Message::<P> {
             src: self.cluster_state.node_id.clone(),
             dst: to.to_string(),
             body: Body::<P> {
                 id: Some(self.seq),
                 in_reply_to,
+                stamp,
                 payload: payload.clone(),
             },
         };
@@ -84,6 +132,22 @@ where
         Ok(seq)
     }
 
+    /// Stamps every outgoing message to another node with `clock` and
+    /// advances it on every stamped message received.
+    pub fn stamp_with(&mut self, clock: LogicalClock) {
+        self.logical_clock = Some(clock);
+    }
+
+    pub fn logical_clock(&mut self) -> Option<&mut LogicalClock> {
+        self.logical_clock.as_mut()
+    }
+
+    fn observe(&mut self, message: &Message<P>) {
+        if let (Some(clock), Some(stamp)) = (&mut self.logical_clock, &message.body.stamp) {
+            clock.observe(&self.cluster_state.node_id, stamp);
+        }
+    }
+
     pub fn fire_and_forget(&mut self, dst: &str, message: &P) -> anyhow::Result<()> {
         // TODO: handle errors?
         _ = self.send(dst, None, message);
@@ -97,14 +161,13 @@ where
         timeout: Duration,
         retry: bool,
     ) -> anyhow::Result<usize> {
-        let dst = dst;
         let id = self.send(dst, None, request)?;
         let request = Request {
             id,
             dst: dst.to_string(), //TODO: try to do it with reference?
             payload: request.clone(),
             timeout,
-            issued_at: Instant::now(),
+            issued_at: self.cluster_state.clock.now(),
             retry,
         };
 
@@ -147,7 +210,7 @@ where
             .pending_requests
             .values()
             .cloned()
-            .partition(|r| r.issued_at.elapsed() >= r.timeout);
+            .partition(|r| self.cluster_state.clock.elapsed(r.issued_at) >= r.timeout);
 
         for r in &timedout {
             let Some(request) = self.pending_requests.remove(&r.id) else {
@@ -163,7 +226,7 @@ where
             .iter()
             .map(|r| {
                 r.timeout
-                    .checked_sub(r.issued_at.elapsed())
+                    .checked_sub(self.cluster_state.clock.elapsed(r.issued_at))
                     .unwrap_or_default()
             })
             .min()
@@ -181,8 +244,8 @@ where
     pub cluster_state: Arc<ClusterState>,
     pub io: IO<'a, P>,
     pub handler: H,
-    in_tx: Sender<Event<P, T>>,
-    in_rx: Receiver<Event<P, T>>,
+    in_tx: Sender<Event<P>>,
+    in_rx: Receiver<Event<P>>,
     timers: Timers<P, T>,
 }
 
@@ -195,6 +258,10 @@ where
     T: Send + Clone + Copy + 'static,
 {
     pub fn init() -> anyhow::Result<Node<'a, H, P, T>> {
+        Self::init_with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn init_with_clock(clock: Arc<dyn Clock>) -> anyhow::Result<Node<'a, H, P, T>> {
         let mut stdin = std::io::stdin().lock().lines();
 
         let init_msg: Message<InitPayload> = serde_json::from_str(
@@ -212,20 +279,18 @@ where
         let cluster_state = ClusterState {
             node_id: init.node_id.clone(),
             node_ids: init.node_ids.clone(),
+            clock,
         };
 
         let cluster_state = Arc::new(cluster_state);
 
-        let io = IO::<P> {
-            seq: 0,
-            cluster_state: cluster_state.clone(),
-            stdout: std::io::stdout().lock(),
-            _payload: PhantomData,
-            pending_requests: HashMap::<usize, Request<P>>::new(),
-        };
+        let mut io = IO::<P>::new(cluster_state.clone(), std::io::stdout().lock());
+        if let Some(clock) = H::logical_clock(&cluster_state) {
+            io.stamp_with(clock);
+        }
 
         let (in_tx, in_rx) = mpsc::channel();
-        let mut timers: Timers<P, T> = Timers::new(in_tx.clone());
+        let mut timers: Timers<P, T> = Timers::new(cluster_state.clock.clone());
 
         let server = Server::init(&cluster_state, &mut timers)?;
 
@@ -238,19 +303,35 @@ where
             in_rx,
         };
 
-        let mut init_io = IO::<InitPayload> {
-            seq: 0,
-            cluster_state: cluster_state.clone(),
-            stdout: std::io::stdout().lock(),
-            _payload: PhantomData,
-            pending_requests: HashMap::<usize, Request<InitPayload>>::new(),
-        };
+        let mut init_io = IO::<InitPayload>::new(cluster_state.clone(), std::io::stdout().lock());
 
         init_io.rpc_reply_to(&init_msg, &InitPayload::InitOk)?;
 
         Ok(node)
     }
 
+    /// Handles the RPC timeouts and timers that are due by the node's clock,
+    /// and returns how long until the next one is.
+    pub fn tick(&mut self) -> anyhow::Result<Duration> {
+        let (timeouts, to_next_timeout) = self.io.rpc_tend()?;
+        for r in timeouts {
+            self.handler
+                .on_rpc_timeout(&self.cluster_state, &mut self.io, r)
+                .context("failed processing message")?;
+        }
+
+        let (due, to_next_timer) = self.timers.tend();
+        for timer in due {
+            self.handler
+                .on_timer(&self.cluster_state, &mut self.io, timer)
+                .context("failed processing message")?;
+        }
+
+        Ok(cmp::min(to_next_timeout, to_next_timer))
+    }
+
+    /// Waits for messages in real time, but timeouts and timers are only
+    /// handled once the node's clock says they're due.
     pub fn run(&mut self) -> anyhow::Result<()> {
         let stdin_tx = self.in_tx.clone();
         let jh = thread::spawn(move || {
@@ -258,7 +339,7 @@ where
             let in_stream = serde_json::Deserializer::from_reader(stdin).into_iter();
             for msg in in_stream {
                 let msg: Message<P> = msg.context("failed to deserialize message from STDIN")?;
-                let event: Event<P, T> = Event::Message(msg);
+                let event: Event<P> = Event::Message(msg);
 
                 if stdin_tx.send(event).is_err() {
                     return Ok::<_, anyhow::Error>(());
@@ -272,45 +353,33 @@ where
             Ok(())
         });
 
+        let clock = self.cluster_state.clock.clone();
         let mut tick_timeout = Duration::ZERO;
-        let mut last_tick = Instant::now();
+        let mut last_tick = clock.now();
         loop {
             let event = self.in_rx.recv_timeout(tick_timeout).unwrap_or(Event::Tick);
             match event {
                 Event::Message(message) => {
+                    self.io.observe(&message);
                     self.handler
                         .on_message(&self.cluster_state, &mut self.io, message)
                         .context("failed processing message")?;
                 }
-                Event::Timer(timer) => {
-                    self.handler
-                        .on_timer(&self.cluster_state, &mut self.io, timer)
-                        .context("failed processing message")?;
-                }
                 Event::EOF => break,
                 Event::Tick => (),
             }
 
-            let since_last_tick = last_tick.elapsed();
+            let since_last_tick = clock.elapsed(last_tick);
 
             if since_last_tick >= tick_timeout {
-                let (timeouts, to_next_timeout) = self.io.rpc_tend()?;
-                tick_timeout = cmp::min(to_next_timeout, TICK_DURATION);
-                for r in timeouts {
-                    self.handler
-                        .on_rpc_timeout(&self.cluster_state, r)
-                        .context("failed processing message")?;
-                }
-
-                let to_next_timer = self.timers.fire()?;
-                tick_timeout = cmp::min(tick_timeout, to_next_timer);
+                tick_timeout = cmp::min(self.tick()?, TICK_DURATION);
             } else {
                 tick_timeout = tick_timeout
                     .checked_sub(since_last_tick)
                     .unwrap_or_default();
             }
 
-            last_tick = Instant::now();
+            last_tick = clock.now();
         }
 
         jh.join().expect("STDIN processing panicked")?;
@@ -327,6 +396,15 @@ where
     where
         Self: Sized;
 
+    /// The clock to stamp messages between nodes with, if any. It's kept by
+    /// `IO`, which advances it on every stamped message received.
+    fn logical_clock(_cluster_state: &ClusterState) -> Option<LogicalClock>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
     fn on_message(
         &mut self,
         cluster_state: &ClusterState,
@@ -340,7 +418,12 @@ where
     where
         Self: Sized;
 
-    fn on_rpc_timeout(&mut self, cluster_state: &ClusterState, timeout: Request<P>) -> Result<()>
+    fn on_rpc_timeout(
+        &mut self,
+        cluster_state: &ClusterState,
+        io: &mut IO<P>,
+        timeout: Request<P>,
+    ) -> Result<()>
     where
         Self: Sized;
 }
@@ -350,6 +433,8 @@ pub struct Body<P> {
     #[serde(rename = "msg_id")]
     pub id: Option<usize>,
     pub in_reply_to: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stamp: Option<Stamp>,
     #[serde(flatten)]
     pub payload: P,
 }
@@ -376,8 +461,7 @@ pub struct Message<P> {
     pub body: Body<P>,
 }
 
-pub enum Event<P, T> {
-    Timer(T),
+pub enum Event<P> {
     Message(Message<P>),
     Tick,
     EOF,
@@ -392,18 +476,19 @@ struct TimerRegistration<T> {
 
 pub struct Timers<P, T> {
     regs: Vec<TimerRegistration<T>>,
-    trigger: Sender<Event<P, T>>,
+    clock: Arc<dyn Clock>,
+    _payload: PhantomData<P>,
 }
 
 impl<P, T> Timers<P, T>
 where
-    P: Send + 'static,
-    T: Clone + Copy + Send + 'static,
+    T: Clone + Copy,
 {
-    fn new(trigger: Sender<Event<P, T>>) -> Self {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
         Timers {
             regs: Vec::new(),
-            trigger,
+            clock,
+            _payload: PhantomData,
         }
     }
 
@@ -411,28 +496,143 @@ where
         let reg = TimerRegistration {
             timer,
             interval,
-            last_fire: Instant::now(),
+            last_fire: self.clock.now(),
         };
 
         self.regs.push(reg);
     }
 
-    fn fire(&mut self) -> anyhow::Result<Duration> {
+    /// Returns the timers that are due by the clock, starting their next
+    /// interval, and how long until the next one is.
+    pub fn tend(&mut self) -> (Vec<T>, Duration) {
+        let mut due = Vec::new();
         let mut sleep = Duration::MAX;
         for reg in &mut self.regs {
-            let last_fire = reg.last_fire.elapsed();
+            let last_fire = self.clock.elapsed(reg.last_fire);
             if last_fire >= reg.interval {
-                let event: Event<P, T> = Event::<P, T>::Timer(reg.timer);
-                if self.trigger.send(event).is_err() {
-                    anyhow::bail!("")
-                }
-                reg.last_fire = Instant::now();
+                due.push(reg.timer);
+                reg.last_fire = self.clock.now();
                 sleep = cmp::min(sleep, reg.interval);
             } else {
-                sleep = reg.interval.checked_sub(last_fire).unwrap_or_default();
+                let until_due = reg.interval.checked_sub(last_fire).unwrap_or_default();
+                sleep = cmp::min(sleep, until_due);
             }
         }
 
-        Ok(sleep)
+        (due, sleep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::time::ManualClock;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Payload {
+        Ping,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Timer {
+        Fast,
+        Slow,
+    }
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        fn lines(&self) -> usize {
+            self.0.borrow().iter().filter(|&&b| b == b'\n').count()
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn cluster_state(clock: Arc<ManualClock>) -> Arc<ClusterState> {
+        Arc::new(ClusterState {
+            node_id: "n0".to_string(),
+            node_ids: vec!["n0".to_string(), "n1".to_string()],
+            clock,
+        })
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn timers_fire_once_the_clock_reaches_their_interval() {
+        let clock = Arc::new(ManualClock::new());
+        let mut timers = Timers::<Payload, Timer>::new(clock.clone());
+        timers.register_timer(Timer::Fast, ms(100));
+        timers.register_timer(Timer::Slow, ms(250));
+
+        assert_eq!(timers.tend(), (vec![], ms(100)));
+
+        clock.advance(ms(100));
+        assert_eq!(timers.tend(), (vec![Timer::Fast], ms(100)));
+
+        clock.advance(ms(60));
+        assert_eq!(timers.tend(), (vec![], ms(40)));
+
+        clock.advance(ms(90));
+        assert_eq!(timers.tend(), (vec![Timer::Fast, Timer::Slow], ms(100)));
+    }
+
+    #[test]
+    fn timed_out_requests_are_returned_and_retried_if_asked_to() {
+        let clock = Arc::new(ManualClock::new());
+        let out = Output::default();
+        let mut io = IO::<Payload>::new(cluster_state(clock.clone()), out.clone());
+
+        let retried = io
+            .rpc_request_with_retry("n1", &Payload::Ping, ms(100))
+            .unwrap();
+        clock.advance(ms(20));
+        let dropped = io
+            .rpc_request("n1", &Payload::Ping, ms(100), false)
+            .unwrap();
+        assert_eq!(out.lines(), 2);
+
+        clock.advance(ms(50));
+        let (timeouts, sleep) = io.rpc_tend().unwrap();
+        assert!(timeouts.is_empty());
+        assert_eq!(sleep, ms(30));
+
+        clock.advance(ms(30));
+        let (timeouts, sleep) = io.rpc_tend().unwrap();
+        assert_eq!(
+            timeouts.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![retried]
+        );
+        assert_eq!(sleep, ms(20));
+        assert_eq!(out.lines(), 3);
+
+        clock.advance(ms(20));
+        let (timeouts, _) = io.rpc_tend().unwrap();
+        assert_eq!(
+            timeouts.iter().map(|r| r.id).collect::<Vec<_>>(),
+            vec![dropped]
+        );
+        assert_eq!(out.lines(), 3);
+
+        // Only the retry is still waiting for a reply.
+        assert_eq!(io.pending_requests.len(), 1);
+        assert!(!io.pending_requests.contains_key(&retried));
     }
 }
@@ -0,0 +1,276 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hash::{fnv1a, mix};
+
+/// Gives 64 leaf buckets, which keeps a full drill down at six round trips.
+pub const DEFAULT_DEPTH: u32 = 6;
+
+/// Tree node index; the root is 1 and the children of `n` are `2n` and
+/// `2n + 1`.
+pub type NodeIndex = usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind")]
+#[serde(rename_all = "snake_case")]
+pub enum MerkleSync<E> {
+    /// Hashes of the tree nodes the sender wants compared.
+    Summary { nodes: Vec<(NodeIndex, u64)> },
+    /// The sender's entries in some leaves, and the leaves it wants the
+    /// receiver's entries for in return.
+    Repair {
+        entries: Vec<E>,
+        wanted: Vec<NodeIndex>,
+    },
+}
+
+/// Merkle tree over a replicated key space. Keys are spread over the leaves
+/// by hash and each leaf is the XOR of its entries' hashes, so an entry can be
+/// added or removed without rehashing the rest of its bucket. Two replicas
+/// compare roots and only drill into the subtrees that differ.
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    depth: u32,
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    pub fn new(depth: u32) -> Self {
+        let mut tree = MerkleTree {
+            depth,
+            nodes: vec![0; 2 << depth],
+        };
+
+        for n in (1..tree.first_leaf()).rev() {
+            tree.nodes[n] = tree.combine(n);
+        }
+
+        tree
+    }
+
+    fn first_leaf(&self) -> NodeIndex {
+        1 << self.depth
+    }
+
+    fn is_leaf(&self, n: NodeIndex) -> bool {
+        n >= self.first_leaf()
+    }
+
+    fn combine(&self, n: NodeIndex) -> u64 {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.nodes[2 * n].to_le_bytes());
+        bytes[8..].copy_from_slice(&self.nodes[2 * n + 1].to_le_bytes());
+        fnv1a(&bytes)
+    }
+
+    pub fn root(&self) -> u64 {
+        self.nodes[1]
+    }
+
+    /// The leaf a key with this hash lives in. The hash is mixed first, as
+    /// FNV's top bits hardly differ between similar keys.
+    pub fn leaf(&self, key_hash: u64) -> NodeIndex {
+        match self.depth {
+            0 => 1,
+            depth => self.first_leaf() + (mix(key_hash) >> (64 - depth)) as usize,
+        }
+    }
+
+    /// Adds an entry, or removes it if it's already there. Replacing an entry
+    /// is toggling the old one out and the new one in.
+    pub fn toggle(&mut self, key_hash: u64, entry_hash: u64) {
+        let mut n = self.leaf(key_hash);
+        self.nodes[n] ^= entry_hash;
+        while n > 1 {
+            n /= 2;
+            self.nodes[n] = self.combine(n);
+        }
+    }
+
+    pub fn summary<E>(&self) -> MerkleSync<E> {
+        MerkleSync::Summary {
+            nodes: vec![(1, self.root())],
+        }
+    }
+
+    /// Answers a peer's sync message: differing inner nodes are summarized one
+    /// level further down, and differing leaves are repaired in both
+    /// directions. `entries` returns our entries in the given leaves. Incoming
+    /// repair entries have to be applied by the caller.
+    pub fn reply<E>(
+        &self,
+        sync: &MerkleSync<E>,
+        entries: impl Fn(&[NodeIndex]) -> Vec<E>,
+    ) -> Vec<MerkleSync<E>> {
+        let mut replies = Vec::new();
+        match sync {
+            MerkleSync::Summary { nodes } => {
+                let mut summary = Vec::new();
+                let mut leaves = Vec::new();
+                let differing = nodes
+                    .iter()
+                    .filter(|&&(n, hash)| self.nodes.get(n).is_some_and(|&h| h != hash));
+
+                for &(n, _) in differing {
+                    if self.is_leaf(n) {
+                        leaves.push(n);
+                    } else {
+                        summary.push((2 * n, self.nodes[2 * n]));
+                        summary.push((2 * n + 1, self.nodes[2 * n + 1]));
+                    }
+                }
+
+                if !summary.is_empty() {
+                    replies.push(MerkleSync::Summary { nodes: summary });
+                }
+                if !leaves.is_empty() {
+                    replies.push(MerkleSync::Repair {
+                        entries: entries(&leaves),
+                        wanted: leaves,
+                    });
+                }
+            }
+            MerkleSync::Repair { wanted, .. } if !wanted.is_empty() => {
+                replies.push(MerkleSync::Repair {
+                    entries: entries(wanted),
+                    wanted: Vec::new(),
+                });
+            }
+            MerkleSync::Repair { .. } => (),
+        }
+
+        replies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashSet};
+
+    use super::*;
+
+    type Entry = (u64, u64);
+
+    /// A replica of a key space where higher values win.
+    struct Replica {
+        entries: BTreeMap<u64, u64>,
+        tree: MerkleTree,
+    }
+
+    impl Replica {
+        fn new() -> Self {
+            Replica {
+                entries: BTreeMap::new(),
+                tree: MerkleTree::new(DEFAULT_DEPTH),
+            }
+        }
+
+        fn put(&mut self, key: u64, value: u64) {
+            let key_hash = fnv1a(&key.to_le_bytes());
+            if let Some(old) = self.entries.insert(key, value) {
+                self.tree.toggle(key_hash, entry_hash(key, old));
+            }
+            self.tree.toggle(key_hash, entry_hash(key, value));
+        }
+
+        fn merge(&mut self, entries: &[Entry]) {
+            for &(key, value) in entries {
+                if self.entries.get(&key).is_none_or(|&v| v < value) {
+                    self.put(key, value);
+                }
+            }
+        }
+
+        fn in_leaves(&self, leaves: &[NodeIndex]) -> Vec<Entry> {
+            self.entries
+                .iter()
+                .filter(|(&k, _)| leaves.contains(&self.tree.leaf(fnv1a(&k.to_le_bytes()))))
+                .map(|(&k, &v)| (k, v))
+                .collect()
+        }
+
+        /// Answers a sync message, applying any entries it carries first.
+        fn reply(&mut self, sync: &MerkleSync<Entry>) -> Vec<MerkleSync<Entry>> {
+            if let MerkleSync::Repair { entries, .. } = sync {
+                self.merge(entries);
+            }
+            self.tree.reply(sync, |leaves| self.in_leaves(leaves))
+        }
+    }
+
+    fn entry_hash(key: u64, value: u64) -> u64 {
+        let mut bytes = key.to_le_bytes().to_vec();
+        bytes.extend(value.to_le_bytes());
+        fnv1a(&bytes)
+    }
+
+    /// Runs a sync started by `a` to completion and returns the leaves that
+    /// got repaired.
+    fn sync(a: &mut Replica, b: &mut Replica) -> HashSet<NodeIndex> {
+        let mut repaired = HashSet::new();
+        let mut in_flight = vec![(true, a.tree.summary())];
+        while let Some((to_b, sync)) = in_flight.pop() {
+            if let MerkleSync::Repair { wanted, .. } = &sync {
+                repaired.extend(wanted);
+            }
+
+            let to = if to_b { &mut *b } else { &mut *a };
+            in_flight.extend(to.reply(&sync).into_iter().map(|r| (!to_b, r)));
+        }
+
+        repaired
+    }
+
+    #[test]
+    fn similar_keys_spread_over_the_leaves() {
+        let tree = MerkleTree::new(DEFAULT_DEPTH);
+        let leaves: HashSet<NodeIndex> = (0..64u64)
+            .map(|k| tree.leaf(fnv1a(&k.to_le_bytes())))
+            .collect();
+
+        assert!(leaves.len() > 32, "only {} leaves used", leaves.len());
+    }
+
+    #[test]
+    fn one_differing_key_repairs_exactly_its_leaf() {
+        let mut a = Replica::new();
+        let mut b = Replica::new();
+        for key in 0..200 {
+            a.put(key, 1);
+            b.put(key, 1);
+        }
+        assert_eq!(a.tree.root(), b.tree.root());
+        assert!(sync(&mut a, &mut b).is_empty());
+
+        b.put(42, 2);
+        let leaf = a.tree.leaf(fnv1a(&42u64.to_le_bytes()));
+        assert_eq!(sync(&mut a, &mut b), HashSet::from([leaf]));
+        assert_eq!(a.entries[&42], 2);
+        assert_eq!(a.tree.root(), b.tree.root());
+    }
+
+    #[test]
+    fn replies_converge_two_diverged_replicas() {
+        let mut a = Replica::new();
+        let mut b = Replica::new();
+        for key in 0..500 {
+            match key % 5 {
+                0 => a.put(key, key),
+                1 => b.put(key, key),
+                2 => {
+                    a.put(key, 1);
+                    b.put(key, 2);
+                }
+                _ => {
+                    a.put(key, 3);
+                    b.put(key, 3);
+                }
+            }
+        }
+
+        sync(&mut a, &mut b);
+        assert_eq!(a.entries, b.entries);
+        assert_eq!(a.tree.root(), b.tree.root());
+        assert_eq!(a.entries.len(), 500);
+        assert!(sync(&mut b, &mut a).is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use crate::hash::{fnv1a, mix};
+
+/// Points each node gets on the ring. More points spread keys more evenly.
+pub const DEFAULT_VIRTUAL_NODES: usize = 64;
+
+fn point(bytes: &[u8]) -> u64 {
+    mix(fnv1a(bytes))
+}
+
+/// Consistent-hash ring. A key belongs to the first node point at or after
+/// its own hash, so adding or removing a node only moves the keys between
+/// that node's points and their predecessors.
+#[derive(Clone, Debug)]
+pub struct HashRing {
+    ring: BTreeMap<u64, String>,
+    virtual_nodes: usize,
+}
+
+impl HashRing {
+    pub fn new(nodes: &[String], virtual_nodes: usize) -> Self {
+        let mut ring = HashRing {
+            ring: BTreeMap::new(),
+            virtual_nodes,
+        };
+
+        for node in nodes {
+            ring.add(node);
+        }
+
+        ring
+    }
+
+    pub fn add(&mut self, node: &str) {
+        for i in 0..self.virtual_nodes {
+            let point = point(format!("{}#{}", node, i).as_bytes());
+            self.ring.insert(point, node.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, node: &str) {
+        self.ring.retain(|_, n| n != node);
+    }
+
+    pub fn contains(&self, node: &str) -> bool {
+        self.ring.values().any(|n| n == node)
+    }
+
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        self.owners(key, 1).into_iter().next()
+    }
+
+    /// The first `count` distinct nodes clockwise from the key, for placing
+    /// replicas.
+    pub fn owners(&self, key: &str, count: usize) -> Vec<&str> {
+        let start = point(key.as_bytes());
+        let mut owners: Vec<&str> = Vec::with_capacity(count);
+        for node in self
+            .ring
+            .range(start..)
+            .chain(self.ring.range(..start))
+            .map(|(_, n)| n)
+        {
+            if owners.len() == count {
+                break;
+            }
+            if !owners.contains(&node.as_str()) {
+                owners.push(node);
+            }
+        }
+
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 10_000;
+
+    fn nodes(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    fn owners(ring: &HashRing) -> Vec<String> {
+        (0..KEYS)
+            .map(|k| ring.owner(&k.to_string()).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn a_joining_node_only_takes_about_its_share_of_keys() {
+        let mut ring = HashRing::new(&nodes(4), DEFAULT_VIRTUAL_NODES);
+        let before = owners(&ring);
+
+        ring.add("n4");
+        let after = owners(&ring);
+        let moved: Vec<usize> = (0..KEYS).filter(|&k| before[k] != after[k]).collect();
+
+        assert!(moved.iter().all(|&k| after[k] == "n4"));
+        let share = moved.len() as f64 / KEYS as f64;
+        assert!((0.1..0.3).contains(&share), "moved {}", share);
+    }
+
+    #[test]
+    fn a_leaving_node_only_gives_up_its_own_keys() {
+        let mut ring = HashRing::new(&nodes(5), DEFAULT_VIRTUAL_NODES);
+        let before = owners(&ring);
+
+        ring.remove("n2");
+        assert!(!ring.contains("n2"));
+        let after = owners(&ring);
+        let moved: Vec<usize> = (0..KEYS).filter(|&k| before[k] != after[k]).collect();
+
+        assert!(moved.iter().all(|&k| before[k] == "n2"));
+        assert_eq!(moved.len(), before.iter().filter(|&n| n == "n2").count());
+        let share = moved.len() as f64 / KEYS as f64;
+        assert!((0.1..0.3).contains(&share), "moved {}", share);
+    }
+
+    #[test]
+    fn replicas_go_to_distinct_nodes() {
+        let ring = HashRing::new(&nodes(5), DEFAULT_VIRTUAL_NODES);
+        for k in 0..100 {
+            let key = k.to_string();
+            let mut owners = ring.owners(&key, 3);
+            assert_eq!(owners[0], ring.owner(&key).unwrap());
+            owners.sort();
+            owners.dedup();
+            assert_eq!(owners.len(), 3);
+        }
+
+        assert_eq!(ring.owners("k", 10).len(), 5);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{bail, Result};
+
+use crate::storage::{LogStore, Offset, Record};
+
+/// How many of each producer's latest sends a log remembers, i.e. how many
+/// it may have in flight and still retry safely.
+pub const PRODUCER_WINDOW: usize = 5;
+
+pub type ProducerId = u64;
+
+/// What a producer's send is, going by its sequence number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sequence {
+    New,
+    /// A retry of a send that's already in the log, at this offset.
+    Duplicate(Offset),
+    /// Sends after the previous one haven't arrived yet, or failed.
+    OutOfOrder {
+        expected: u64,
+    },
+    /// A retry of a send too far back to know where it went.
+    TooOld,
+}
+
+/// The latest sequence numbers each producer sent to one log, and the offsets
+/// they were appended at. It's rebuilt from the log itself, so replicas that
+/// have the records have this too.
+///
+/// Producers we know nothing about may start from any sequence number: their
+/// records may have been deleted, taking what we knew about them along.
+#[derive(Clone, Debug, Default)]
+pub struct Producers {
+    sent: HashMap<ProducerId, VecDeque<(u64, Offset)>>,
+}
+
+impl Producers {
+    pub fn new() -> Self {
+        Producers::default()
+    }
+
+    /// Reads through `store` for the producers' latest sends.
+    pub fn load(store: &dyn LogStore) -> Result<Self> {
+        let mut producers = Producers::new();
+        let mut offset = store.start_offset();
+        loop {
+            let records = store.read(offset, 100)?;
+            let Some(last) = records.last() else {
+                break;
+            };
+            offset = last.offset + 1;
+
+            for record in &records {
+                if let Some((producer, sequence)) = producer(record) {
+                    producers.record(producer, sequence, record.offset);
+                }
+            }
+        }
+
+        Ok(producers)
+    }
+
+    pub fn check(&self, producer: ProducerId, sequence: u64) -> Sequence {
+        let Some(sent) = self.sent.get(&producer) else {
+            return Sequence::New;
+        };
+
+        let &(last, _) = sent.back().expect("producers are only kept with sends");
+        if let Some(&(_, offset)) = sent.iter().find(|&&(s, _)| s == sequence) {
+            Sequence::Duplicate(offset)
+        } else if sequence == last + 1 {
+            Sequence::New
+        } else if sequence > last {
+            Sequence::OutOfOrder { expected: last + 1 }
+        } else {
+            Sequence::TooOld
+        }
+    }
+
+    /// Notes a send appended at `offset`.
+    pub fn record(&mut self, producer: ProducerId, sequence: u64, offset: Offset) {
+        let sent = self.sent.entry(producer).or_default();
+        sent.push_back((sequence, offset));
+        if sent.len() > PRODUCER_WINDOW {
+            sent.pop_front();
+        }
+    }
+}
+
+/// Records are the message, the producer id and sequence number it was sent
+/// with, or zeros, and then its sub-key if it has one.
+pub fn encode(
+    message: usize,
+    producer: Option<(ProducerId, u64)>,
+    sub_key: Option<&str>,
+) -> Vec<u8> {
+    let (producer, sequence) = producer.unwrap_or_default();
+    let mut value = (message as u64).to_le_bytes().to_vec();
+    value.extend(producer.to_le_bytes());
+    value.extend(sequence.to_le_bytes());
+    value.extend(sub_key.unwrap_or_default().as_bytes());
+    value
+}
+
+/// The offset and message of a record written by `encode`.
+pub fn decode(record: &Record) -> Result<(Offset, usize)> {
+    let Some(Ok(message)) = record.value.get(..8).map(<[u8; 8]>::try_from) else {
+        bail!("record {} isn't a message", record.offset);
+    };
+
+    Ok((record.offset, u64::from_le_bytes(message) as usize))
+}
+
+/// The producer id and sequence number a record was sent with, if any.
+pub fn producer(record: &Record) -> Option<(ProducerId, u64)> {
+    let producer = u64::from_le_bytes(record.value.get(8..16)?.try_into().ok()?);
+    let sequence = u64::from_le_bytes(record.value.get(16..24)?.try_into().ok()?);
+    (producer != 0).then_some((producer, sequence))
+}
+
+pub fn sub_key(record: &Record) -> Option<Vec<u8>> {
+    record
+        .value
+        .get(24..)
+        .filter(|k| !k.is_empty())
+        .map(<[u8]>::to_vec)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::MemoryLog;
+
+    use super::*;
+
+    #[test]
+    fn retries_of_remembered_sends_are_duplicates() {
+        let mut producers = Producers::new();
+        assert_eq!(producers.check(1, 7), Sequence::New);
+        producers.record(1, 7, 10);
+        producers.record(1, 8, 12);
+
+        assert_eq!(producers.check(1, 7), Sequence::Duplicate(10));
+        assert_eq!(producers.check(1, 8), Sequence::Duplicate(12));
+        assert_eq!(producers.check(1, 9), Sequence::New);
+        assert_eq!(producers.check(2, 7), Sequence::New);
+    }
+
+    #[test]
+    fn gaps_are_out_of_order_and_forgotten_sends_too_old() {
+        let mut producers = Producers::new();
+        for sequence in 0..3 {
+            producers.record(1, sequence, sequence as Offset);
+        }
+
+        assert_eq!(producers.check(1, 5), Sequence::OutOfOrder { expected: 3 });
+        assert_eq!(producers.check(1, 4), Sequence::OutOfOrder { expected: 3 });
+
+        // Only a window of sends is kept; anything before it is lost.
+        for sequence in 3..3 + PRODUCER_WINDOW as u64 {
+            producers.record(1, sequence, sequence as Offset);
+        }
+        let oldest = 3;
+        assert_eq!(producers.check(1, oldest), Sequence::Duplicate(3));
+        assert_eq!(producers.check(1, oldest - 1), Sequence::TooOld);
+        assert_eq!(producers.check(1, 0), Sequence::TooOld);
+    }
+
+    #[test]
+    fn loading_a_log_recovers_its_producers() {
+        let mut store = MemoryLog::new();
+        store.append(0, &encode(1, Some((9, 0)), None)).unwrap();
+        store.append(0, &encode(2, None, Some("sub"))).unwrap();
+        store
+            .append(0, &encode(3, Some((9, 1)), Some("sub")))
+            .unwrap();
+
+        let records = store.read(0, 10).unwrap();
+        assert_eq!(decode(&records[2]).unwrap(), (2, 3));
+        assert_eq!(producer(&records[1]), None);
+        assert_eq!(producer(&records[2]), Some((9, 1)));
+        assert_eq!(sub_key(&records[0]), None);
+        assert_eq!(sub_key(&records[2]), Some(b"sub".to_vec()));
+
+        let producers = Producers::load(&store).unwrap();
+        assert_eq!(producers.check(9, 0), Sequence::Duplicate(0));
+        assert_eq!(producers.check(9, 1), Sequence::Duplicate(2));
+        assert_eq!(producers.check(9, 2), Sequence::New);
+    }
+}
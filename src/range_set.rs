@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A set of integers stored as disjoint, non-adjacent inclusive ranges.
+/// Broadcast values are mostly dense, so this stays tiny where a `HashSet`
+/// or a JSON array would grow with every value.
+///
+/// Serializes as a compact string such as `"0-5,7,9-12"`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSet {
+    ranges: BTreeMap<usize, usize>,
+}
+
+impl RangeSet {
+    pub fn new() -> Self {
+        RangeSet::default()
+    }
+
+    pub fn contains(&self, value: usize) -> bool {
+        self.ranges
+            .range(..=value)
+            .next_back()
+            .is_some_and(|(_, &end)| end >= value)
+    }
+
+    /// Returns whether the value was new.
+    pub fn insert(&mut self, value: usize) -> bool {
+        if self.contains(value) {
+            return false;
+        }
+
+        self.insert_range(value, value);
+        true
+    }
+
+    pub fn insert_range(&mut self, mut start: usize, mut end: usize) {
+        if let Some((&s, &e)) = self.ranges.range(..=start).next_back() {
+            if e.saturating_add(1) >= start {
+                start = s;
+                end = end.max(e);
+            }
+        }
+
+        while let Some((&s, &e)) = self.ranges.range(start..).next() {
+            if s > end.saturating_add(1) {
+                break;
+            }
+
+            self.ranges.remove(&s);
+            end = end.max(e);
+        }
+
+        self.ranges.insert(start, end);
+    }
+
+    pub fn union(&mut self, other: &RangeSet) {
+        for (&start, &end) in &other.ranges {
+            self.insert_range(start, end);
+        }
+    }
+
+    /// Values in `self` that aren't in `other`.
+    pub fn difference(&self, other: &RangeSet) -> RangeSet {
+        let mut result = RangeSet::new();
+        for (&start, &end) in &self.ranges {
+            let first = other
+                .ranges
+                .range(..=start)
+                .next_back()
+                .map_or(start, |(&s, _)| s);
+
+            let mut next = Some(start);
+            for (&s, &e) in other.ranges.range(first..=end) {
+                let Some(from) = next else {
+                    break;
+                };
+
+                if e < from {
+                    continue;
+                }
+                if s > from {
+                    result.insert_range(from, s - 1);
+                }
+                next = e.checked_add(1).filter(|&n| n <= end);
+            }
+
+            if let Some(from) = next {
+                result.insert_range(from, end);
+            }
+        }
+
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.iter().map(|(s, e)| e - s + 1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn ranges(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.ranges.iter().map(|(&s, &e)| (s, e))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ranges.iter().flat_map(|(&s, &e)| s..=e)
+    }
+}
+
+impl FromIterator<usize> for RangeSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = RangeSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<usize> for RangeSet {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, iter: I) {
+        for value in iter {
+            self.insert(value);
+        }
+    }
+}
+
+impl fmt::Display for RangeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (start, end)) in self.ranges().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+
+            if start == end {
+                write!(f, "{}", start)?;
+            } else {
+                write!(f, "{}-{}", start, end)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for RangeSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut set = RangeSet::new();
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start = start
+                .parse()
+                .with_context(|| format!("invalid range {:?}", part))?;
+            let end = end
+                .parse()
+                .with_context(|| format!("invalid range {:?}", part))?;
+            if start > end {
+                bail!("invalid range {:?}", part);
+            }
+            set.insert_range(start, end);
+        }
+
+        Ok(set)
+    }
+}
+
+impl Serialize for RangeSet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RangeSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(set: &RangeSet) -> Vec<(usize, usize)> {
+        set.ranges().collect()
+    }
+
+    #[test]
+    fn insert_range_merges_adjacent_and_overlapping_ranges() {
+        let mut set = RangeSet::new();
+        set.insert_range(0, 2);
+        set.insert_range(3, 4);
+        assert_eq!(ranges(&set), [(0, 4)]);
+
+        set.insert_range(10, 12);
+        set.insert_range(20, 22);
+        assert_eq!(ranges(&set), [(0, 4), (10, 12), (20, 22)]);
+
+        set.insert_range(11, 21);
+        assert_eq!(ranges(&set), [(0, 4), (10, 22)]);
+
+        set.insert_range(5, 9);
+        assert_eq!(ranges(&set), [(0, 22)]);
+        assert_eq!(set.len(), 23);
+
+        assert!(!set.insert(7));
+        assert!(set.insert(23));
+        assert_eq!(ranges(&set), [(0, 23)]);
+
+        set.insert_range(usize::MAX - 1, usize::MAX);
+        set.insert_range(usize::MAX, usize::MAX);
+        assert_eq!(ranges(&set), [(0, 23), (usize::MAX - 1, usize::MAX)]);
+    }
+
+    #[test]
+    fn difference_trims_partial_overlaps_at_both_ends() {
+        let set: RangeSet = "0-20".parse().unwrap();
+
+        let other: RangeSet = "0-3,8-9,18-25".parse().unwrap();
+        assert_eq!(ranges(&set.difference(&other)), [(4, 7), (10, 17)]);
+
+        let other: RangeSet = "5-10".parse().unwrap();
+        assert_eq!(ranges(&set.difference(&other)), [(0, 4), (11, 20)]);
+
+        let set: RangeSet = "3-6,10-14".parse().unwrap();
+        let other: RangeSet = "0-4,12-30".parse().unwrap();
+        assert_eq!(ranges(&set.difference(&other)), [(5, 6), (10, 11)]);
+
+        assert!(set.difference(&"0-30".parse().unwrap()).is_empty());
+        assert_eq!(set.difference(&RangeSet::new()), set);
+    }
+
+    #[test]
+    fn serializes_as_compact_ranges_and_parses_back() {
+        let set: RangeSet = [0, 1, 2, 3, 4, 5, 7].into_iter().collect();
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "\"0-5,7\"");
+        assert_eq!(serde_json::from_str::<RangeSet>(&json).unwrap(), set);
+
+        assert_eq!("".parse::<RangeSet>().unwrap(), RangeSet::new());
+        assert_eq!("7,0-5".parse::<RangeSet>().unwrap(), set);
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        for s in ["5-3", "a", "1-", "-1", "1-2-3", "1;2", "0-5,x"] {
+            assert!(s.parse::<RangeSet>().is_err(), "{:?} parsed", s);
+        }
+
+        assert!(serde_json::from_str::<RangeSet>("\"4-2\"").is_err());
+        assert!(serde_json::from_str::<RangeSet>("[1, 2]").is_err());
+    }
+}
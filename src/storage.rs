@@ -0,0 +1,1251 @@
+use std::{
+    collections::HashMap,
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{hash::fnv1a, time::Clock};
+
+/// Environment variable selecting where logs are kept: `memory`, or
+/// `segmented:<dir>` for segment files under `<dir>`.
+pub const LOG_STORAGE_ENV: &str = "LOG_STORAGE";
+/// Environment variable with the fsync policy of segmented logs: `always`,
+/// `never`, or the milliseconds to leave between fsyncs.
+pub const LOG_FSYNC_ENV: &str = "LOG_FSYNC";
+/// Environment variable with how much of each log to keep: comma separated
+/// `records=<n>`, `bytes=<n>` and `ms=<n>` limits, plus `compact` to keep only
+/// the latest record per key, e.g. `LOG_RETENTION=bytes=1048576,compact`.
+pub const LOG_RETENTION_ENV: &str = "LOG_RETENTION";
+
+pub const DEFAULT_SEGMENT_BYTES: u64 = 1 << 20;
+/// Bytes of records between two entries of a segment's sparse index.
+pub const DEFAULT_INDEX_INTERVAL: u64 = 4 << 10;
+
+/// Record frames are the body length and checksum, then the body: offset,
+/// timestamp and value.
+const HEADER_BYTES: usize = 8;
+const BODY_PREFIX_BYTES: usize = 16;
+/// Index entries are an offset and the position of its record, both u64.
+const INDEX_ENTRY_BYTES: usize = 16;
+/// File in a segmented log's directory with its start offset, once records
+/// have been deleted from it.
+const START_FILE: &str = "log-start";
+
+pub type Offset = usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub offset: Offset,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub value: Vec<u8>,
+}
+
+impl Record {
+    /// Bytes the record takes in a segment file.
+    pub fn size(&self) -> u64 {
+        (HEADER_BYTES + BODY_PREFIX_BYTES + self.value.len()) as u64
+    }
+}
+
+/// An append-only log of records with increasing offsets. Appends get
+/// consecutive offsets; compaction leaves gaps between them.
+pub trait LogStore {
+    /// The first offset still kept; everything before it was deleted.
+    fn start_offset(&self) -> Offset;
+
+    /// The offset the next appended record gets.
+    fn next_offset(&self) -> Offset;
+
+    /// Appends a record copied from another log, keeping its offset. That
+    /// can't be below `next_offset`, but may skip offsets compacted away
+    /// from the other log.
+    fn append_record(&mut self, record: Record) -> Result<()>;
+
+    fn append(&mut self, timestamp: u64, value: &[u8]) -> Result<Offset> {
+        let offset = self.next_offset();
+        self.append_record(Record {
+            offset,
+            timestamp,
+            value: value.to_vec(),
+        })?;
+
+        Ok(offset)
+    }
+
+    /// Up to `max` records, starting at `offset` or the first one after it.
+    fn read(&self, offset: Offset, max: usize) -> Result<Vec<Record>>;
+
+    /// Drops `offset` and everything after it.
+    fn truncate(&mut self, offset: Offset) -> Result<()>;
+
+    /// Drops everything before `offset`, which becomes the start of the log.
+    /// Past the end of the log that's every record, and the log carries on
+    /// from `offset`.
+    fn delete_before(&mut self, offset: Offset) -> Result<()>;
+
+    /// Drops the records before `end` that `keep` rejects, leaving gaps in
+    /// the offsets. Some may be kept anyway, e.g. to mark where a segment
+    /// ends.
+    fn compact(&mut self, end: Offset, keep: &mut dyn FnMut(&Record) -> bool) -> Result<()>;
+
+    /// Bytes taken up by the records from the start of the log, as counted
+    /// by `Record::size`.
+    fn size(&self) -> u64;
+
+    /// Makes everything appended so far durable.
+    fn flush(&mut self) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every append.
+    Always,
+    /// On the first append once this long has passed since the last fsync.
+    Interval(Duration),
+    /// Left to the OS, except on `flush`.
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn from_env(default: FsyncPolicy) -> Result<FsyncPolicy> {
+        match env::var(LOG_FSYNC_ENV) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(default),
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            ms => {
+                let ms = ms
+                    .parse()
+                    .with_context(|| format!("unknown fsync policy {:?}", s))?;
+                Ok(FsyncPolicy::Interval(Duration::from_millis(ms)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentOptions {
+    /// Segments are rolled once they grow past this.
+    pub segment_bytes: u64,
+    pub index_interval: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        SegmentOptions {
+            segment_bytes: DEFAULT_SEGMENT_BYTES,
+            index_interval: DEFAULT_INDEX_INTERVAL,
+            fsync: FsyncPolicy::Interval(Duration::from_millis(100)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Memory,
+    /// One directory of segments per log under `dir`.
+    Segmented {
+        dir: PathBuf,
+        options: SegmentOptions,
+    },
+}
+
+impl Storage {
+    /// Reads the storage from `LOG_STORAGE` and the fsync policy from
+    /// `LOG_FSYNC`.
+    pub fn from_env(default: Storage) -> Result<Storage> {
+        let storage = match env::var(LOG_STORAGE_ENV) {
+            Ok(s) => s.parse()?,
+            Err(_) => default,
+        };
+
+        match storage {
+            Storage::Segmented { dir, mut options } => {
+                options.fsync = FsyncPolicy::from_env(options.fsync)?;
+                Ok(Storage::Segmented { dir, options })
+            }
+            storage => Ok(storage),
+        }
+    }
+
+    /// The same storage, kept apart from everything outside `scope`, e.g.
+    /// other nodes sharing the directory.
+    pub fn within(&self, scope: &str) -> Storage {
+        match self {
+            Storage::Memory => Storage::Memory,
+            Storage::Segmented { dir, options } => Storage::Segmented {
+                dir: dir.join(encode_name(scope)),
+                options: *options,
+            },
+        }
+    }
+
+    /// Names of the logs already stored, to be reopened after a restart.
+    pub fn names(&self) -> Result<Vec<String>> {
+        let Storage::Segmented { dir, .. } = self else {
+            return Ok(Vec::new());
+        };
+
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("failed to list {:?}", dir))? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str().and_then(decode_name) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Opens the log called `name`. The clock times interval fsyncs.
+    pub fn open(&self, name: &str, clock: Arc<dyn Clock>) -> Result<Box<dyn LogStore>> {
+        match self {
+            Storage::Memory => Ok(Box::new(MemoryLog::new())),
+            Storage::Segmented { dir, options } => {
+                let log = SegmentedLog::open(dir.join(encode_name(name)), *options, clock)?;
+                Ok(Box::new(log))
+            }
+        }
+    }
+}
+
+impl FromStr for Storage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Storage::Memory),
+            Some(("segmented", dir)) if !dir.is_empty() => Ok(Storage::Segmented {
+                dir: PathBuf::from(dir),
+                options: SegmentOptions::default(),
+            }),
+            _ => bail!("unknown log storage {:?}", s),
+        }
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Storage::Memory => write!(f, "memory"),
+            Storage::Segmented { dir, .. } => write!(f, "segmented:{}", dir.display()),
+        }
+    }
+}
+
+/// How much of a log to keep. Records past any of the limits are deleted
+/// from the start of the log.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retention {
+    pub max_records: Option<usize>,
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    /// Whether to drop records that a later one with the same key replaces.
+    pub compact: bool,
+}
+
+impl Retention {
+    pub fn from_env(default: Retention) -> Result<Retention> {
+        match env::var(LOG_RETENTION_ENV) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(default),
+        }
+    }
+
+    /// Where the start of `store` should move to for it to be within the
+    /// limits at `now`, in milliseconds since the Unix epoch. Never past
+    /// `end`, so records that aren't committed yet stay.
+    pub fn start_for(&self, store: &dyn LogStore, now: u64, end: Offset) -> Result<Offset> {
+        let mut excess = self
+            .max_bytes
+            .map_or(0, |max| store.size().saturating_sub(max));
+        let expired_before = self
+            .max_age
+            .map(|age| now.saturating_sub(age.as_millis() as u64));
+
+        let mut start = store.start_offset();
+        'scan: while start < end {
+            let records = store.read(start, 100)?;
+            if records.is_empty() {
+                break;
+            }
+
+            for record in records {
+                let expired = expired_before.is_some_and(|t| record.timestamp < t);
+                if record.offset >= end || (excess == 0 && !expired) {
+                    break 'scan;
+                }
+                excess = excess.saturating_sub(record.size());
+                start = record.offset + 1;
+            }
+        }
+
+        if let Some(max_records) = self.max_records {
+            start = start.max(store.next_offset().saturating_sub(max_records));
+        }
+
+        Ok(start.min(end))
+    }
+
+    /// Compacts `store` up to `end`, keeping the latest record for each key
+    /// before it. Records without a key are kept.
+    pub fn compact(
+        &self,
+        store: &mut dyn LogStore,
+        end: Offset,
+        key: impl Fn(&Record) -> Option<Vec<u8>>,
+    ) -> Result<()> {
+        if !self.compact {
+            return Ok(());
+        }
+
+        let mut latest = HashMap::new();
+        let mut offset = store.start_offset();
+        while offset < end {
+            let records = store.read(offset, 100)?;
+            let Some(last) = records.last() else {
+                break;
+            };
+            offset = last.offset + 1;
+
+            for record in records.iter().filter(|r| r.offset < end) {
+                if let Some(key) = key(record) {
+                    latest.insert(key, record.offset);
+                }
+            }
+        }
+
+        store.compact(end, &mut |record| {
+            key(record).is_none_or(|k| latest.get(&k).is_none_or(|&o| o == record.offset))
+        })
+    }
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut retention = Retention::default();
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            let (name, value) = part.split_once('=').unwrap_or((part, ""));
+            let invalid = || format!("invalid log retention {:?}", part);
+            match name {
+                "records" => retention.max_records = Some(value.parse().with_context(invalid)?),
+                "bytes" => retention.max_bytes = Some(value.parse().with_context(invalid)?),
+                "ms" => {
+                    let ms = value.parse().with_context(invalid)?;
+                    retention.max_age = Some(Duration::from_millis(ms));
+                }
+                "compact" if value.is_empty() => retention.compact = true,
+                _ => bail!("unknown log retention {:?}", part),
+            }
+        }
+
+        Ok(retention)
+    }
+}
+
+/// Names can be any string, so they're hex encoded to make file names.
+fn encode_name(name: &str) -> String {
+    name.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_name(encoded: &str) -> Option<String> {
+    if !encoded.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let bytes = (0..encoded.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(encoded.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+pub struct MemoryLog {
+    start: Offset,
+    next: Offset,
+    records: Vec<Record>,
+    size: u64,
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        MemoryLog {
+            start: 0,
+            next: 0,
+            records: Vec::new(),
+            size: 0,
+        }
+    }
+
+    /// Index of the first record at or after `offset`.
+    fn index_of(&self, offset: Offset) -> usize {
+        self.records.partition_point(|r| r.offset < offset)
+    }
+}
+
+impl Default for MemoryLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LogStore for MemoryLog {
+    fn start_offset(&self) -> Offset {
+        self.start
+    }
+
+    fn next_offset(&self) -> Offset {
+        self.next
+    }
+
+    fn append_record(&mut self, record: Record) -> Result<()> {
+        if record.offset < self.next {
+            bail!(
+                "can't append offset {} before the end of the log at {}",
+                record.offset,
+                self.next
+            );
+        }
+
+        self.next = record.offset + 1;
+        self.size += record.size();
+        self.records.push(record);
+
+        Ok(())
+    }
+
+    fn read(&self, offset: Offset, max: usize) -> Result<Vec<Record>> {
+        let first = self.index_of(offset);
+        Ok(self.records[first..].iter().take(max).cloned().collect())
+    }
+
+    fn truncate(&mut self, offset: Offset) -> Result<()> {
+        if offset >= self.next {
+            return Ok(());
+        }
+
+        let dropped = self.records.drain(self.index_of(offset)..);
+        self.size -= dropped.map(|r| r.size()).sum::<u64>();
+        self.next = offset;
+        self.start = self.start.min(offset);
+
+        Ok(())
+    }
+
+    fn delete_before(&mut self, offset: Offset) -> Result<()> {
+        if offset <= self.start {
+            return Ok(());
+        }
+
+        let dropped = self.records.drain(..self.index_of(offset));
+        self.size -= dropped.map(|r| r.size()).sum::<u64>();
+        self.start = offset;
+        self.next = self.next.max(offset);
+
+        Ok(())
+    }
+
+    fn compact(&mut self, end: Offset, keep: &mut dyn FnMut(&Record) -> bool) -> Result<()> {
+        self.records.retain(|r| r.offset >= end || keep(r));
+        self.size = self.records.iter().map(|r| r.size()).sum();
+
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    let mut body = Vec::with_capacity(BODY_PREFIX_BYTES + record.value.len());
+    body.extend((record.offset as u64).to_le_bytes());
+    body.extend(record.timestamp.to_le_bytes());
+    body.extend(&record.value);
+
+    let mut frame = Vec::with_capacity(HEADER_BYTES + body.len());
+    frame.extend((body.len() as u32).to_le_bytes());
+    frame.extend((fnv1a(&body) as u32).to_le_bytes());
+    frame.extend(body);
+    frame
+}
+
+/// Reads the record framed at `position`, and where the next one starts.
+/// `None` if the frame is cut short or fails its checksum, which is what a
+/// crash in the middle of an append leaves behind.
+fn read_frame(file: &File, position: u64, size: u64) -> Result<Option<(Record, u64)>> {
+    if position + HEADER_BYTES as u64 > size {
+        return Ok(None);
+    }
+
+    let mut header = [0; HEADER_BYTES];
+    file.read_exact_at(&mut header, position)?;
+    let len = u32::from_le_bytes(header[..4].try_into()?) as u64;
+    let checksum = u32::from_le_bytes(header[4..].try_into()?);
+
+    let body_position = position + HEADER_BYTES as u64;
+    if len < BODY_PREFIX_BYTES as u64 || body_position + len > size {
+        return Ok(None);
+    }
+
+    let mut body = vec![0; len as usize];
+    file.read_exact_at(&mut body, body_position)?;
+    if fnv1a(&body) as u32 != checksum {
+        return Ok(None);
+    }
+
+    let record = Record {
+        offset: u64::from_le_bytes(body[..8].try_into()?) as Offset,
+        timestamp: u64::from_le_bytes(body[8..16].try_into()?),
+        value: body[BODY_PREFIX_BYTES..].to_vec(),
+    };
+
+    Ok(Some((record, body_position + len)))
+}
+
+/// One file of consecutive records, starting at `base`, and its sparse
+/// index.
+struct Segment {
+    base: Offset,
+    next: Offset,
+    log: File,
+    index_file: File,
+    size: u64,
+    /// `(offset, position)` of every record that starts an index interval,
+    /// the segment's first record included.
+    index: Vec<(Offset, u64)>,
+    indexed_size: u64,
+}
+
+impl Segment {
+    fn paths(dir: &Path, base: Offset) -> (PathBuf, PathBuf) {
+        (
+            dir.join(format!("{:020}.log", base)),
+            dir.join(format!("{:020}.index", base)),
+        )
+    }
+
+    fn open_files(dir: &Path, base: Offset) -> Result<(File, File)> {
+        let (log_path, index_path) = Segment::paths(dir, base);
+        let log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&log_path)
+            .with_context(|| format!("failed to open {:?}", log_path))?;
+        let index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&index_path)
+            .with_context(|| format!("failed to open {:?}", index_path))?;
+
+        Ok((log, index_file))
+    }
+
+    fn create(dir: &Path, base: Offset) -> Result<Segment> {
+        let (log, index_file) = Segment::open_files(dir, base)?;
+        log.set_len(0)?;
+        index_file.set_len(0)?;
+
+        Ok(Segment {
+            base,
+            next: base,
+            log,
+            index_file,
+            size: 0,
+            index: Vec::new(),
+            indexed_size: 0,
+        })
+    }
+
+    /// Opens an existing segment. Its index is trusted up to its last entry;
+    /// the records after that are checked one by one and the log is cut
+    /// back to the last good one. Offsets only have to increase, as
+    /// compaction leaves gaps between them.
+    fn recover(dir: &Path, base: Offset, index_interval: u64) -> Result<Segment> {
+        let (log, index_file) = Segment::open_files(dir, base)?;
+        let file_size = log.metadata()?.len();
+
+        let mut bytes = vec![0; index_file.metadata()?.len() as usize];
+        index_file.read_exact_at(&mut bytes, 0)?;
+        let mut index: Vec<(Offset, u64)> = bytes
+            .chunks_exact(INDEX_ENTRY_BYTES)
+            .map(|entry| {
+                let offset = u64::from_le_bytes(entry[..8].try_into().unwrap()) as Offset;
+                let position = u64::from_le_bytes(entry[8..].try_into().unwrap());
+                (offset, position)
+            })
+            .collect();
+
+        // Entries have to be ascending and point at records that check out.
+        let valid = index
+            .iter()
+            .enumerate()
+            .take_while(|&(i, &(offset, position))| {
+                (i == 0 || index[i - 1] < (offset, position))
+                    && matches!(
+                        read_frame(&log, position, file_size),
+                        Ok(Some((ref r, _))) if r.offset == offset
+                    )
+            })
+            .count();
+        index.truncate(valid);
+        if index.first().is_some_and(|&e| e != (base, 0)) {
+            index.clear();
+        }
+
+        let (mut next, mut position) = match index.last() {
+            Some(&(offset, position)) => (offset, position),
+            None => (base, 0),
+        };
+        while let Some((record, end)) = read_frame(&log, position, file_size)? {
+            if record.offset < next {
+                break;
+            }
+
+            let indexed = index.last().map(|&(_, p)| p);
+            if indexed.is_none_or(|p| position - p >= index_interval) {
+                index.push((record.offset, position));
+            }
+            next = record.offset + 1;
+            position = end;
+        }
+
+        if position < file_size {
+            eprintln!(
+                "truncating {} bytes of partial records from segment {}",
+                file_size - position,
+                base
+            );
+            log.set_len(position)?;
+        }
+
+        let mut segment = Segment {
+            base,
+            next,
+            log,
+            index_file,
+            size: position,
+            index: Vec::new(),
+            indexed_size: 0,
+        };
+        segment.rewrite_index(index)?;
+
+        Ok(segment)
+    }
+
+    fn rewrite_index(&mut self, index: Vec<(Offset, u64)>) -> Result<()> {
+        let bytes: Vec<u8> = index
+            .iter()
+            .flat_map(|&(offset, position)| {
+                let mut entry = (offset as u64).to_le_bytes().to_vec();
+                entry.extend(position.to_le_bytes());
+                entry
+            })
+            .collect();
+
+        self.index_file.set_len(0)?;
+        self.index_file.write_all_at(&bytes, 0)?;
+        self.indexed_size = index.last().map_or(0, |&(_, position)| position);
+        self.index = index;
+
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Record, index_interval: u64) -> Result<()> {
+        let frame = encode(record);
+        let position = self.size;
+        (&self.log).write_all(&frame)?;
+        self.size += frame.len() as u64;
+        self.next = record.offset + 1;
+
+        if self.index.is_empty() || position - self.indexed_size >= index_interval {
+            let mut entry = (record.offset as u64).to_le_bytes().to_vec();
+            entry.extend(position.to_le_bytes());
+            let index_position = (self.index.len() * INDEX_ENTRY_BYTES) as u64;
+            self.index_file.write_all_at(&entry, index_position)?;
+            self.index.push((record.offset, position));
+            self.indexed_size = position;
+        }
+
+        Ok(())
+    }
+
+    /// Position of the first record at or after `offset`, scanning from the
+    /// closest index entry before it.
+    fn position_of(&self, offset: Offset) -> Result<u64> {
+        let entry = self.index.partition_point(|&(o, _)| o <= offset);
+        let mut position = entry.checked_sub(1).map_or(0, |e| self.index[e].1);
+
+        loop {
+            match read_frame(&self.log, position, self.size)? {
+                Some((record, end)) if record.offset < offset => position = end,
+                Some(_) => return Ok(position),
+                None if position < self.size => {
+                    bail!("segment {} is corrupt before offset {}", self.base, offset)
+                }
+                None => return Ok(position),
+            }
+        }
+    }
+
+    fn read(&self, offset: Offset, max: usize, records: &mut Vec<Record>) -> Result<()> {
+        let mut position = self.position_of(offset)?;
+        while records.len() < max {
+            let Some((record, end)) = read_frame(&self.log, position, self.size)? else {
+                break;
+            };
+            records.push(record);
+            position = end;
+        }
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, offset: Offset) -> Result<()> {
+        let position = self.position_of(offset)?;
+        self.log.set_len(position)?;
+        self.size = position;
+        self.next = offset;
+
+        let index = self
+            .index
+            .iter()
+            .copied()
+            .filter(|&(o, _)| o < offset)
+            .collect();
+        self.rewrite_index(index)
+    }
+
+    /// Replaces the segment's records with `records`, which have to be a
+    /// subset of them. The new log is written next to the old one and then
+    /// renamed over it, so a crash leaves one or the other.
+    fn rewrite(&mut self, dir: &Path, records: &[Record], index_interval: u64) -> Result<()> {
+        let (log_path, index_path) = Segment::paths(dir, self.base);
+        let cleaned_path = log_path.with_extension("cleaned");
+        let mut cleaned = File::create(&cleaned_path)
+            .with_context(|| format!("failed to create {:?}", cleaned_path))?;
+        for record in records {
+            cleaned.write_all(&encode(record))?;
+        }
+        cleaned.sync_data()?;
+
+        fs::rename(&cleaned_path, &log_path)
+            .with_context(|| format!("failed to replace {:?}", log_path))?;
+        // Positions all moved, so the index is rebuilt from scratch.
+        fs::write(&index_path, [])?;
+
+        let next = self.next;
+        *self = Segment::recover(dir, self.base, index_interval)?;
+        self.next = next;
+        Ok(())
+    }
+
+    fn delete(self, dir: &Path) -> Result<()> {
+        Segment::remove(dir, self.base)
+    }
+
+    fn remove(dir: &Path, base: Offset) -> Result<()> {
+        let (log_path, index_path) = Segment::paths(dir, base);
+        fs::remove_file(log_path)?;
+        fs::remove_file(index_path)?;
+        Ok(())
+    }
+}
+
+/// A log kept in a directory of segment files named after the first offset
+/// in them. Appends go to the last segment, which is rolled once it's full.
+/// Reads find their segment and then the closest entry in its sparse index,
+/// both by binary search, and scan forward from there.
+///
+/// Deleting the start of the log removes whole segments; the start offset
+/// itself is kept in a file of its own. Compaction rewrites the segments
+/// that were rolled, but keeps their last record so that each one still
+/// ends where the next one starts.
+pub struct SegmentedLog {
+    dir: PathBuf,
+    options: SegmentOptions,
+    segments: Vec<Segment>,
+    start: Offset,
+    /// Bytes in the first segment before `start`.
+    skipped: u64,
+    clock: Arc<dyn Clock>,
+    last_sync: Instant,
+    dirty: bool,
+}
+
+impl SegmentedLog {
+    /// Opens the log in `dir`, creating it if needed. Partial records left at
+    /// the end by a crash are truncated, as is everything after them.
+    pub fn open(
+        dir: impl Into<PathBuf>,
+        options: SegmentOptions,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("failed to create {:?}", dir))?;
+
+        let mut bases: Vec<Offset> = fs::read_dir(&dir)?
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                name.strip_suffix(".log")?.parse().ok()
+            })
+            .collect();
+        bases.sort();
+
+        let start = read_start(&dir)?;
+        let mut segments: Vec<Segment> = Vec::new();
+        for (i, &base) in bases.iter().enumerate() {
+            // Segments that end before the start were being deleted.
+            if bases.get(i + 1).is_some_and(|&b| b <= start) {
+                Segment::remove(&dir, base)?;
+                continue;
+            }
+
+            let contiguous = segments.last().is_none_or(|s| s.next == base);
+            let segment = Segment::recover(&dir, base, options.index_interval)?;
+            if contiguous {
+                segments.push(segment);
+            } else {
+                eprintln!("dropping segment {} after a gap in {:?}", base, dir);
+                segment.delete(&dir)?;
+            }
+        }
+
+        if segments.last().is_none_or(|s| s.next < start) {
+            for segment in segments.drain(..) {
+                segment.delete(&dir)?;
+            }
+            segments.push(Segment::create(&dir, start)?);
+        }
+
+        let start = start.max(segments[0].base);
+        let skipped = segments[0].position_of(start)?;
+        Ok(SegmentedLog {
+            dir,
+            options,
+            segments,
+            start,
+            skipped,
+            last_sync: clock.now(),
+            clock,
+            dirty: false,
+        })
+    }
+
+    fn active(&mut self) -> &mut Segment {
+        self.segments
+            .last_mut()
+            .expect("there's always an active segment")
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            self.active().log.sync_data()?;
+            self.dirty = false;
+        }
+        self.last_sync = self.clock.now();
+
+        Ok(())
+    }
+
+    /// Moves the start of the log, writing it out before any segment is
+    /// deleted for it.
+    fn set_start(&mut self, start: Offset) -> Result<()> {
+        let path = self.dir.join(START_FILE);
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, (start as u64).to_le_bytes())?;
+        File::open(&temporary)?.sync_all()?;
+        fs::rename(&temporary, &path).with_context(|| format!("failed to write {:?}", path))?;
+
+        self.start = start;
+        self.skipped = self.segments[0].position_of(start)?;
+        Ok(())
+    }
+}
+
+fn read_start(dir: &Path) -> Result<Offset> {
+    let path = dir.join(START_FILE);
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let bytes = fs::read(&path).with_context(|| format!("failed to read {:?}", path))?;
+    let Ok(bytes) = bytes.as_slice().try_into() else {
+        bail!("{:?} is corrupt", path);
+    };
+
+    Ok(u64::from_le_bytes(bytes) as Offset)
+}
+
+impl LogStore for SegmentedLog {
+    fn start_offset(&self) -> Offset {
+        self.start
+    }
+
+    fn next_offset(&self) -> Offset {
+        self.segments.last().map_or(0, |s| s.next)
+    }
+
+    fn append_record(&mut self, record: Record) -> Result<()> {
+        let next = self.next_offset();
+        if record.offset < next {
+            bail!(
+                "can't append offset {} before the end of the log at {}",
+                record.offset,
+                next
+            );
+        }
+
+        if self.active().size >= self.options.segment_bytes {
+            // Whatever is still buffered for the old segment goes first.
+            self.sync()?;
+            let segment = Segment::create(&self.dir, record.offset)?;
+            self.segments.push(segment);
+        }
+
+        let index_interval = self.options.index_interval;
+        self.active().append(&record, index_interval)?;
+        self.dirty = true;
+
+        match self.options.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Interval(interval) if self.clock.elapsed(self.last_sync) >= interval => {
+                self.sync()?
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    fn read(&self, offset: Offset, max: usize) -> Result<Vec<Record>> {
+        let offset = offset.max(self.start);
+        let mut records = Vec::new();
+        let first = self
+            .segments
+            .partition_point(|s| s.base <= offset)
+            .saturating_sub(1);
+        for segment in &self.segments[first..] {
+            if records.len() >= max {
+                break;
+            }
+            if segment.next > offset {
+                segment.read(offset, max, &mut records)?;
+            }
+        }
+
+        Ok(records)
+    }
+
+    fn truncate(&mut self, offset: Offset) -> Result<()> {
+        if offset >= self.next_offset() {
+            return Ok(());
+        }
+
+        if offset < self.start {
+            self.set_start(offset)?;
+        }
+
+        while self.segments.len() > 1 && self.active().base >= offset {
+            let segment = self.segments.pop().expect("checked the length");
+            segment.delete(&self.dir)?;
+        }
+
+        if self.active().base >= offset {
+            let segment = self
+                .segments
+                .pop()
+                .expect("there's always an active segment");
+            segment.delete(&self.dir)?;
+            self.segments.push(Segment::create(&self.dir, offset)?);
+        } else {
+            self.active().truncate(offset)?;
+        }
+
+        self.skipped = self.segments[0].position_of(self.start)?;
+        self.dirty = true;
+        self.sync()
+    }
+
+    fn delete_before(&mut self, offset: Offset) -> Result<()> {
+        if offset <= self.start {
+            return Ok(());
+        }
+
+        // An empty active segment already starting there, e.g. one rolled
+        // just before a crash, can stay; creating it again would delete it.
+        if offset >= self.next_offset() && self.active().base != offset {
+            // The new active segment has to be there before the start moves
+            // past the old ones, or a crash could leave nothing to open.
+            self.sync()?;
+            self.segments.push(Segment::create(&self.dir, offset)?);
+        }
+
+        let first = self
+            .segments
+            .partition_point(|s| s.base <= offset)
+            .saturating_sub(1);
+        let deleted: Vec<Segment> = self.segments.drain(..first).collect();
+        self.set_start(offset)?;
+        for segment in deleted {
+            segment.delete(&self.dir)?;
+        }
+
+        Ok(())
+    }
+
+    fn compact(&mut self, end: Offset, keep: &mut dyn FnMut(&Record) -> bool) -> Result<()> {
+        let rolled = self.segments.len() - 1;
+        for i in 0..rolled {
+            if self.segments[i].next > end {
+                break;
+            }
+
+            let mut records = Vec::new();
+            self.segments[i].read(0, usize::MAX, &mut records)?;
+            let last = records.len().saturating_sub(1);
+            let kept: Vec<Record> = records
+                .iter()
+                .enumerate()
+                .filter(|&(j, r)| j == last || r.offset < self.start || keep(r))
+                .map(|(_, r)| r.clone())
+                .collect();
+            if kept.len() == records.len() {
+                continue;
+            }
+
+            let index_interval = self.options.index_interval;
+            self.segments[i].rewrite(&self.dir, &kept, index_interval)?;
+        }
+
+        self.skipped = self.segments[0].position_of(self.start)?;
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size).sum::<u64>() - self.skipped
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.sync()
+    }
+}
+
+impl Drop for SegmentedLog {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            eprintln!("failed to sync {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use crate::time::ManualClock;
+
+    use super::*;
+
+    fn options(segment_bytes: u64, index_interval: u64) -> SegmentOptions {
+        SegmentOptions {
+            segment_bytes,
+            index_interval,
+            fsync: FsyncPolicy::Never,
+        }
+    }
+
+    fn open(dir: &Path, options: SegmentOptions) -> SegmentedLog {
+        SegmentedLog::open(dir, options, Arc::new(ManualClock::new())).unwrap()
+    }
+
+    fn fill(log: &mut SegmentedLog, records: usize) {
+        for i in 0..records {
+            log.append(i as u64, format!("value {}", i).as_bytes())
+                .unwrap();
+        }
+    }
+
+    fn offsets(log: &SegmentedLog, from: Offset) -> Vec<Offset> {
+        log.read(from, usize::MAX)
+            .unwrap()
+            .iter()
+            .map(|r| r.offset)
+            .collect()
+    }
+
+    #[test]
+    fn a_torn_trailing_record_is_dropped_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = open(dir.path(), SegmentOptions::default());
+        fill(&mut log, 3);
+        drop(log);
+
+        let (log_path, _) = Segment::paths(dir.path(), 0);
+        let whole = fs::metadata(&log_path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log_path).unwrap();
+        file.set_len(whole - 3).unwrap();
+
+        let mut log = open(dir.path(), SegmentOptions::default());
+        assert_eq!(log.next_offset(), 2);
+        assert_eq!(offsets(&log, 0), vec![0, 1]);
+
+        // Garbage past the end goes the same way, and appends carry on
+        // where the intact records stop.
+        log.append(9, b"replacement").unwrap();
+        drop(log);
+        let mut file = OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(&[0xff; 7]).unwrap();
+
+        let log = open(dir.path(), SegmentOptions::default());
+        let records = log.read(0, usize::MAX).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].offset, 2);
+        assert_eq!(records[2].value, b"replacement");
+    }
+
+    #[test]
+    fn reads_find_every_offset_through_the_sparse_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = open(dir.path(), options(DEFAULT_SEGMENT_BYTES, 100));
+        fill(&mut log, 50);
+
+        let index = log.segments[0].index.clone();
+        assert!(index.len() > 5 && index.len() < 50, "{:?}", index);
+        assert_eq!(index[0], (0, 0));
+
+        for offset in 0..50 {
+            let records = log.read(offset, 1).unwrap();
+            assert_eq!(records[0].offset, offset);
+            assert_eq!(records[0].value, format!("value {}", offset).as_bytes());
+        }
+
+        // Reopening rebuilds the same index from the files.
+        drop(log);
+        let log = open(dir.path(), options(DEFAULT_SEGMENT_BYTES, 100));
+        assert_eq!(log.segments[0].index, index);
+        assert_eq!(offsets(&log, 37)[0], 37);
+    }
+
+    #[test]
+    fn truncate_drops_the_tail_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = open(dir.path(), options(100, 50));
+        fill(&mut log, 20);
+        assert!(log.segments.len() > 3);
+
+        log.truncate(7).unwrap();
+        assert_eq!(log.next_offset(), 7);
+        assert_eq!(offsets(&log, 0), (0..7).collect::<Vec<_>>());
+        assert!(log.segments.iter().all(|s| s.base < 7));
+
+        log.append(0, b"after").unwrap();
+        drop(log);
+
+        let log = open(dir.path(), options(100, 50));
+        assert_eq!(log.next_offset(), 8);
+        assert_eq!(log.read(7, 1).unwrap()[0].value, b"after");
+    }
+
+    #[test]
+    fn delete_before_removes_whole_segments_and_keeps_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = open(dir.path(), options(100, 50));
+        fill(&mut log, 20);
+        let segments = log.segments.len();
+
+        log.delete_before(9).unwrap();
+        assert_eq!(log.start_offset(), 9);
+        assert!(log.segments.len() < segments);
+        assert!(log.segments[0].base <= 9);
+        assert_eq!(offsets(&log, 0), (9..20).collect::<Vec<_>>());
+        drop(log);
+
+        let mut log = open(dir.path(), options(100, 50));
+        assert_eq!(log.start_offset(), 9);
+        assert_eq!(offsets(&log, 0), (9..20).collect::<Vec<_>>());
+
+        // Past the end, the log carries on from there.
+        log.delete_before(25).unwrap();
+        assert_eq!(log.start_offset(), 25);
+        assert_eq!(log.next_offset(), 25);
+        assert_eq!(log.append(0, b"next").unwrap(), 25);
+    }
+
+    #[test]
+    fn delete_before_keeps_an_empty_active_segment_starting_there() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = open(dir.path(), options(100, 50));
+        fill(&mut log, 20);
+
+        // A crash right after rolling leaves an empty segment starting where
+        // the log ends.
+        Segment::create(dir.path(), 20).unwrap();
+        drop(log);
+        let mut log = open(dir.path(), options(100, 50));
+        assert_eq!(log.active().base, 20);
+        assert_eq!(log.active().size, 0);
+
+        log.delete_before(20).unwrap();
+        assert_eq!(log.segments.len(), 1);
+        log.append(0, b"kept").unwrap();
+        drop(log);
+
+        let log = open(dir.path(), options(100, 50));
+        assert_eq!(log.start_offset(), 20);
+        assert_eq!(log.read(0, usize::MAX).unwrap()[0].value, b"kept");
+    }
+
+    #[test]
+    fn interval_fsyncs_follow_the_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let options = SegmentOptions {
+            fsync: FsyncPolicy::Interval(Duration::from_millis(100)),
+            ..SegmentOptions::default()
+        };
+        let mut log = SegmentedLog::open(dir.path(), options, clock.clone()).unwrap();
+
+        log.append(0, b"a").unwrap();
+        clock.advance(Duration::from_millis(99));
+        log.append(0, b"b").unwrap();
+        assert!(log.dirty);
+
+        clock.advance(Duration::from_millis(1));
+        log.append(0, b"c").unwrap();
+        assert!(!log.dirty);
+    }
+}
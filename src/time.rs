@@ -0,0 +1,86 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, used for timeouts and timer intervals.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, used where timestamps leave the process.
+    fn wall(&self) -> SystemTime;
+
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    /// Blocks until `duration` has passed by this clock.
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when told to, so that timeout and timer logic can
+/// be driven step by step.
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    start_wall: SystemTime,
+    offset: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            start_wall: SystemTime::now(),
+            offset: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut offset = self.offset.lock().expect("clock lock poisoned");
+        *offset += by;
+    }
+
+    fn offset(&self) -> Duration {
+        *self.offset.lock().expect("clock lock poisoned")
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.offset()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.start_wall + self.offset()
+    }
+
+    /// Nobody else would move the clock while we wait, so this moves it.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
@@ -0,0 +1,438 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    env,
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Serialize;
+
+pub type Topology = HashMap<String, Vec<String>>;
+
+/// Environment variable the broadcast binaries read their strategy from,
+/// e.g. `TOPOLOGY=tree:4`.
+pub const TOPOLOGY_ENV: &str = "TOPOLOGY";
+
+const RANDOM_REGULAR_ATTEMPTS: usize = 100;
+
+/// Partners drawn for a stub in `random_regular` before giving up on the
+/// attempt, which only tends to happen with the last few stubs.
+const PAIRING_DRAWS: usize = 50;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Everyone connected to the first node.
+    Star,
+    /// Fully connected roots, with the remaining nodes split evenly between
+    /// them.
+    MultiRoot {
+        roots: usize,
+    },
+    Tree {
+        arity: usize,
+    },
+    Grid,
+    Ring,
+    /// Every node gets `degree` random neighbours. The seed has to be the same
+    /// on every node so that they all build the same graph.
+    RandomRegular {
+        degree: usize,
+        seed: u64,
+    },
+    /// Whatever Maelstrom sends in the `topology` message.
+    Maelstrom,
+}
+
+impl Strategy {
+    pub fn from_env(default: Strategy) -> Result<Strategy> {
+        match env::var(TOPOLOGY_ENV) {
+            Ok(s) => s.parse(),
+            Err(_) => Ok(default),
+        }
+    }
+
+    pub fn build(&self, node_ids: &[String]) -> Result<Topology> {
+        let n = node_ids.len();
+        let edges = match self {
+            Strategy::Star => (1..n).map(|i| (0, i)).collect(),
+            Strategy::MultiRoot { roots } => multi_root(n, *roots)?,
+            Strategy::Tree { arity } => {
+                if *arity == 0 {
+                    bail!("tree arity must be positive");
+                }
+                (1..n).map(|i| ((i - 1) / arity, i)).collect()
+            }
+            Strategy::Grid => grid(n),
+            Strategy::Ring => ring(n),
+            Strategy::RandomRegular { degree, seed } => random_regular(n, *degree, *seed)?,
+            Strategy::Maelstrom => bail!("the maelstrom topology comes from the topology message"),
+        };
+
+        let mut topology: Topology = node_ids.iter().map(|n| (n.clone(), Vec::new())).collect();
+        for (a, b) in edges {
+            topology
+                .get_mut(&node_ids[a])
+                .unwrap()
+                .push(node_ids[b].clone());
+            topology
+                .get_mut(&node_ids[b])
+                .unwrap()
+                .push(node_ids[a].clone());
+        }
+
+        Ok(topology)
+    }
+}
+
+fn multi_root(n: usize, roots: usize) -> Result<Vec<(usize, usize)>> {
+    if roots == 0 {
+        bail!("need at least one root");
+    }
+
+    let roots = roots.min(n);
+    let mut edges = Vec::new();
+    for a in 0..roots {
+        for b in a + 1..roots {
+            edges.push((a, b));
+        }
+    }
+
+    let children = n - roots;
+    let per_root = children.div_ceil(roots).max(1);
+    for (i, child) in (roots..n).enumerate() {
+        edges.push((i / per_root, child));
+    }
+
+    Ok(edges)
+}
+
+fn grid(n: usize) -> Vec<(usize, usize)> {
+    let side = (1..).find(|s| s * s >= n).unwrap_or(1);
+    let mut edges = Vec::new();
+    for i in 0..n {
+        if !(i + 1).is_multiple_of(side) && i + 1 < n {
+            edges.push((i, i + 1));
+        }
+        if i + side < n {
+            edges.push((i, i + side));
+        }
+    }
+
+    edges
+}
+
+fn ring(n: usize) -> Vec<(usize, usize)> {
+    match n {
+        0 | 1 => Vec::new(),
+        2 => vec![(0, 1)],
+        _ => (0..n).map(|i| (i, (i + 1) % n)).collect(),
+    }
+}
+
+/// Pairs up shuffled stubs, redrawing a partner that would make a self-loop
+/// or a duplicate edge a few times before starting over.
+fn random_regular(n: usize, degree: usize, seed: u64) -> Result<Vec<(usize, usize)>> {
+    if degree >= n.max(1) || !(n * degree).is_multiple_of(2) {
+        bail!("no {}-regular graph on {} nodes", degree, n);
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    'attempt: for _ in 0..RANDOM_REGULAR_ATTEMPTS {
+        let mut stubs: Vec<usize> = (0..n)
+            .flat_map(|i| std::iter::repeat_n(i, degree))
+            .collect();
+        stubs.shuffle(&mut rng);
+        let mut edges: HashSet<(usize, usize)> = HashSet::new();
+
+        while let Some(a) = stubs.pop() {
+            let partner = (0..PAIRING_DRAWS)
+                .map(|_| rng.gen_range(0..stubs.len()))
+                .find(|&j| a != stubs[j] && !edges.contains(&(a.min(stubs[j]), a.max(stubs[j]))));
+            let Some(j) = partner else {
+                continue 'attempt;
+            };
+
+            let b = stubs.swap_remove(j);
+            edges.insert((a.min(b), a.max(b)));
+        }
+
+        let mut edges: Vec<(usize, usize)> = edges.into_iter().collect();
+        edges.sort();
+        return Ok(edges);
+    }
+
+    bail!(
+        "failed to build a {}-regular graph on {} nodes in {} attempts",
+        degree,
+        n,
+        RANDOM_REGULAR_ATTEMPTS
+    )
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Stats {
+    pub nodes: usize,
+    pub edges: usize,
+    pub max_degree: usize,
+    /// `None` when the graph is disconnected.
+    pub diameter: Option<usize>,
+    /// Average number of hops a message originating at each node needs to
+    /// reach the others.
+    pub mean_hops: BTreeMap<String, f64>,
+}
+
+fn distances(topology: &Topology, from: &str) -> HashMap<String, usize> {
+    let mut distances = HashMap::from([(from.to_string(), 0)]);
+    let mut queue = VecDeque::from([from.to_string()]);
+    while let Some(node) = queue.pop_front() {
+        let d = distances[&node];
+        for n in topology.get(&node).into_iter().flatten() {
+            if !distances.contains_key(n) {
+                distances.insert(n.clone(), d + 1);
+                queue.push_back(n.clone());
+            }
+        }
+    }
+
+    distances
+}
+
+pub fn stats(topology: &Topology) -> Stats {
+    let nodes = topology.len();
+    let edges = topology.values().map(Vec::len).sum::<usize>() / 2;
+    let max_degree = topology.values().map(Vec::len).max().unwrap_or_default();
+
+    let mut diameter = Some(0);
+    let mut mean_hops = BTreeMap::new();
+    for node in topology.keys() {
+        let distances = distances(topology, node);
+        if distances.len() < nodes {
+            diameter = None;
+        }
+
+        let eccentricity = distances.values().copied().max().unwrap_or_default();
+        diameter = diameter.map(|d: usize| d.max(eccentricity));
+
+        let others = distances.len().saturating_sub(1).max(1);
+        let mean = distances.values().sum::<usize>() as f64 / others as f64;
+        mean_hops.insert(node.clone(), mean);
+    }
+
+    Stats {
+        nodes,
+        edges,
+        max_degree,
+        diameter,
+        mean_hops,
+    }
+}
+
+/// Graphviz rendering, with every undirected edge drawn once.
+pub fn to_dot(topology: &Topology, node_ids: &[String]) -> String {
+    let mut dot = String::from("graph topology {\n");
+    for (i, node) in node_ids.iter().enumerate() {
+        _ = writeln!(dot, "    \"{}\";", node);
+        for neighbour in topology.get(node).into_iter().flatten() {
+            let drawn = node_ids[..i].contains(neighbour);
+            if !drawn {
+                _ = writeln!(dot, "    \"{}\" -- \"{}\";", node, neighbour);
+            }
+        }
+    }
+    dot.push_str("}\n");
+
+    dot
+}
+
+pub fn to_json(topology: &Topology) -> Result<String> {
+    let sorted: BTreeMap<&String, &Vec<String>> = topology.iter().collect();
+    serde_json::to_string_pretty(&sorted).context("serializing topology")
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or_default();
+        let mut arg = |default: u64| -> Result<u64> {
+            match parts.next() {
+                Some(p) => p
+                    .parse()
+                    .with_context(|| format!("invalid argument {:?} for {}", p, name)),
+                None => Ok(default),
+            }
+        };
+
+        let strategy = match name {
+            "star" => Strategy::Star,
+            "multi-root" => Strategy::MultiRoot {
+                roots: arg(2)? as usize,
+            },
+            "tree" => Strategy::Tree {
+                arity: arg(2)? as usize,
+            },
+            "grid" => Strategy::Grid,
+            "ring" => Strategy::Ring,
+            "random" => Strategy::RandomRegular {
+                degree: arg(4)? as usize,
+                seed: arg(0)?,
+            },
+            "maelstrom" => Strategy::Maelstrom,
+            _ => bail!("unknown topology strategy {:?}", s),
+        };
+
+        Ok(strategy)
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Star => write!(f, "star"),
+            Strategy::MultiRoot { roots } => write!(f, "multi-root:{}", roots),
+            Strategy::Tree { arity } => write!(f, "tree:{}", arity),
+            Strategy::Grid => write!(f, "grid"),
+            Strategy::Ring => write!(f, "ring"),
+            Strategy::RandomRegular { degree, seed } => write!(f, "random:{}:{}", degree, seed),
+            Strategy::Maelstrom => write!(f, "maelstrom"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("n{}", i)).collect()
+    }
+
+    fn degrees(topology: &Topology, node_ids: &[String]) -> Vec<usize> {
+        node_ids.iter().map(|n| topology[n].len()).collect()
+    }
+
+    #[test]
+    fn star_connects_everyone_to_the_first_node() {
+        let ids = node_ids(5);
+        let topology = Strategy::Star.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids), [4, 1, 1, 1, 1]);
+        assert_eq!(stats(&topology).diameter, Some(2));
+    }
+
+    #[test]
+    fn multi_root_splits_the_rest_between_connected_roots() {
+        let ids = node_ids(9);
+        let topology = Strategy::MultiRoot { roots: 3 }.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids), [4, 4, 4, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(stats(&topology).diameter, Some(3));
+        assert!(Strategy::MultiRoot { roots: 0 }.build(&ids).is_err());
+    }
+
+    #[test]
+    fn tree_nodes_have_at_most_arity_children() {
+        let ids = node_ids(13);
+        let topology = Strategy::Tree { arity: 3 }.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids)[..5], [3, 4, 4, 4, 1]);
+        let stats = stats(&topology);
+        assert_eq!((stats.edges, stats.diameter), (12, Some(4)));
+        assert!(Strategy::Tree { arity: 0 }.build(&ids).is_err());
+    }
+
+    #[test]
+    fn grid_links_rows_and_columns() {
+        let ids = node_ids(9);
+        let topology = Strategy::Grid.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids), [2, 3, 2, 3, 4, 3, 2, 3, 2]);
+        assert_eq!(stats(&topology).diameter, Some(4));
+
+        let ids = node_ids(7);
+        let topology = Strategy::Grid.build(&ids).unwrap();
+        assert_eq!(degrees(&topology, &ids), [2, 3, 2, 3, 3, 2, 1]);
+        assert!(stats(&topology).diameter.is_some());
+    }
+
+    #[test]
+    fn ring_gives_every_node_two_neighbours() {
+        let ids = node_ids(6);
+        let topology = Strategy::Ring.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids), [2; 6]);
+        assert_eq!(stats(&topology).diameter, Some(3));
+
+        let ids = node_ids(2);
+        let topology = Strategy::Ring.build(&ids).unwrap();
+        assert_eq!(degrees(&topology, &ids), [1, 1]);
+    }
+
+    #[test]
+    fn random_regular_graphs_are_simple_connected_and_seeded() {
+        let ids = node_ids(25);
+        let strategy = Strategy::RandomRegular { degree: 4, seed: 7 };
+        let topology = strategy.build(&ids).unwrap();
+
+        assert_eq!(degrees(&topology, &ids), [4; 25]);
+        for (node, neighbours) in &topology {
+            let distinct: HashSet<&String> = neighbours.iter().collect();
+            assert_eq!(distinct.len(), neighbours.len());
+            assert!(!distinct.contains(node));
+        }
+        assert!(stats(&topology).diameter.is_some());
+        assert_eq!(strategy.build(&ids).unwrap(), topology);
+
+        let dense = Strategy::RandomRegular {
+            degree: 10,
+            seed: 7,
+        };
+        assert_eq!(degrees(&dense.build(&ids).unwrap(), &ids), [10; 25]);
+
+        let odd = Strategy::RandomRegular { degree: 3, seed: 7 };
+        assert!(odd.build(&ids).is_err());
+    }
+
+    #[test]
+    fn stats_report_size_diameter_and_mean_hops() {
+        let ids = node_ids(4);
+        let stats = stats(&Strategy::Tree { arity: 2 }.build(&ids).unwrap());
+
+        assert_eq!((stats.nodes, stats.edges, stats.max_degree), (4, 3, 2));
+        assert_eq!(stats.diameter, Some(3));
+        assert_eq!(stats.mean_hops["n0"], 4.0 / 3.0);
+        assert_eq!(stats.mean_hops["n3"], 6.0 / 3.0);
+        assert_eq!(stats.mean_hops["n2"], 6.0 / 3.0);
+    }
+
+    #[test]
+    fn stats_have_no_diameter_when_the_graph_is_disconnected() {
+        let mut topology = Strategy::Ring.build(&node_ids(3)).unwrap();
+        topology.insert("n3".to_string(), Vec::new());
+
+        let stats = stats(&topology);
+        assert_eq!((stats.nodes, stats.edges), (4, 3));
+        assert_eq!(stats.diameter, None);
+        assert_eq!(stats.mean_hops["n3"], 0.0);
+    }
+
+    #[test]
+    fn dot_draws_every_edge_once_and_json_is_sorted() {
+        let ids = node_ids(3);
+        let topology = Strategy::Star.build(&ids).unwrap();
+
+        let dot = to_dot(&topology, &ids);
+        assert_eq!(
+            dot,
+            "graph topology {\n    \"n0\";\n    \"n0\" -- \"n1\";\n    \"n0\" -- \"n2\";\n    \"n1\";\n    \"n2\";\n}\n"
+        );
+
+        let json: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(&to_json(&topology).unwrap()).unwrap();
+        assert_eq!(json["n0"], ["n1", "n2"]);
+        assert_eq!(json.keys().collect::<Vec<_>>(), ["n0", "n1", "n2"]);
+    }
+}